@@ -0,0 +1,284 @@
+use crate::fs_utils::{omission_marker, FileInfo, LineNumberCost, TruncationPolicy};
+use crate::tokenizer::TokenEstimator;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// What budget packing does with one selected file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackDecision {
+    Full,
+    /// Cut to the head/tail lines of the truncation policy.
+    Truncated,
+    Omitted,
+}
+
+/// The result of fitting a selection into a token budget.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackPlan {
+    pub budget: usize,
+    pub decisions: HashMap<PathBuf, PackDecision>,
+    /// Estimated tokens of the packed selection.
+    pub total_tokens: usize,
+    pub head_lines: usize,
+    pub tail_lines: usize,
+}
+
+impl PackPlan {
+    pub fn decision(&self, path: &Path) -> PackDecision {
+        self.decisions
+            .get(path)
+            .copied()
+            .unwrap_or(PackDecision::Full)
+    }
+
+    pub fn count(&self, decision: PackDecision) -> usize {
+        self.decisions.values().filter(|d| **d == decision).count()
+    }
+}
+
+/// Chooses, for every selected text file, whether it goes in full, truncated
+/// or not at all so the total stays within `budget`. Pinned files are placed
+/// first, then smaller files, then more recently modified ones.
+///
+/// Costs come from `FileInfo`: a file already over the per-file cap costs its
+/// truncated size and is not cut again, and the cost of truncating any other
+/// file is estimated from its share of kept lines. Files not counted yet are
+/// estimated at 4 bytes per token. Line-number prefixes are included when
+/// `line_numbers` is set.
+pub fn pack_selection(
+    files: &[FileInfo],
+    selected: &HashSet<PathBuf>,
+    pinned: &HashSet<PathBuf>,
+    budget: usize,
+    policy: &TruncationPolicy,
+    line_numbers: Option<&LineNumberCost>,
+    estimator: &TokenEstimator,
+) -> PackPlan {
+    let marker_tokens = estimator.estimate_tokens(&omission_marker(0));
+    let numbering = |lines: usize| line_numbers.map_or(0, |cost| cost.tokens(lines));
+    let full_cost = |file: &FileInfo| {
+        let (tokens, lines) = match file.truncated {
+            Some(truncation) => (truncation.tokens, truncation.kept_lines),
+            None if !file.counted => ((file.size / 4) as usize, 0),
+            None => (file.token_count, file.line_count),
+        };
+        tokens + numbering(lines)
+    };
+    // Estimated from the share of kept lines; None if there are too few lines
+    // to cut, or the file is already cut
+    let truncated_cost = |file: &FileInfo| {
+        let kept_lines = policy.head_lines + policy.tail_lines;
+        if file.truncated.is_some() || file.line_count <= kept_lines {
+            return None;
+        }
+        let kept_tokens = (file.token_count * kept_lines).div_ceil(file.line_count);
+        Some(kept_tokens + marker_tokens + numbering(kept_lines))
+    };
+
+    let mut candidates: Vec<&FileInfo> = files
+        .iter()
+        .filter(|file| selected.contains(&file.path) && !file.is_binary)
+        .collect();
+    let mtime = |file: &FileInfo| file.modified.unwrap_or(SystemTime::UNIX_EPOCH);
+    candidates.sort_by(|a, b| {
+        pinned
+            .contains(&b.path)
+            .cmp(&pinned.contains(&a.path))
+            .then_with(|| full_cost(a).cmp(&full_cost(b)))
+            .then_with(|| mtime(b).cmp(&mtime(a)))
+            .then_with(|| a.path.cmp(&b.path))
+    });
+
+    let mut decisions = HashMap::new();
+    let mut total_tokens = 0;
+    for file in candidates {
+        let remaining = budget.saturating_sub(total_tokens);
+        let full = full_cost(file);
+        let (decision, cost) = if full <= remaining {
+            (PackDecision::Full, full)
+        } else {
+            match truncated_cost(file) {
+                Some(cost) if cost <= remaining => (PackDecision::Truncated, cost),
+                _ => (PackDecision::Omitted, 0),
+            }
+        };
+        total_tokens += cost;
+        decisions.insert(file.path.clone(), decision);
+    }
+
+    PackPlan {
+        budget,
+        decisions,
+        total_tokens,
+        head_lines: policy.head_lines,
+        tail_lines: policy.tail_lines,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::TextEncoding;
+    use crate::fs_utils::FileLimit;
+
+    fn file_info(path: &str, token_count: usize, line_count: usize) -> FileInfo {
+        FileInfo {
+            name: path.rsplit('/').next().unwrap().to_string(),
+            path: PathBuf::from(path),
+            size: token_count as u64 * 4,
+            token_count,
+            line_count,
+            counted: true,
+            is_binary: false,
+            encoding: TextEncoding::Utf8,
+            truncated: None,
+            modified: None,
+        }
+    }
+
+    fn policy(head_lines: usize, tail_lines: usize) -> TruncationPolicy {
+        TruncationPolicy {
+            limit: FileLimit::Tokens(usize::MAX),
+            head_lines,
+            tail_lines,
+        }
+    }
+
+    #[test]
+    fn test_everything_fits() {
+        let files = vec![file_info("/w/a.rs", 100, 10), file_info("/w/b.rs", 200, 20)];
+        let selected: HashSet<PathBuf> = files.iter().map(|f| f.path.clone()).collect();
+
+        let plan = pack_selection(
+            &files,
+            &selected,
+            &HashSet::new(),
+            1_000,
+            &policy(1, 1),
+            None,
+            &TokenEstimator::CharDiv4,
+        );
+        assert_eq!(plan.total_tokens, 300);
+        assert_eq!(plan.count(PackDecision::Full), 2);
+
+        // "   1 | " is 7 chars, so 10 numbered lines add 70 chars = 17 tokens
+        let numbered = pack_selection(
+            &files[..1],
+            &selected,
+            &HashSet::new(),
+            1_000,
+            &policy(1, 1),
+            Some(&LineNumberCost::new(4, &TokenEstimator::CharDiv4)),
+            &TokenEstimator::CharDiv4,
+        );
+        assert_eq!(numbered.total_tokens, 117);
+    }
+
+    #[test]
+    fn test_smaller_files_first_then_truncate_then_omit() {
+        let files = vec![
+            file_info("/w/small.rs", 100, 10),
+            file_info("/w/big.rs", 10_000, 1_000),
+            file_info("/w/huge.rs", 50_000, 10),
+        ];
+        let selected: HashSet<PathBuf> = files.iter().map(|f| f.path.clone()).collect();
+
+        let plan = pack_selection(
+            &files,
+            &selected,
+            &HashSet::new(),
+            1_000,
+            &policy(20, 20),
+            None,
+            &TokenEstimator::CharDiv4,
+        );
+        assert_eq!(plan.decision(Path::new("/w/small.rs")), PackDecision::Full);
+        // 40 of 1,000 lines ≈ 400 tokens, plus the marker
+        assert_eq!(
+            plan.decision(Path::new("/w/big.rs")),
+            PackDecision::Truncated
+        );
+        // Too few lines to truncate
+        assert_eq!(
+            plan.decision(Path::new("/w/huge.rs")),
+            PackDecision::Omitted
+        );
+        assert!(plan.total_tokens <= 1_000);
+        assert!(plan.total_tokens > 500);
+    }
+
+    #[test]
+    fn test_pinned_files_go_first() {
+        let files = vec![
+            file_info("/w/small.rs", 600, 10),
+            file_info("/w/pinned.rs", 800, 10),
+        ];
+        let selected: HashSet<PathBuf> = files.iter().map(|f| f.path.clone()).collect();
+        let pinned: HashSet<PathBuf> = [PathBuf::from("/w/pinned.rs")].into_iter().collect();
+
+        let plan = pack_selection(
+            &files,
+            &selected,
+            &pinned,
+            1_000,
+            &policy(5, 5),
+            None,
+            &TokenEstimator::CharDiv4,
+        );
+        assert_eq!(plan.decision(Path::new("/w/pinned.rs")), PackDecision::Full);
+        assert_eq!(
+            plan.decision(Path::new("/w/small.rs")),
+            PackDecision::Omitted
+        );
+    }
+
+    #[test]
+    fn test_unselected_and_binary_files_are_ignored() {
+        let mut image = file_info("/w/logo.png", 0, 0);
+        image.is_binary = true;
+        let files = vec![file_info("/w/a.rs", 100, 10), image];
+        let selected: HashSet<PathBuf> = [PathBuf::from("/w/logo.png")].into_iter().collect();
+
+        let plan = pack_selection(
+            &files,
+            &selected,
+            &HashSet::new(),
+            10,
+            &policy(1, 1),
+            None,
+            &TokenEstimator::CharDiv4,
+        );
+        assert!(plan.decisions.is_empty());
+        assert_eq!(plan.decision(Path::new("/w/logo.png")), PackDecision::Full);
+    }
+
+    #[test]
+    fn test_uncounted_and_capped_files() {
+        let mut uncounted = file_info("/w/new.rs", 0, 0);
+        uncounted.size = 4_000;
+        uncounted.counted = false;
+        let mut capped = file_info("/w/capped.rs", 5_000, 500);
+        capped.truncated = Some(crate::fs_utils::Truncation {
+            tokens: 100,
+            kept_lines: 10,
+            omitted: crate::fs_utils::Omitted::Lines(490),
+        });
+        let files = vec![uncounted, capped];
+        let selected: HashSet<PathBuf> = files.iter().map(|f| f.path.clone()).collect();
+
+        let plan = pack_selection(
+            &files,
+            &selected,
+            &HashSet::new(),
+            1_050,
+            &policy(2, 2),
+            None,
+            &TokenEstimator::CharDiv4,
+        );
+        // Estimated at 1,000 tokens, not packed as free
+        assert_eq!(plan.decision(Path::new("/w/new.rs")), PackDecision::Omitted);
+        assert_eq!(plan.decision(Path::new("/w/capped.rs")), PackDecision::Full);
+        assert_eq!(plan.total_tokens, 100);
+    }
+}
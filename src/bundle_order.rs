@@ -0,0 +1,278 @@
+use crate::fs_utils::FileInfo;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
+
+/// How selected files are ordered in a copied bundle. Every policy is
+/// deterministic: ties are broken by path.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OrderPolicy {
+    /// Plain lexicographic path order.
+    #[default]
+    Path,
+    /// Depth-first with folders before files, like a file explorer.
+    Tree,
+    LargestFirst,
+    RecentlyModified,
+    /// The order set by dragging files in the bundle list.
+    Manual,
+}
+
+impl fmt::Display for OrderPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Path => write!(f, "Path"),
+            Self::Tree => write!(f, "Tree"),
+            Self::LargestFirst => write!(f, "LargestFirst"),
+            Self::RecentlyModified => write!(f, "RecentlyModified"),
+            Self::Manual => write!(f, "Manual"),
+        }
+    }
+}
+
+impl FromStr for OrderPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Path" => Ok(Self::Path),
+            "Tree" => Ok(Self::Tree),
+            "LargestFirst" => Ok(Self::LargestFirst),
+            "RecentlyModified" => Ok(Self::RecentlyModified),
+            "Manual" => Ok(Self::Manual),
+            _ => Err(format!("Unknown order policy: {}", s)),
+        }
+    }
+}
+
+impl OrderPolicy {
+    /// All policies, in Toolbar order.
+    pub const ALL: [OrderPolicy; 5] = [
+        Self::Path,
+        Self::Tree,
+        Self::LargestFirst,
+        Self::RecentlyModified,
+        Self::Manual,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Path => "Path order",
+            Self::Tree => "Tree order",
+            Self::LargestFirst => "Largest first",
+            Self::RecentlyModified => "Recently modified",
+            Self::Manual => "Manual order",
+        }
+    }
+}
+
+/// Orders the selected paths for a bundle. `files` supplies sizes for
+/// `LargestFirst` and modification times for `RecentlyModified`; `manual` is the user's drag order for `Manual`, and any
+/// selected path missing from it goes last, in path order.
+pub fn order_paths(
+    selected: &HashSet<PathBuf>,
+    policy: OrderPolicy,
+    files: &[FileInfo],
+    manual: &[PathBuf],
+) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = selected.iter().cloned().collect();
+    paths.sort();
+
+    match policy {
+        OrderPolicy::Path => {}
+        OrderPolicy::Tree => paths.sort_by(|a, b| compare_tree_order(a, b)),
+        OrderPolicy::LargestFirst => {
+            let sizes: HashMap<&Path, u64> = files
+                .iter()
+                .map(|file| (file.path.as_path(), file.size))
+                .collect();
+            let size_of = |path: &PathBuf| sizes.get(path.as_path()).copied().unwrap_or(0);
+            paths.sort_by(|a, b| size_of(b).cmp(&size_of(a)).then_with(|| a.cmp(b)));
+        }
+        OrderPolicy::RecentlyModified => {
+            let mtimes: HashMap<&Path, SystemTime> = files
+                .iter()
+                .filter_map(|file| Some((file.path.as_path(), file.modified?)))
+                .collect();
+            let mtime_of = |path: &PathBuf| {
+                mtimes
+                    .get(path.as_path())
+                    .copied()
+                    .unwrap_or(SystemTime::UNIX_EPOCH)
+            };
+            paths.sort_by(|a, b| mtime_of(b).cmp(&mtime_of(a)).then_with(|| a.cmp(b)));
+        }
+        OrderPolicy::Manual => {
+            let mut ordered: Vec<PathBuf> = Vec::with_capacity(paths.len());
+            let mut seen = HashSet::new();
+            for path in manual {
+                if selected.contains(path) && seen.insert(path) {
+                    ordered.push(path.clone());
+                }
+            }
+            ordered.extend(paths.into_iter().filter(|path| !seen.contains(path)));
+            return ordered;
+        }
+    }
+    paths
+}
+
+/// Compares two paths the way a folders-first tree lists them.
+fn compare_tree_order(a: &Path, b: &Path) -> Ordering {
+    let mut a_components = a.components().peekable();
+    let mut b_components = b.components().peekable();
+    loop {
+        match (a_components.next(), b_components.next()) {
+            (Some(a_part), Some(b_part)) if a_part == b_part => continue,
+            (Some(a_part), Some(b_part)) => {
+                let a_is_folder = a_components.peek().is_some();
+                let b_is_folder = b_components.peek().is_some();
+                return b_is_folder
+                    .cmp(&a_is_folder)
+                    .then_with(|| a_part.cmp(&b_part));
+            }
+            (a_part, b_part) => return a_part.is_some().cmp(&b_part.is_some()),
+        }
+    }
+}
+
+/// Moves the item at `from` so it ends up at index `to`.
+pub fn move_item<T>(items: &mut Vec<T>, from: usize, to: usize) {
+    if from >= items.len() || from == to {
+        return;
+    }
+    let item = items.remove(from);
+    items.insert(to.min(items.len()), item);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::TextEncoding;
+
+    fn file_info(path: &str, size: u64) -> FileInfo {
+        FileInfo {
+            name: Path::new(path)
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .into_owned(),
+            path: PathBuf::from(path),
+            size,
+            token_count: 0,
+            line_count: 0,
+            counted: false,
+            is_binary: false,
+            encoding: TextEncoding::Utf8,
+            truncated: None,
+            modified: None,
+        }
+    }
+
+    fn selection(paths: &[&str]) -> HashSet<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    fn strings(paths: &[PathBuf]) -> Vec<String> {
+        paths
+            .iter()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_order_policy_round_trip() {
+        for policy in OrderPolicy::ALL {
+            assert_eq!(policy.to_string().parse::<OrderPolicy>(), Ok(policy));
+        }
+        assert!("Random".parse::<OrderPolicy>().is_err());
+        assert_eq!(OrderPolicy::default(), OrderPolicy::Path);
+    }
+
+    #[test]
+    fn test_path_and_tree_order() {
+        let selected = selection(&["/w/b.rs", "/w/a/z.rs", "/w/a.rs", "/w/c/d/e.rs"]);
+
+        let by_path = order_paths(&selected, OrderPolicy::Path, &[], &[]);
+        assert_eq!(
+            strings(&by_path),
+            vec!["/w/a/z.rs", "/w/a.rs", "/w/b.rs", "/w/c/d/e.rs"]
+        );
+
+        let by_tree = order_paths(&selected, OrderPolicy::Tree, &[], &[]);
+        assert_eq!(
+            strings(&by_tree),
+            vec!["/w/a/z.rs", "/w/c/d/e.rs", "/w/a.rs", "/w/b.rs"]
+        );
+    }
+
+    #[test]
+    fn test_largest_first_breaks_ties_by_path() {
+        let files = vec![
+            file_info("/w/small.rs", 10),
+            file_info("/w/b.rs", 50),
+            file_info("/w/a.rs", 50),
+        ];
+        let selected = selection(&["/w/small.rs", "/w/b.rs", "/w/a.rs"]);
+
+        let ordered = order_paths(&selected, OrderPolicy::LargestFirst, &files, &[]);
+        assert_eq!(strings(&ordered), vec!["/w/a.rs", "/w/b.rs", "/w/small.rs"]);
+    }
+
+    #[test]
+    fn test_recently_modified_first() {
+        let now = SystemTime::now();
+        let mut old = file_info("/w/old.txt", 3);
+        old.modified = Some(now - std::time::Duration::from_secs(3600));
+        let mut new = file_info("/w/new.txt", 3);
+        new.modified = Some(now);
+        let files = vec![old, new];
+
+        let selected = selection(&["/w/old.txt", "/w/new.txt", "/w/unknown.txt"]);
+        let ordered = order_paths(&selected, OrderPolicy::RecentlyModified, &files, &[]);
+        assert_eq!(
+            strings(&ordered),
+            vec!["/w/new.txt", "/w/old.txt", "/w/unknown.txt"]
+        );
+    }
+
+    #[test]
+    fn test_manual_order_appends_unlisted_files() {
+        let selected = selection(&["/w/a.rs", "/w/b.rs", "/w/c.rs"]);
+        let manual = vec![
+            PathBuf::from("/w/c.rs"),
+            PathBuf::from("/w/deselected.rs"),
+            PathBuf::from("/w/a.rs"),
+        ];
+
+        let ordered = order_paths(&selected, OrderPolicy::Manual, &[], &manual);
+        assert_eq!(strings(&ordered), vec!["/w/c.rs", "/w/a.rs", "/w/b.rs"]);
+    }
+
+    #[test]
+    fn test_order_is_stable_across_calls() {
+        let selected = selection(&["/w/x.rs", "/w/y.rs", "/w/z.rs", "/w/a/b.rs"]);
+        let first = order_paths(&selected, OrderPolicy::Path, &[], &[]);
+        for _ in 0..10 {
+            let rebuilt: HashSet<PathBuf> = selected.iter().cloned().collect();
+            assert_eq!(order_paths(&rebuilt, OrderPolicy::Path, &[], &[]), first);
+        }
+    }
+
+    #[test]
+    fn test_move_item() {
+        let mut items = vec!['a', 'b', 'c', 'd'];
+        move_item(&mut items, 0, 2);
+        assert_eq!(items, vec!['b', 'c', 'a', 'd']);
+        move_item(&mut items, 3, 0);
+        assert_eq!(items, vec!['d', 'b', 'c', 'a']);
+        move_item(&mut items, 1, 10);
+        assert_eq!(items, vec!['d', 'c', 'a', 'b']);
+        move_item(&mut items, 10, 0);
+        assert_eq!(items, vec!['d', 'c', 'a', 'b']);
+    }
+}
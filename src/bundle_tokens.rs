@@ -0,0 +1,128 @@
+use crate::tokenizer::{is_token_boundary, TokenEstimator};
+use std::collections::HashMap;
+
+/// Counts the tokens of a rendered bundle, given as the consecutive pieces
+/// from `fs_utils::bundle_pieces`.
+///
+/// Pieces are grouped into segments split at `is_token_boundary`, so the
+/// total matches tokenizing the whole bundle at once wherever that rule
+/// holds. Segment counts are cached by a hash of their text, so after a
+/// selection change only new or edited files are tokenized.
+#[derive(Debug, Default)]
+pub struct BundleTokenCounter {
+    estimator: Option<TokenEstimator>,
+    counts: HashMap<blake3::Hash, usize>,
+}
+
+impl BundleTokenCounter {
+    pub fn count(&mut self, pieces: &[String], estimator: &TokenEstimator) -> usize {
+        if *estimator == TokenEstimator::CharDiv4 {
+            let chars: usize = pieces.iter().map(|piece| piece.chars().count()).sum();
+            return chars / 4;
+        }
+        if self.estimator.as_ref() != Some(estimator) {
+            self.invalidate();
+            self.estimator = Some(estimator.clone());
+        }
+
+        // Only segments of the latest bundle are kept
+        let mut counts = HashMap::with_capacity(self.counts.len());
+        let mut total = 0;
+        for segment in segments(pieces) {
+            let hash = blake3::hash(segment.as_bytes());
+            let tokens = match self.counts.get(&hash) {
+                Some(&tokens) => tokens,
+                None => estimator.estimate_tokens(&segment),
+            };
+            counts.insert(hash, tokens);
+            total += tokens;
+        }
+        self.counts = counts;
+        total
+    }
+
+    /// Forgets cached counts, e.g. after the estimator's tokenizer file changed.
+    pub fn invalidate(&mut self) {
+        self.counts.clear();
+    }
+}
+
+/// Joins `pieces`, cutting only at token boundaries between them.
+fn segments(pieces: &[String]) -> Vec<String> {
+    let mut segments: Vec<String> = Vec::new();
+    for piece in pieces.iter().filter(|piece| !piece.is_empty()) {
+        match segments.last_mut() {
+            Some(segment) if !is_token_boundary(segment, piece) => segment.push_str(piece),
+            _ => segments.push(piece.clone()),
+        }
+    }
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pieces(parts: &[&str]) -> Vec<String> {
+        parts.iter().map(|part| part.to_string()).collect()
+    }
+
+    #[test]
+    fn test_segments_cut_at_line_starts() {
+        let bundle = pieces(&[
+            "@@@ ./a.rs @@@\n\nfn a() {}\n",
+            "\n\n",
+            "@@@ ./b.rs @@@\n\n b",
+            "",
+        ]);
+        assert_eq!(
+            segments(&bundle),
+            vec!["@@@ ./a.rs @@@\n\nfn a() {}\n\n\n", "@@@ ./b.rs @@@\n\n b"]
+        );
+        // No newline before the next piece, or it starts with whitespace
+        assert_eq!(segments(&pieces(&["a", "b\n", " c"])), vec!["ab\n c"]);
+    }
+
+    #[test]
+    fn test_count_matches_whole_bundle() {
+        let bundle = pieces(&[
+            "<directory_tree>\nsrc\n└── main.rs\n</directory_tree>\n",
+            "<file path=\"./main.rs\">\nfn main() {\n    println!(\"hi\");\n}\n</file>",
+            "\n",
+            "<file path=\"./notes.md\">\n# Notes\n\n  - indented\n</file>",
+        ]);
+        let whole = bundle.concat();
+        let mut counter = BundleTokenCounter::default();
+        for estimator in [
+            TokenEstimator::CharDiv4,
+            TokenEstimator::Cl100k,
+            TokenEstimator::O200k,
+            TokenEstimator::P50k,
+        ] {
+            assert_eq!(
+                counter.count(&bundle, &estimator),
+                estimator.estimate_tokens(&whole),
+                "{}",
+                estimator
+            );
+        }
+    }
+
+    #[test]
+    fn test_cache_keeps_latest_bundle_only() {
+        let estimator = TokenEstimator::Cl100k;
+        let mut counter = BundleTokenCounter::default();
+        let first = pieces(&["@@@ ./a @@@\n\na\n", "\n\n", "@@@ ./b @@@\n\nb\n"]);
+        let total = counter.count(&first, &estimator);
+        assert_eq!(counter.counts.len(), 2);
+        assert_eq!(counter.count(&first, &estimator), total);
+
+        let second = pieces(&["@@@ ./b @@@\n\nb\n"]);
+        counter.count(&second, &estimator);
+        assert_eq!(counter.counts.len(), 1);
+
+        counter.count(&second, &TokenEstimator::O200k);
+        assert_eq!(counter.estimator, Some(TokenEstimator::O200k));
+        assert_eq!(counter.counts.len(), 1);
+    }
+}
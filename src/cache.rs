@@ -1,103 +1,491 @@
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use fs2::FileExt;
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
 use std::io;
 use std::path::{Path, PathBuf};
-use tokio::fs;
+use std::sync::{Mutex, MutexGuard};
+use std::time::SystemTime;
 
+use crate::cache_log::{self, Record};
+use crate::encoding::TextEncoding;
+use crate::fs_utils;
 use crate::tokenizer::TokenEstimator;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Where a file of the workspace was last seen: valid while its mtime, or
+/// failing that its hash, is unchanged.
+#[derive(Debug, Clone, PartialEq)]
 pub struct CacheEntry {
-    pub token_count: usize,
+    /// Modification time, in nanoseconds since the Unix epoch.
     pub mtime: u64,
+    /// blake3 hex digest of the file's bytes.
     pub hash: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// What is known of some contents, whatever the tokenizer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ContentInfo {
+    pub line_count: usize,
+    pub encoding: TextEncoding,
+    /// Length of the decoded text, to check byte caps without reading it.
+    pub text_len: usize,
+}
+
+/// Token counts for one workspace. Files are known by path, but what was
+/// counted is keyed by content hash and shared by every workspace, so an
+/// identical file in another clone, worktree or vendored copy is never
+/// tokenized twice. The workspace's files are saved to its own log in the
+/// `token_cache` directory, contents and counts to `contents.log`; see
+/// `cache_log`.
+#[derive(Debug, Clone, Default)]
 pub struct TokenCache {
-    #[serde(with = "path_map_serde")]
+    /// The workspace root.
+    root: PathBuf,
     entries: HashMap<PathBuf, CacheEntry>,
-    estimator: TokenEstimator,
-}
-
-mod path_map_serde {
-    use serde::{Deserialize, Deserializer, Serialize, Serializer};
-    use std::collections::HashMap;
-    use std::path::PathBuf;
-
-    pub fn serialize<S>(
-        map: &HashMap<PathBuf, super::CacheEntry>,
-        serializer: S,
-    ) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        let string_map: HashMap<String, super::CacheEntry> = map
-            .iter()
-            .map(|(k, v)| (k.to_string_lossy().into_owned(), v.clone()))
-            .collect();
-        string_map.serialize(serializer)
+    contents: HashMap<String, ContentInfo>,
+    /// Token counts by `tokenizer_id`, then by content hash.
+    counts: HashMap<String, HashMap<String, usize>>,
+    /// The workspace's log, of `entries`.
+    index: LogState,
+    /// The shared log, of `contents` and `counts`.
+    shared: LogState,
+}
+
+/// What of a log is not on disk yet.
+#[derive(Debug, Clone, Default)]
+struct LogState {
+    pending: Vec<Record>,
+    /// Records in the log, including superseded ones.
+    logged: usize,
+    /// Whether the next save rewrites the log instead of appending to it.
+    rewrite: bool,
+}
+
+impl LogState {
+    fn has_changes(&self) -> bool {
+        self.rewrite || !self.pending.is_empty()
     }
 
-    pub fn deserialize<'de, D>(
-        deserializer: D,
-    ) -> Result<HashMap<PathBuf, super::CacheEntry>, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        let string_map: HashMap<String, super::CacheEntry> = HashMap::deserialize(deserializer)?;
-        Ok(string_map
-            .into_iter()
-            .map(|(k, v)| (PathBuf::from(k), v))
-            .collect())
+    /// Counts the pending records as saved. Returns whether they are saved
+    /// by rewriting the log, which is due once superseded records outnumber
+    /// the `live` ones.
+    fn mark_saved(&mut self, live: usize) -> bool {
+        let rewrite = self.rewrite || self.logged + self.pending.len() > 2 * live + REWRITE_SLACK;
+        self.logged = if rewrite {
+            live
+        } else {
+            self.logged + self.pending.len()
+        };
+        self.pending.clear();
+        self.rewrite = false;
+        rewrite
     }
 }
 
+/// Names the tokenizer counts are made with: the estimator and, for
+/// file-backed ones, the tokenizer file with its size and mtime, so a file
+/// replaced at the same path names another tokenizer.
+pub fn tokenizer_id(estimator: &TokenEstimator, tokenizer: Option<&Path>) -> String {
+    let Some(path) = tokenizer else {
+        return estimator.to_string();
+    };
+    let size = std::fs::metadata(path).map(|meta| meta.len());
+    match (size, fs_utils::mtime_nanos(path)) {
+        (Ok(size), Ok(mtime)) => format!("{}@{}#{}:{}", estimator, path.display(), size, mtime),
+        _ => format!("{}@{}", estimator, path.display()),
+    }
+}
+
+/// Keeps saves and loads of the logs from interleaving within this process.
+static LOG_LOCK: Mutex<()> = Mutex::new(());
+
+/// Locked while the logs are read or written, against other running
+/// instances of the app.
+const LOCK_FILE: &str = "cache.lock";
+
+/// Holds both locks on the logs in a cache directory until dropped; closing
+/// the lock file releases the OS lock.
+struct LogGuard {
+    _file: File,
+    _thread: MutexGuard<'static, ()>,
+}
+
+fn lock_logs(dir: &Path) -> io::Result<LogGuard> {
+    let thread = LOG_LOCK.lock().unwrap();
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir.join(LOCK_FILE))?;
+    file.lock_exclusive()?;
+    Ok(LogGuard {
+        _file: file,
+        _thread: thread,
+    })
+}
+
 impl TokenCache {
-    pub async fn new(estimator: TokenEstimator) -> std::io::Result<Self> {
-        let dir = ensure_config_dir()?;
-        let cache_file = dir.join("token_cache.json");
-
-        if let Ok(content) = fs::read_to_string(&cache_file).await {
-            if let Ok(cache) = serde_json::from_str::<TokenCache>(&content) {
-                if cache.estimator == estimator {
-                    return Ok(cache);
-                }
-            }
+    /// A cache with no files; saving it still only adds to the shared log.
+    pub fn empty(root: PathBuf) -> Self {
+        TokenCache {
+            root,
+            index: LogState {
+                rewrite: true,
+                ..LogState::default()
+            },
+            ..Self::default()
         }
+    }
 
-        Ok(TokenCache {
-            entries: HashMap::new(),
-            estimator,
+    /// Loads the cache saved for the workspace at `root`, along with the
+    /// contents shared by every workspace.
+    pub async fn new(root: &Path) -> io::Result<Self> {
+        let dir = ensure_cache_dir()?;
+        let root = root.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            let _lock = lock_logs(&dir)?;
+            Ok(Self::load(&dir, root))
         })
+        .await
+        .map_err(io::Error::other)?
+    }
+
+    fn load(dir: &Path, root: PathBuf) -> Self {
+        let mut cache = Self::empty(root);
+        let (records, index) = read_log(&cache_file(dir, &cache.root));
+        if records.first() == Some(&Record::Root(cache.root.clone())) {
+            cache.index = index;
+            for record in records {
+                cache.apply(record);
+            }
+        }
+        let (records, shared) = read_log(&dir.join(CONTENTS_FILE));
+        cache.shared = shared;
+        for record in records {
+            cache.apply(record);
+        }
+        cache
+    }
+
+    fn apply(&mut self, record: Record) {
+        match record {
+            Record::Root(_) => {}
+            Record::Entry(path, entry) => {
+                self.entries.insert(path, entry);
+            }
+            Record::Content(hash, content) => {
+                self.contents.insert(hash, content);
+            }
+            Record::Count {
+                tokenizer,
+                hash,
+                tokens,
+            } => {
+                self.counts
+                    .entry(tokenizer)
+                    .or_default()
+                    .insert(hash, tokens);
+            }
+            Record::Remove(path) => {
+                self.entries.remove(&path);
+            }
+        }
     }
 
-    pub async fn save(&self) -> std::io::Result<()> {
-        let dir = ensure_config_dir()?;
-        let cache_file = dir.join("token_cache.json");
+    /// Saves in the background, see `save_blocking`.
+    pub async fn save(self) -> io::Result<()> {
+        tokio::task::spawn_blocking(move || self.save_blocking())
+            .await
+            .map_err(io::Error::other)?
+    }
 
-        let content = serde_json::to_string_pretty(self)?;
-        fs::write(cache_file, content).await?;
+    /// Writes the changes, forgetting deleted files, and evicts the least
+    /// recently used workspaces while the caches exceed `MAX_CACHE_BYTES`.
+    pub fn save_blocking(self) -> io::Result<()> {
+        let dir = ensure_cache_dir()?;
+        let _lock = lock_logs(&dir)?;
+        let index_path = self.store(&dir)?;
+        // Cache files of earlier versions
+        let _ = std::fs::remove_file(index_path.with_extension("json"));
+        if let Some(config_dir) = dir.parent() {
+            let _ = std::fs::remove_file(config_dir.join("token_cache.json"));
+        }
         Ok(())
     }
 
+    /// Appends the pending changes to the logs in `dir`, or rewrites them
+    /// whole when due. Returns the path of the workspace's log.
+    fn store(mut self, dir: &Path) -> io::Result<PathBuf> {
+        let index_path = cache_file(dir, &self.root);
+        let removed = self.prune_entries();
+        if self.index.rewrite || !index_path.exists() {
+            cache_log::write_atomic(&index_path, &self.index_records())?;
+        } else {
+            self.index
+                .pending
+                .extend(removed.into_iter().map(Record::Remove));
+            cache_log::append(&index_path, &self.index.pending)?;
+        }
+
+        let shared_path = dir.join(CONTENTS_FILE);
+        // Contents only evicted workspaces had go with a rewrite
+        let lock_path = dir.join(LOCK_FILE);
+        let evicted = evict(
+            dir,
+            MAX_CACHE_BYTES,
+            &[&index_path, &shared_path, &lock_path],
+        )?;
+        if self.shared.rewrite || evicted || !shared_path.exists() {
+            // Keep what other workspaces added since this one was loaded
+            if let Ok(saved) = cache_log::read(&shared_path) {
+                for record in saved.records {
+                    self.apply(record);
+                }
+            }
+            self.prune_contents(dir, &index_path);
+            cache_log::write_atomic(&shared_path, &self.shared_records())?;
+        } else {
+            cache_log::append(&shared_path, &self.shared.pending)?;
+        }
+        Ok(index_path)
+    }
+
+    /// Forgets files that no longer exist and returns their paths.
+    fn prune_entries(&mut self) -> Vec<PathBuf> {
+        let removed: Vec<PathBuf> = self
+            .entries
+            .keys()
+            .filter(|path| !path.is_file())
+            .cloned()
+            .collect();
+        for path in &removed {
+            self.entries.remove(path);
+        }
+        removed
+    }
+
+    /// Forgets contents no file of any workspace in `dir` has any more.
+    /// `index_path` is this workspace's log, whose files are in memory.
+    fn prune_contents(&mut self, dir: &Path, index_path: &Path) {
+        let mut hashes: HashSet<String> = self
+            .entries
+            .values()
+            .map(|entry| entry.hash.clone())
+            .collect();
+        for other in workspace_logs(dir) {
+            if other != index_path {
+                hashes.extend(files_in_log(&other).into_values().map(|entry| entry.hash));
+            }
+        }
+        self.contents.retain(|hash, _| hashes.contains(hash));
+        for counts in self.counts.values_mut() {
+            counts.retain(|hash, _| hashes.contains(hash));
+        }
+        self.counts.retain(|_, counts| !counts.is_empty());
+    }
+
+    fn index_records(&self) -> Vec<Record> {
+        let entries = self
+            .entries
+            .iter()
+            .map(|(path, entry)| Record::Entry(path.clone(), entry.clone()));
+        std::iter::once(Record::Root(self.root.clone()))
+            .chain(entries)
+            .collect()
+    }
+
+    fn shared_records(&self) -> Vec<Record> {
+        let contents = self
+            .contents
+            .iter()
+            .map(|(hash, &content)| Record::Content(hash.clone(), content));
+        let counts = self.counts.iter().flat_map(|(tokenizer, counts)| {
+            counts.iter().map(|(hash, &tokens)| Record::Count {
+                tokenizer: tokenizer.clone(),
+                hash: hash.clone(),
+                tokens,
+            })
+        });
+        contents.chain(counts).collect()
+    }
+
+    /// A copy to save if there are unsaved changes; the cache then counts
+    /// as saved.
+    pub fn take_unsaved(&mut self) -> Option<TokenCache> {
+        if !self.index.has_changes() && !self.shared.has_changes() {
+            return None;
+        }
+        let mut unsaved = self.clone();
+        unsaved.index.rewrite = self.index.mark_saved(1 + self.entries.len());
+        let counts: usize = self.counts.values().map(HashMap::len).sum();
+        unsaved.shared.rewrite = self.shared.mark_saved(self.contents.len() + counts);
+        Some(unsaved)
+    }
+
     pub fn get_entry(&self, path: &Path) -> Option<&CacheEntry> {
         self.entries.get(path)
     }
 
     pub fn insert_entry(&mut self, path: PathBuf, entry: CacheEntry) {
+        self.index
+            .pending
+            .push(Record::Entry(path.clone(), entry.clone()));
         self.entries.insert(path, entry);
     }
 
-    pub fn clear(&mut self) {
-        self.entries.clear();
+    pub fn get_content(&self, hash: &str) -> Option<ContentInfo> {
+        self.contents.get(hash).copied()
+    }
+
+    pub fn insert_content(&mut self, hash: String, content: ContentInfo) {
+        self.shared
+            .pending
+            .push(Record::Content(hash.clone(), content));
+        self.contents.insert(hash, content);
+    }
+
+    /// Tokens in the contents with blake3 `hash`, as counted by `tokenizer`.
+    pub fn get_count(&self, tokenizer: &str, hash: &str) -> Option<usize> {
+        self.counts.get(tokenizer)?.get(hash).copied()
+    }
+
+    pub fn insert_count(&mut self, tokenizer: &str, hash: String, tokens: usize) {
+        self.shared.pending.push(Record::Count {
+            tokenizer: tokenizer.to_string(),
+            hash: hash.clone(),
+            tokens,
+        });
+        self.counts
+            .entry(tokenizer.to_string())
+            .or_default()
+            .insert(hash, tokens);
+    }
+}
+
+/// Upper bound on the size of all cache logs together.
+const MAX_CACHE_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Superseded records tolerated in a small log before it is rewritten.
+const REWRITE_SLACK: usize = 1024;
+
+/// The log of contents and counts shared by every workspace.
+const CONTENTS_FILE: &str = "contents.log";
+
+/// The log of the workspace at `root`, named by a hash of the path.
+fn cache_file(dir: &Path, root: &Path) -> PathBuf {
+    let hash = blake3::hash(root.to_string_lossy().as_bytes());
+    dir.join(format!("{}.log", &hash.to_hex()[..16]))
+}
+
+/// Every workspace log in `dir`.
+fn workspace_logs(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension().is_some_and(|ext| ext == "log")
+                && path.file_name().is_some_and(|name| name != CONTENTS_FILE)
+        })
+        .collect()
+}
+
+/// The files a workspace log ends up with.
+fn files_in_log(path: &Path) -> HashMap<PathBuf, CacheEntry> {
+    let mut files = HashMap::new();
+    let Ok(log) = cache_log::read(path) else {
+        return files;
+    };
+    for record in log.records {
+        match record {
+            Record::Entry(path, entry) => {
+                files.insert(path, entry);
+            }
+            Record::Remove(path) => {
+                files.remove(&path);
+            }
+            _ => {}
+        }
+    }
+    files
+}
+
+/// Reads the log at `path` for replay. A log cut short keeps its intact
+/// records, an unreadable one is set aside as `.corrupt`, and one of an
+/// older format is replaced; all but the intact ones are rewritten on the
+/// next save.
+fn read_log(path: &Path) -> (Vec<Record>, LogState) {
+    let fresh = LogState {
+        rewrite: true,
+        ..LogState::default()
+    };
+    let log = match cache_log::read(path) {
+        Ok(log) => log,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return (Vec::new(), fresh),
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => return (Vec::new(), fresh),
+        Err(e) => {
+            log::warn!("Token cache {} is unreadable: {}", path.display(), e);
+            let _ = std::fs::rename(path, path.with_extension("corrupt"));
+            return (Vec::new(), fresh);
+        }
+    };
+    if log.damaged {
+        log::warn!(
+            "Token cache {} was cut short, keeping its first {} records",
+            path.display(),
+            log.records.len()
+        );
     }
+    // The modification time orders workspaces for eviction
+    let _ = touch(path);
+    let state = LogState {
+        pending: Vec::new(),
+        logged: log.records.len(),
+        rewrite: log.damaged,
+    };
+    (log.records, state)
+}
+
+fn touch(path: &Path) -> io::Result<()> {
+    std::fs::File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(SystemTime::now())
 }
 
-fn ensure_config_dir() -> io::Result<PathBuf> {
+/// Deletes the least recently used cache files in `dir` until they fit in
+/// `max_bytes`, except those in `keep`. Returns whether any was deleted.
+fn evict(dir: &Path, max_bytes: u64, keep: &[&Path]) -> io::Result<bool> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            files.push((metadata.modified()?, metadata.len(), entry.path()));
+        }
+    }
+    let mut total: u64 = files.iter().map(|(_, len, _)| len).sum();
+    files.sort();
+    let mut evicted = false;
+    for (_, len, path) in files {
+        if total <= max_bytes {
+            break;
+        }
+        if !keep.contains(&path.as_path()) {
+            std::fs::remove_file(&path)?;
+            total -= len;
+            evicted = true;
+        }
+    }
+    Ok(evicted)
+}
+
+fn ensure_cache_dir() -> io::Result<PathBuf> {
     let path = dirs_next::config_dir()
         .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Config directory not found"))?
-        .join("context-loader");
+        .join("context-loader")
+        .join("token_cache");
     std::fs::create_dir_all(&path)?;
     Ok(path)
 }
@@ -107,27 +495,283 @@ mod tests {
     use super::*;
     use tempfile::tempdir;
 
-    #[tokio::test]
-    async fn test_cache_operations() {
+    fn hash(content: &str) -> String {
+        blake3::hash(content.as_bytes()).to_hex().to_string()
+    }
+
+    fn entry(hash: &str) -> CacheEntry {
+        CacheEntry {
+            mtime: 123456789,
+            hash: hash.to_string(),
+        }
+    }
+
+    fn content() -> ContentInfo {
+        ContentInfo {
+            line_count: 3,
+            encoding: TextEncoding::Utf8,
+            text_len: 120,
+        }
+    }
+
+    #[test]
+    fn test_cache_operations() {
         let temp_dir = tempdir().unwrap();
         let file_path = temp_dir.path().join("test.txt");
 
-        let mut cache = TokenCache::new(TokenEstimator::Cl100k).await.unwrap();
+        let mut cache = TokenCache::default();
         assert!(cache.get_entry(&file_path).is_none());
 
-        let entry = CacheEntry {
-            token_count: 42,
-            mtime: 123456789,
-            hash: "test_hash".to_string(),
-        };
-        cache.insert_entry(file_path.clone(), entry);
-
+        cache.insert_entry(file_path.clone(), entry("test_hash"));
+        cache.insert_content("test_hash".to_string(), content());
         let retrieved = cache.get_entry(&file_path).unwrap();
-        assert_eq!(retrieved.token_count, 42);
         assert_eq!(retrieved.mtime, 123456789);
         assert_eq!(retrieved.hash.as_str(), "test_hash");
+        assert_eq!(cache.get_content("test_hash"), Some(content()));
+    }
 
-        cache.clear();
-        assert!(cache.get_entry(&file_path).is_none());
+    #[test]
+    fn test_counts_of_every_tokenizer_coexist() {
+        let cl100k = tokenizer_id(&TokenEstimator::Cl100k, None);
+        let chars = tokenizer_id(&TokenEstimator::CharDiv4, None);
+        let mut cache = TokenCache::default();
+        cache.insert_count(&cl100k, "abc".to_string(), 10);
+        cache.insert_count(&chars, "abc".to_string(), 12);
+        assert_eq!(cache.get_count(&cl100k, "abc"), Some(10));
+        assert_eq!(cache.get_count(&chars, "abc"), Some(12));
+        assert_eq!(cache.get_count(&cl100k, "def"), None);
+
+        // Another tokenizer file is another tokenizer
+        let llama = tokenizer_id(
+            &TokenEstimator::Llama2,
+            Some(Path::new("/a/tokenizer.json")),
+        );
+        let moved = tokenizer_id(
+            &TokenEstimator::Llama2,
+            Some(Path::new("/b/tokenizer.json")),
+        );
+        cache.insert_count(&llama, "abc".to_string(), 11);
+        assert_eq!(cache.get_count(&moved, "abc"), None);
+
+        // As is another file at the same path
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("tokenizer.json");
+        std::fs::write(&path, "{}").unwrap();
+        let before = tokenizer_id(&TokenEstimator::Llama2, Some(&path));
+        assert_eq!(before, tokenizer_id(&TokenEstimator::Llama2, Some(&path)));
+        std::fs::write(&path, "{\"model\": {}}").unwrap();
+        assert_ne!(before, tokenizer_id(&TokenEstimator::Llama2, Some(&path)));
+    }
+
+    #[test]
+    fn test_store_and_load_per_workspace() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path();
+        let app = dir.join("app");
+        let lib = dir.join("lib");
+        std::fs::create_dir(&app).unwrap();
+        let file_path = app.join("main.rs");
+        std::fs::write(&file_path, "fn main() {}\n").unwrap();
+        let cl100k = tokenizer_id(&TokenEstimator::Cl100k, None);
+        let abc = hash("abc");
+
+        let mut cache = TokenCache::empty(app.clone());
+        cache.insert_entry(file_path.clone(), entry(&abc));
+        cache.insert_content(abc.clone(), content());
+        cache.insert_count(&cl100k, abc.clone(), 7);
+        let unsaved = cache.take_unsaved().unwrap();
+        assert!(cache.take_unsaved().is_none());
+        unsaved.store(dir).unwrap();
+        TokenCache::empty(lib.clone()).store(dir).unwrap();
+        assert_ne!(cache_file(dir, &app), cache_file(dir, &lib));
+
+        let loaded = TokenCache::load(dir, app.clone());
+        assert_eq!(loaded.get_entry(&file_path).unwrap().hash, abc);
+        assert_eq!(loaded.get_content(&abc), Some(content()));
+        assert_eq!(loaded.get_count(&cl100k, &abc), Some(7));
+        assert!(!loaded.index.has_changes());
+        assert!(!loaded.shared.has_changes());
+
+        // Another workspace has its own files but the same contents
+        let other = TokenCache::load(dir, lib.clone());
+        assert_eq!(other.root, lib);
+        assert!(other.get_entry(&file_path).is_none());
+        assert_eq!(other.get_count(&cl100k, &abc), Some(7));
+    }
+
+    #[test]
+    fn test_saves_append_changes() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path();
+        let root = dir.join("app");
+        std::fs::create_dir(&root).unwrap();
+        let cl100k = tokenizer_id(&TokenEstimator::Cl100k, None);
+        let index = cache_file(dir, &root);
+        let shared = dir.join(CONTENTS_FILE);
+
+        let mut cache = TokenCache::empty(root.clone());
+        let mut sizes = Vec::new();
+        for name in ["a.rs", "b.rs", "c.rs"] {
+            let path = root.join(name);
+            std::fs::write(&path, name).unwrap();
+            cache.insert_entry(path, entry(&hash(name)));
+            cache.insert_content(hash(name), content());
+            cache.insert_count(&cl100k, hash(name), 1);
+            cache.take_unsaved().unwrap().store(dir).unwrap();
+            sizes.push((
+                std::fs::metadata(&index).unwrap().len(),
+                std::fs::metadata(&shared).unwrap().len(),
+            ));
+        }
+        // Each save adds the same records
+        assert_eq!(sizes[2].0 - sizes[1].0, sizes[1].0 - sizes[0].0);
+        assert_eq!(sizes[2].1 - sizes[1].1, sizes[1].1 - sizes[0].1);
+
+        // A deleted file is logged as removed
+        std::fs::remove_file(root.join("b.rs")).unwrap();
+        cache.insert_count(&cl100k, hash("a.rs"), 2);
+        cache.take_unsaved().unwrap().store(dir).unwrap();
+        let loaded = TokenCache::load(dir, root.clone());
+        assert!(loaded.get_entry(&root.join("b.rs")).is_none());
+        assert_eq!(loaded.get_count(&cl100k, &hash("a.rs")), Some(2));
+        assert_eq!(loaded.index.logged, 1 + 3 + 1);
+        assert_eq!(loaded.shared.logged, 6 + 1);
+    }
+
+    #[test]
+    fn test_superseded_records_are_rewritten() {
+        let root = PathBuf::from("/work/app");
+        let mut cache = TokenCache::empty(root);
+        cache.take_unsaved().unwrap();
+        for round in 0..2 * REWRITE_SLACK {
+            cache.insert_count("cl100k", hash("same"), round);
+            let unsaved = cache.take_unsaved().unwrap();
+            assert!(!unsaved.index.rewrite);
+            if unsaved.shared.rewrite {
+                assert_eq!(cache.shared.logged, 1);
+                return;
+            }
+        }
+        panic!("the log was never rewritten");
+    }
+
+    #[test]
+    fn test_damaged_log_is_recovered() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path();
+        let root = dir.join("app");
+        std::fs::create_dir(&root).unwrap();
+        let log = cache_file(dir, &root);
+        let mut cache = TokenCache::empty(root.clone());
+        for name in ["a.rs", "b.rs"] {
+            let path = root.join(name);
+            std::fs::write(&path, name).unwrap();
+            cache.insert_entry(path, entry(&hash(name)));
+        }
+        cache.take_unsaved().unwrap().store(dir).unwrap();
+
+        // A crash in the middle of the last record
+        let len = std::fs::metadata(&log).unwrap().len();
+        std::fs::File::options()
+            .write(true)
+            .open(&log)
+            .unwrap()
+            .set_len(len - 5)
+            .unwrap();
+        let mut recovered = TokenCache::load(dir, root.clone());
+        assert_eq!(recovered.entries.len(), 1);
+        assert!(recovered.index.rewrite);
+        recovered.take_unsaved().unwrap().store(dir).unwrap();
+        let log_after = cache_log::read(&log).unwrap();
+        assert!(!log_after.damaged);
+        assert_eq!(log_after.records.len(), 2);
+
+        // Not a log at all: set aside, not silently dropped
+        std::fs::write(&log, "{ not a log").unwrap();
+        let fresh = TokenCache::load(dir, root);
+        assert!(fresh.entries.is_empty());
+        assert!(log.with_extension("corrupt").exists());
+        assert!(!log.exists());
+    }
+
+    #[test]
+    fn test_contents_of_evicted_workspaces_are_dropped() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path();
+        let cl100k = tokenizer_id(&TokenEstimator::Cl100k, None);
+        let mut workspaces = Vec::new();
+        for name in ["app", "lib", "gone"] {
+            let root = dir.join(name);
+            std::fs::create_dir(&root).unwrap();
+            let path = root.join("main.rs");
+            std::fs::write(&path, name).unwrap();
+            let mut cache = TokenCache::load(dir, root.clone());
+            cache.insert_entry(path, entry(&hash(name)));
+            cache.insert_content(hash(name), content());
+            cache.insert_count(&cl100k, hash(name), 1);
+            cache.take_unsaved().unwrap().store(dir).unwrap();
+            workspaces.push(root);
+        }
+        std::fs::remove_file(cache_file(dir, &workspaces[2])).unwrap();
+
+        // Only "app" is in memory; "lib" is known from its log
+        let mut cache = TokenCache::load(dir, workspaces[0].clone());
+        assert_eq!(cache.get_count(&cl100k, &hash("gone")), Some(1));
+        cache.shared.rewrite = true;
+        cache.store(dir).unwrap();
+        let loaded = TokenCache::load(dir, workspaces[0].clone());
+        assert_eq!(loaded.get_count(&cl100k, &hash("app")), Some(1));
+        assert_eq!(loaded.get_count(&cl100k, &hash("lib")), Some(1));
+        assert_eq!(loaded.get_count(&cl100k, &hash("gone")), None);
+        assert_eq!(loaded.get_content(&hash("gone")), None);
+    }
+
+    #[test]
+    fn test_logs_locked_against_other_processes() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path();
+        // Another open of the lock file stands in for another instance
+        let other = File::create(dir.join(LOCK_FILE)).unwrap();
+        let lock = lock_logs(dir).unwrap();
+        assert!(other.try_lock_exclusive().is_err());
+        drop(lock);
+        other.try_lock_exclusive().unwrap();
+    }
+
+    #[test]
+    fn test_evict_least_recently_used() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path();
+        let now = SystemTime::now();
+        let mut paths = Vec::new();
+        for (name, age) in [
+            ("old", 300),
+            ("older", 400),
+            ("recent", 100),
+            ("current", 500),
+            ("contents", 600),
+        ] {
+            let path = dir.join(format!("{}.log", name));
+            std::fs::write(&path, vec![b'x'; 100]).unwrap();
+            let file = std::fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(now - std::time::Duration::from_secs(age))
+                .unwrap();
+            paths.push(path);
+        }
+        let [old, older, recent, current, contents] = &paths[..] else {
+            unreachable!()
+        };
+
+        // The oldest caches are the ones just saved
+        let keep = [current.as_path(), contents.as_path()];
+        assert!(evict(dir, 350, &keep).unwrap());
+        assert!(!older.exists());
+        assert!(!old.exists());
+        assert!(recent.exists());
+        assert!(current.exists());
+        assert!(contents.exists());
+
+        assert!(!evict(dir, 1_000, &keep).unwrap());
+        assert!(recent.exists());
     }
 }
@@ -0,0 +1,358 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::cache::{CacheEntry, ContentInfo};
+use crate::encoding::TextEncoding;
+
+/// Starts every token cache log, followed by the format version.
+const MAGIC: &[u8; 4] = b"CLTC";
+const VERSION: u8 = 2;
+
+/// Leading bytes of a payload's blake3 digest stored to check it.
+const CHECKSUM_LEN: usize = 8;
+
+/// One change to a token cache. A log is its records in order; replaying
+/// them rebuilds the cache, so saving only appends what changed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record {
+    /// First record of a workspace's log: its root.
+    Root(PathBuf),
+    Entry(PathBuf, CacheEntry),
+    Content(String, ContentInfo),
+    Count {
+        tokenizer: String,
+        hash: String,
+        tokens: usize,
+    },
+    Remove(PathBuf),
+}
+
+/// The records of a log up to the first damaged one.
+#[derive(Debug)]
+pub struct Log {
+    pub records: Vec<Record>,
+    /// Whether bytes after the last intact record were dropped, e.g. left by
+    /// a crash while appending.
+    pub damaged: bool,
+}
+
+/// Reads the log at `path`. A foreign header is an `InvalidData` error and
+/// a log of another format version an `Unsupported` one; damage further on
+/// only ends the log early.
+pub fn read(path: &Path) -> io::Result<Log> {
+    parse(&fs::read(path)?)
+}
+
+pub fn parse(bytes: &[u8]) -> io::Result<Log> {
+    let versioned = bytes
+        .strip_prefix(MAGIC.as_slice())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not a token cache log"))?;
+    let body = versioned.strip_prefix(&[VERSION]).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "token cache log of another version",
+        )
+    })?;
+
+    let mut records = Vec::new();
+    let mut rest = body;
+    while !rest.is_empty() {
+        match next_frame(rest) {
+            Some((record, len)) => {
+                records.push(record);
+                rest = &rest[len..];
+            }
+            None => break,
+        }
+    }
+    Ok(Log {
+        records,
+        damaged: !rest.is_empty(),
+    })
+}
+
+/// Appends `records` to the log at `path`, which must exist.
+pub fn append(path: &Path, records: &[Record]) -> io::Result<()> {
+    let mut bytes = Vec::new();
+    for record in records {
+        write_frame(&mut bytes, record)?;
+    }
+    let mut file = OpenOptions::new().append(true).open(path)?;
+    file.write_all(&bytes)?;
+    file.sync_data()
+}
+
+/// Replaces the log at `path` with `records`. They are written to a
+/// temporary file first, so a crash leaves either the old log or the new.
+pub fn write_atomic(path: &Path, records: &[Record]) -> io::Result<()> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(MAGIC);
+    bytes.push(VERSION);
+    for record in records {
+        write_frame(&mut bytes, record)?;
+    }
+    let temp = path.with_extension("tmp");
+    let mut file = File::create(&temp)?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    fs::rename(&temp, path)
+}
+
+// A frame is the payload length (u32), its checksum, then the payload.
+// Integers are little-endian, strings are length-prefixed UTF-8 and hashes
+// are the raw 32 bytes.
+
+fn write_frame(out: &mut Vec<u8>, record: &Record) -> io::Result<()> {
+    let mut payload = Vec::new();
+    match record {
+        Record::Root(root) => {
+            payload.push(0);
+            put_str(&mut payload, &root.to_string_lossy());
+        }
+        Record::Entry(path, entry) => {
+            payload.push(1);
+            put_str(&mut payload, &path.to_string_lossy());
+            put_u64(&mut payload, entry.mtime);
+            put_hash(&mut payload, &entry.hash)?;
+        }
+        Record::Content(hash, content) => {
+            payload.push(4);
+            put_hash(&mut payload, hash)?;
+            put_u64(&mut payload, content.line_count as u64);
+            put_u64(&mut payload, content.text_len as u64);
+            payload.push(encoding_tag(content.encoding));
+        }
+        Record::Count {
+            tokenizer,
+            hash,
+            tokens,
+        } => {
+            payload.push(2);
+            put_str(&mut payload, tokenizer);
+            put_hash(&mut payload, hash)?;
+            put_u64(&mut payload, *tokens as u64);
+        }
+        Record::Remove(path) => {
+            payload.push(3);
+            put_str(&mut payload, &path.to_string_lossy());
+        }
+    }
+    out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    out.extend_from_slice(&checksum(&payload));
+    out.extend_from_slice(&payload);
+    Ok(())
+}
+
+/// The record at the start of `bytes` and the length of its frame, or None
+/// if the frame is cut short or fails its checksum.
+fn next_frame(bytes: &[u8]) -> Option<(Record, usize)> {
+    let len = u32::from_le_bytes(bytes.get(..4)?.try_into().ok()?) as usize;
+    let sum = bytes.get(4..4 + CHECKSUM_LEN)?;
+    let start = 4 + CHECKSUM_LEN;
+    let payload = bytes.get(start..start.checked_add(len)?)?;
+    if checksum(payload) != sum {
+        return None;
+    }
+    Some((decode(payload)?, start + len))
+}
+
+fn decode(payload: &[u8]) -> Option<Record> {
+    let mut reader = Reader(payload);
+    let record = match reader.u8()? {
+        0 => Record::Root(PathBuf::from(reader.str()?)),
+        1 => {
+            let path = PathBuf::from(reader.str()?);
+            let entry = CacheEntry {
+                mtime: reader.u64()?,
+                hash: reader.hash()?,
+            };
+            Record::Entry(path, entry)
+        }
+        2 => Record::Count {
+            tokenizer: reader.str()?,
+            hash: reader.hash()?,
+            tokens: reader.u64()? as usize,
+        },
+        3 => Record::Remove(PathBuf::from(reader.str()?)),
+        4 => {
+            let hash = reader.hash()?;
+            let content = ContentInfo {
+                line_count: reader.u64()? as usize,
+                text_len: reader.u64()? as usize,
+                encoding: encoding_from_tag(reader.u8()?)?,
+            };
+            Record::Content(hash, content)
+        }
+        _ => return None,
+    };
+    reader.0.is_empty().then_some(record)
+}
+
+fn checksum(payload: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let digest = blake3::hash(payload);
+    let mut sum = [0; CHECKSUM_LEN];
+    sum.copy_from_slice(&digest.as_bytes()[..CHECKSUM_LEN]);
+    sum
+}
+
+fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_str(out: &mut Vec<u8>, value: &str) {
+    out.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    out.extend_from_slice(value.as_bytes());
+}
+
+fn put_hash(out: &mut Vec<u8>, hex: &str) -> io::Result<()> {
+    let hash =
+        blake3::Hash::from_hex(hex).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    out.extend_from_slice(hash.as_bytes());
+    Ok(())
+}
+
+fn encoding_tag(encoding: TextEncoding) -> u8 {
+    match encoding {
+        TextEncoding::Utf8 => 0,
+        TextEncoding::Utf8Bom => 1,
+        TextEncoding::Utf16Le => 2,
+        TextEncoding::Utf16Be => 3,
+        TextEncoding::Windows1252 => 4,
+    }
+}
+
+fn encoding_from_tag(tag: u8) -> Option<TextEncoding> {
+    Some(match tag {
+        0 => TextEncoding::Utf8,
+        1 => TextEncoding::Utf8Bom,
+        2 => TextEncoding::Utf16Le,
+        3 => TextEncoding::Utf16Be,
+        4 => TextEncoding::Windows1252,
+        _ => return None,
+    })
+}
+
+/// Reads fields off the front of a payload.
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Option<&[u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn str(&mut self) -> Option<String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).ok()
+    }
+
+    fn hash(&mut self) -> Option<String> {
+        let bytes: [u8; 32] = self.take(32)?.try_into().ok()?;
+        Some(blake3::Hash::from(bytes).to_hex().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn records() -> Vec<Record> {
+        let hash = blake3::hash(b"fn main() {}\n").to_hex().to_string();
+        vec![
+            Record::Root(PathBuf::from("/work/app")),
+            Record::Entry(
+                PathBuf::from("/work/app/main.rs"),
+                CacheEntry {
+                    mtime: 1_700_000_000_123_456_789,
+                    hash: hash.clone(),
+                },
+            ),
+            Record::Content(
+                hash.clone(),
+                ContentInfo {
+                    line_count: 1,
+                    encoding: TextEncoding::Utf16Le,
+                    text_len: 13,
+                },
+            ),
+            Record::Count {
+                tokenizer: "cl100k".to_string(),
+                hash,
+                tokens: 5,
+            },
+            Record::Remove(PathBuf::from("/work/app/old.rs")),
+        ]
+    }
+
+    #[test]
+    fn test_write_append_and_read() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("cache.log");
+        let records = records();
+
+        write_atomic(&path, &records[..2]).unwrap();
+        assert!(!path.with_extension("tmp").exists());
+        append(&path, &records[2..]).unwrap();
+
+        let log = read(&path).unwrap();
+        assert_eq!(log.records, records);
+        assert!(!log.damaged);
+    }
+
+    #[test]
+    fn test_damaged_tail_keeps_intact_records() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("cache.log");
+        let records = records();
+        write_atomic(&path, &records).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        let full_len = bytes.len();
+
+        // Cut at every byte: only whole records survive
+        let mut boundaries = vec![MAGIC.len() + 1];
+        let mut frames = Vec::new();
+        for record in &records {
+            write_frame(&mut frames, record).unwrap();
+            boundaries.push(MAGIC.len() + 1 + frames.len());
+        }
+        for cut in MAGIC.len() + 1..full_len {
+            let log = parse(&bytes[..cut]).unwrap();
+            let intact = boundaries.iter().filter(|&&end| end <= cut).count() - 1;
+            assert_eq!(log.records, records[..intact]);
+            assert_eq!(log.damaged, !boundaries.contains(&cut));
+        }
+
+        // A flipped bit fails the checksum of its record
+        let mut corrupt = bytes.clone();
+        corrupt[full_len - 3] ^= 0x10;
+        let log = parse(&corrupt).unwrap();
+        assert_eq!(log.records, records[..records.len() - 1]);
+        assert!(log.damaged);
+    }
+
+    #[test]
+    fn test_foreign_file_is_rejected() {
+        let err = parse(b"{\"root\": \"/work/app\"}").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = parse(b"CLTC\x01").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+}
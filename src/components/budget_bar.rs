@@ -0,0 +1,49 @@
+use crate::models::{BudgetLevel, TargetModel};
+use dioxus::prelude::*;
+
+#[derive(Props, Clone, PartialEq)]
+pub struct BudgetBarProps {
+    used: usize,
+    model: TargetModel,
+}
+
+/// How much of the target model's input budget (its context window minus
+/// the output reserve) the bundle uses, amber from `WARNING_RATIO` and red
+/// once over.
+#[component]
+pub fn BudgetBar(props: BudgetBarProps) -> Element {
+    let BudgetBarProps { used, model } = props;
+
+    let budget = model.input_budget();
+    let percent = if budget == 0 {
+        100.0
+    } else {
+        (used as f64 / budget as f64 * 100.0).min(100.0)
+    };
+    let (bar_class, text_class) = match model.budget_level(used) {
+        BudgetLevel::Ok => ("bg-green-500", "text-light-foreground"),
+        BudgetLevel::Warning => ("bg-yellow-500", "text-yellow-700"),
+        BudgetLevel::Over => ("bg-red-500", "text-red-500 font-medium"),
+    };
+    let remaining = if used > budget {
+        format!("{} over", used - budget)
+    } else {
+        format!("{} left", budget - used)
+    };
+
+    rsx! {
+        div {
+            class: "flex items-center space-x-2 text-sm",
+            title: "{model.name}: {model.context_window} token context, {model.output_reserve} reserved for the answer",
+            div {
+                class: "w-40 h-2 bg-light-border rounded overflow-hidden",
+                div {
+                    class: "h-full {bar_class}",
+                    style: "width: {percent:.1}%",
+                }
+            }
+            span { class: "{text_class}", "{used} / {budget}" }
+            span { class: "{text_class}", "({remaining})" }
+        }
+    }
+}
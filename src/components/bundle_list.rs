@@ -0,0 +1,74 @@
+use crate::bundle_order::move_item;
+use dioxus::prelude::*;
+use std::path::PathBuf;
+
+#[derive(Props, Clone, PartialEq)]
+pub struct BundleListProps {
+    /// Selected files in their current bundle order.
+    paths: Vec<PathBuf>,
+    workspace_root: PathBuf,
+    on_reorder: EventHandler<Vec<PathBuf>>,
+}
+
+/// The files that will be copied, in order. Rows can be dragged to set the
+/// manual order.
+#[component]
+pub fn BundleList(props: BundleListProps) -> Element {
+    let BundleListProps {
+        paths,
+        workspace_root,
+        on_reorder,
+    } = props;
+
+    let mut dragging = use_signal(|| None::<usize>);
+
+    rsx! {
+        div {
+            class: "w-80 flex-shrink-0 overflow-auto p-4 border-l border-light-border bg-light-card",
+            div {
+                class: "text-sm font-medium text-light-foreground mb-2",
+                "Bundle order"
+            }
+            if paths.is_empty() {
+                div {
+                    class: "text-sm text-light-secondary-text",
+                    "No files selected"
+                }
+            }
+            ol {
+                class: "space-y-1",
+                for (index, path) in paths.iter().enumerate() {
+                    li {
+                        key: "{path.display()}",
+                        class: if *dragging.read() == Some(index) {
+                            "px-2 py-1 text-sm rounded border border-blue-500 opacity-50 cursor-move"
+                        } else {
+                            "px-2 py-1 text-sm rounded border border-light-border bg-light-background cursor-move"
+                        },
+                        draggable: "true",
+                        title: "{path.display()}",
+                        ondragstart: move |_| dragging.set(Some(index)),
+                        ondragend: move |_| dragging.set(None),
+                        ondragover: move |evt| evt.prevent_default(),
+                        ondrop: {
+                            let paths = paths.clone();
+                            move |evt: DragEvent| {
+                                evt.prevent_default();
+                                if let Some(from) = dragging.take() {
+                                    let mut reordered = paths.clone();
+                                    move_item(&mut reordered, from, index);
+                                    on_reorder.call(reordered);
+                                }
+                            }
+                        },
+                        span {
+                            class: "text-light-secondary-text mr-2",
+                            "{index + 1}."
+                        }
+                        "{path.strip_prefix(&workspace_root).unwrap_or(path).display()}"
+                    }
+                }
+            }
+        }
+    }
+}
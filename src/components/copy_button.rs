@@ -1,22 +1,97 @@
-use crate::fs_utils::concat_files;
+use crate::budget::PackPlan;
+use crate::bundle_order::{order_paths, OrderPolicy};
+use crate::components::file_tree::{build_tree_from_file_info, render_ascii_tree};
+use crate::fs_utils::{concat_files, BundleOptions, FileInfo, TruncationPolicy};
+use crate::output_format::{OutputFormat, OutputTemplate};
+use crate::tokenizer::TokenEstimator;
 use arboard::Clipboard;
 use dioxus::prelude::*;
 use std::collections::HashSet;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tracing::error;
 
 #[derive(Props, Clone, PartialEq)]
 pub struct CopyButtonProps {
     pub selected_files: Signal<HashSet<PathBuf>>,
+    pub output_format: OutputFormat,
+    pub templates: Vec<OutputTemplate>,
+    pub estimator: TokenEstimator,
+    /// Prepend an ASCII tree of the workspace (minus .gitignored files) to the bundle.
+    #[props(default)]
+    pub include_tree: bool,
+    /// Relative paths the root .gitignore keeps, listed when the workspace opened.
+    pub non_ignored_files: Signal<HashSet<String>>,
+    #[props(default)]
+    pub all_files: Vec<FileInfo>,
+    #[props(default)]
+    pub workspace_root: PathBuf,
+    /// Line-number width, or `None` to copy lines as-is.
+    #[props(default)]
+    pub line_numbers: Option<usize>,
+    #[props(default)]
+    pub binary_placeholders: bool,
+    /// Per-file cap, or `None` to copy files whole.
+    #[props(default)]
+    pub truncation: Option<TruncationPolicy>,
+    /// Budget packing plan for the current selection, if a budget is set.
+    #[props(default)]
+    pub packing: Option<PackPlan>,
+    #[props(default)]
+    pub order_policy: OrderPolicy,
+    /// Drag order from the bundle list, used by `OrderPolicy::Manual`.
+    #[props(default)]
+    pub manual_order: Vec<PathBuf>,
     pub on_copy: EventHandler<Result<(), String>>,
     #[props(default)]
     pub id: Option<&'static str>,
 }
 
+/// The ASCII tree of the workspace prepended to a bundle: files .gitignore
+/// keeps (`non_ignored`, relative to the root), plus the selected files even
+/// if ignored.
+pub fn bundle_tree(
+    all_files: &[FileInfo],
+    selected_files: &HashSet<PathBuf>,
+    workspace_root: &Path,
+    non_ignored: &HashSet<String>,
+) -> String {
+    let tree_files: Vec<FileInfo> = all_files
+        .iter()
+        .filter(|file| {
+            selected_files.contains(&file.path)
+                || file
+                    .path
+                    .strip_prefix(workspace_root)
+                    .map(|rel| non_ignored.contains(&*rel.to_string_lossy()))
+                    .unwrap_or(false)
+        })
+        .cloned()
+        .collect();
+    let blueprints = build_tree_from_file_info(&tree_files, selected_files, workspace_root);
+    let root_name = workspace_root
+        .file_name()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| ".".to_string());
+    render_ascii_tree(&root_name, &blueprints)
+}
+
 #[component]
 pub fn CopyButton(props: CopyButtonProps) -> Element {
     let CopyButtonProps {
         selected_files,
+        output_format,
+        templates,
+        estimator,
+        include_tree,
+        non_ignored_files,
+        all_files,
+        workspace_root,
+        line_numbers,
+        binary_placeholders,
+        truncation,
+        packing,
+        order_policy,
+        manual_order,
         on_copy,
         id,
     } = props;
@@ -29,6 +104,15 @@ pub fn CopyButton(props: CopyButtonProps) -> Element {
             return;
         }
 
+        let formatter = match output_format.formatter(&templates) {
+            Ok(formatter) => formatter,
+            Err(e) => {
+                error!("Failed to resolve output format: {}", e);
+                on_copy.call(Err(e));
+                return;
+            }
+        };
+
         is_copying.set(true);
         let mut clipboard = match Clipboard::new() {
             Ok(clipboard) => clipboard,
@@ -40,12 +124,34 @@ pub fn CopyButton(props: CopyButtonProps) -> Element {
             }
         };
 
-        let paths: Vec<PathBuf> = selected_files.iter().cloned().collect();
+        let tree = include_tree.then(|| {
+            bundle_tree(
+                &all_files,
+                &selected_files,
+                &workspace_root,
+                &non_ignored_files.read(),
+            )
+        });
+
+        let paths = order_paths(&selected_files, order_policy, &all_files, &manual_order);
+        let packing = packing.clone();
+        let estimator = estimator.clone();
 
         // Handle the async result
         spawn(async move {
-            // First, concatenate the files asynchronously
-            let content_result = concat_files(&paths).await;
+            // First, concatenate the files; reading and tokenizing them
+            // blocks, so it runs on tokio's blocking pool
+            let content_result = tokio::task::spawn_blocking(move || {
+                let mut options = BundleOptions::new(formatter.as_ref(), &estimator);
+                options.tree = tree;
+                options.line_numbers = line_numbers;
+                options.binary_placeholders = binary_placeholders;
+                options.truncation = truncation;
+                options.packing = packing;
+                concat_files(&paths, &options)
+            })
+            .await
+            .unwrap_or_else(|e| Err(std::io::Error::other(e)));
 
             // Then handle the clipboard operation based on the result
             let copy_result = match content_result {
@@ -0,0 +1,82 @@
+use crate::tokenizer::{CustomTokenizer, SplitPattern, TokenEstimator};
+use dioxus::prelude::*;
+
+#[derive(Props, Clone, PartialEq)]
+pub struct CustomTokenizerControlsProps {
+    current_estimator: TokenEstimator,
+    on_add: EventHandler<CustomTokenizer>,
+    on_remove: EventHandler<String>,
+}
+
+/// Toolbar controls to register a tokenizer file under a name, and to remove
+/// the selected custom tokenizer.
+#[component]
+pub fn CustomTokenizerControls(props: CustomTokenizerControlsProps) -> Element {
+    let CustomTokenizerControlsProps {
+        current_estimator,
+        on_add,
+        on_remove,
+    } = props;
+
+    let mut name = use_signal(String::new);
+    let mut split_pattern = use_signal(SplitPattern::default);
+
+    rsx! {
+        div {
+            class: "flex items-center space-x-2 text-sm text-light-foreground",
+            input {
+                "type": "text",
+                class: "w-32 px-2 py-1 bg-light-background border border-light-border rounded",
+                placeholder: "Tokenizer name",
+                value: "{name}",
+                oninput: move |evt| name.set(evt.value()),
+            }
+            select {
+                class: "px-2 py-1 bg-light-background border border-light-border rounded",
+                title: "How a .tiktoken file splits text before ranking; it does not record its own. Pick the encoding its vocabulary derives from.",
+                value: "{split_pattern}",
+                onchange: move |evt| {
+                    if let Ok(pattern) = evt.value().parse() {
+                        split_pattern.set(pattern);
+                    }
+                },
+                for pattern in SplitPattern::ALL {
+                    option { value: "{pattern}", "{pattern.name()}" }
+                }
+            }
+            button {
+                class: "px-3 py-1 bg-light-background border border-light-border rounded",
+                title: "Import a Hugging Face tokenizer.json or a tiktoken .tiktoken file",
+                onclick: move |_| {
+                    let Some(path) = rfd::FileDialog::new()
+                        .add_filter("Tokenizer", &["json", "tiktoken"])
+                        .pick_file() else {
+                        return;
+                    };
+                    let typed = name.read().trim().to_string();
+                    // Default to the file name without its extension
+                    let name_for_file = if typed.is_empty() {
+                        path.file_stem().unwrap_or_default().to_string_lossy().into_owned()
+                    } else {
+                        typed
+                    };
+                    on_add.call(CustomTokenizer {
+                        name: name_for_file,
+                        path,
+                        split_pattern: *split_pattern.read(),
+                    });
+                    name.set(String::new());
+                },
+                "Import tokenizer…"
+            }
+            if let TokenEstimator::Custom(current) = current_estimator {
+                button {
+                    class: "px-3 py-1 text-red-700 bg-light-background border border-light-border rounded",
+                    title: "Remove the {current} tokenizer",
+                    onclick: move |_| on_remove.call(current.clone()),
+                    "Remove"
+                }
+            }
+        }
+    }
+}
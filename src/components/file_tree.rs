@@ -1,4 +1,5 @@
-use crate::fs_utils::FileInfo;
+use crate::encoding::TextEncoding;
+use crate::fs_utils::{FileInfo, Omitted};
 use dioxus::prelude::*;
 use dioxus_desktop::use_window;
 use log;
@@ -29,6 +30,10 @@ pub struct FileTreeNodeBlueprint {
     pub is_expanded: bool,
     pub selection_state: NodeSelectionState,
     pub depth: usize,
+    pub is_binary: bool,
+    pub encoding: TextEncoding,
+    /// What the per-file cap dropped, if the file is truncated.
+    pub omitted: Option<Omitted>,
 }
 
 // This is the struct used for display, containing Dioxus Signals
@@ -42,6 +47,9 @@ pub struct FileTreeNode {
     pub is_expanded: Signal<bool>,
     pub selection_state: Signal<NodeSelectionState>,
     pub depth: usize,
+    pub is_binary: bool,
+    pub encoding: TextEncoding,
+    pub omitted: Option<Omitted>,
 }
 
 impl FileTreeNode {
@@ -64,6 +72,20 @@ impl FileTreeNode {
         }
         paths
     }
+
+    // Like collect_all_file_paths_recursive, but skips binary files so checking
+    // a folder never pulls them into the selection
+    pub fn collect_selectable_file_paths_recursive(&self) -> Vec<PathBuf> {
+        match self.node_type {
+            TreeNodeType::File if self.is_binary => Vec::new(),
+            TreeNodeType::File => vec![self.path.clone()],
+            TreeNodeType::Folder => self
+                .children
+                .iter()
+                .flat_map(|child| child.collect_selectable_file_paths_recursive())
+                .collect(),
+        }
+    }
 }
 
 // Helper to find or create a blueprint node in a list of children blueprints
@@ -93,6 +115,9 @@ fn find_or_create_blueprint_node<'a>(
             is_expanded: if depth == 0 { true } else { is_root_folder },
             selection_state: NodeSelectionState::NotSelected,
             depth,
+            is_binary: false,
+            encoding: TextEncoding::default(),
+            omitted: None,
         };
         children.push(new_node);
         children.last_mut().unwrap()
@@ -181,6 +206,9 @@ pub fn build_tree_from_file_info(
                         is_expanded: false,
                         selection_state: selection,
                         depth: idx, // Depth is based on iteration over relative components
+                        is_binary: file_info.is_binary,
+                        encoding: file_info.encoding,
+                        omitted: file_info.truncated.map(|t| t.omitted),
                     };
                     current_parent_children_list.push(file_node);
                 }
@@ -203,6 +231,37 @@ pub fn build_tree_from_file_info(
     final_roots
 }
 
+/// Renders blueprints as an ASCII tree for the bundle preamble. Files whose
+/// blueprint is not `Selected` are marked "(omitted)" so the model still sees
+/// the full layout.
+pub fn render_ascii_tree(root_name: &str, nodes: &[FileTreeNodeBlueprint]) -> String {
+    let mut tree = format!("{}/\n", root_name);
+    render_ascii_tree_level(nodes, "", &mut tree);
+    tree
+}
+
+fn render_ascii_tree_level(nodes: &[FileTreeNodeBlueprint], prefix: &str, out: &mut String) {
+    for (idx, node) in nodes.iter().enumerate() {
+        let is_last = idx == nodes.len() - 1;
+        out.push_str(prefix);
+        out.push_str(if is_last { "└── " } else { "├── " });
+        out.push_str(&node.name);
+        match node.node_type {
+            TreeNodeType::Folder => {
+                out.push_str("/\n");
+                let child_prefix = format!("{}{}", prefix, if is_last { "    " } else { "│   " });
+                render_ascii_tree_level(&node.children, &child_prefix, out);
+            }
+            TreeNodeType::File => {
+                if node.selection_state != NodeSelectionState::Selected {
+                    out.push_str(" (omitted)");
+                }
+                out.push('\n');
+            }
+        }
+    }
+}
+
 // Recursive function to convert blueprints to signal-based FileTreeNodes
 // This must be called within a Dioxus component/hook context for Signal::new to work.
 // Making it pub(crate) for testing the full tree construction and update logic.
@@ -226,7 +285,13 @@ pub(crate) fn convert_blueprint_to_file_tree_node_recursive(
         current_node_selection_state = blueprint.selection_state;
     } else {
         // It's a Folder
-        if children_nodes.is_empty() {
+        // Binary files can't be selected by folder toggles, so they don't count
+        // towards the folder's state
+        let selectable_children: Vec<&FileTreeNode> = children_nodes
+            .iter()
+            .filter(|child| !(child.node_type == TreeNodeType::File && child.is_binary))
+            .collect();
+        if selectable_children.is_empty() {
             // An empty folder has no selected children, so it's NotSelected.
             // Or, if folders themselves can be selected, this might need parent's `selected_paths` check.
             // For now, assuming empty folders are NotSelected unless `selected_paths` implies otherwise
@@ -237,7 +302,7 @@ pub(crate) fn convert_blueprint_to_file_tree_node_recursive(
             let mut any_child_selected = false;
             let mut any_child_partially_selected = false;
 
-            for child_node in &children_nodes {
+            for child_node in &selectable_children {
                 // Iterate over the newly created child FileTreeNodes
                 let child_state = *child_node.selection_state.read(); // Read from the child's signal
                 match child_state {
@@ -275,6 +340,9 @@ pub(crate) fn convert_blueprint_to_file_tree_node_recursive(
         // Initialize the signal directly with the calculated state.
         selection_state: Signal::new_in_scope(current_node_selection_state, scope_id),
         depth: blueprint.depth,
+        is_binary: blueprint.is_binary,
+        encoding: blueprint.encoding,
+        omitted: blueprint.omitted,
     }
 }
 
@@ -285,6 +353,8 @@ pub struct FileTreeProps {
     pub on_select_all: EventHandler<()>,
     pub on_deselect_all: EventHandler<()>,
     pub workspace_root: PathBuf,
+    /// Files packed first when a token budget is set.
+    pub pinned_paths: Signal<HashSet<PathBuf>>,
 }
 
 #[allow(non_snake_case)]
@@ -335,7 +405,7 @@ pub fn FileTree(props: FileTreeProps) -> Element {
                     onclick: move |_| {
                         let mut all_file_paths_hs = HashSet::new();
                         // Use the cloned all_files specific to this button
-                        for file_info in &all_files_clone_for_buttons { // Use the clone
+                        for file_info in all_files_clone_for_buttons.iter().filter(|f| !f.is_binary) { // Use the clone
                             all_file_paths_hs.insert(file_info.path.clone());
                         }
                         // Signal is Copy, get a mutable copy for set()
@@ -361,6 +431,7 @@ pub fn FileTree(props: FileTreeProps) -> Element {
                         key: "{node.id}",
                         node: node.clone(),
                         selected_paths: selected_paths_for_buttons,
+                        pinned_paths: props.pinned_paths,
                     }
                 }
             }
@@ -374,6 +445,7 @@ pub fn FileTree(props: FileTreeProps) -> Element {
 pub struct FileTreeNodeDisplayProps {
     pub node: FileTreeNode,
     pub selected_paths: Signal<HashSet<PathBuf>>,
+    pub pinned_paths: Signal<HashSet<PathBuf>>,
 }
 
 #[allow(non_snake_case)]
@@ -402,6 +474,9 @@ pub fn FileTreeNodeDisplay(props: FileTreeNodeDisplayProps) -> Element {
     // Clone necessary props for the oninput closure
     let node_for_input = props.node.clone();
     let mut selected_paths_signal = props.selected_paths; // This is already a Signal
+    let mut pinned_paths_signal = props.pinned_paths;
+    let pin_path = props.node.path.clone();
+    let is_pinned = pinned_paths_signal.read().contains(&props.node.path);
 
     use_effect(move || {
         // Script to set indeterminate state
@@ -459,7 +534,12 @@ pub fn FileTreeNodeDisplay(props: FileTreeNodeDisplayProps) -> Element {
                                 }
                             }
                             TreeNodeType::Folder => {
-                                let descendant_file_paths = node_for_input.collect_all_file_paths_recursive();
+                                // Checking a folder skips binary files; unchecking clears everything
+                                let descendant_file_paths = if is_checked {
+                                    node_for_input.collect_selectable_file_paths_recursive()
+                                } else {
+                                    node_for_input.collect_all_file_paths_recursive()
+                                };
                                 for path in descendant_file_paths {
                             if is_checked {
                                         selected_paths_writer.insert(path);
@@ -483,9 +563,39 @@ pub fn FileTreeNodeDisplay(props: FileTreeNodeDisplayProps) -> Element {
                     "{icon}"
             }
             span {
-                    class: "node-name",
+                    class: if props.node.is_binary { "node-name text-light-secondary-text opacity-60" } else { "node-name" },
+                    title: if props.node.is_binary { "Binary file" } else { "" },
                     "{props.node.name}"
             }
+            // Only non-UTF-8 files get a badge, to keep the tree quiet
+            if props.node.node_type == TreeNodeType::File && !props.node.is_binary && props.node.encoding != TextEncoding::Utf8 {
+                span {
+                    class: "ml-2 px-1 text-xs rounded border border-light-border text-light-secondary-text",
+                    title: "Transcoded to UTF-8 when copied",
+                    "{props.node.encoding}"
+                }
+            }
+            if props.node.node_type == TreeNodeType::File && !props.node.is_binary {
+                button {
+                    class: if is_pinned { "ml-2 text-xs" } else { "ml-2 text-xs opacity-30 hover:opacity-70" },
+                    title: if is_pinned { "Unpin" } else { "Pin: packed before other files when a token budget is set" },
+                    onclick: move |evt| {
+                        evt.stop_propagation();
+                        let mut pinned = pinned_paths_signal.write();
+                        if !pinned.remove(&pin_path) {
+                            pinned.insert(pin_path.clone());
+                        }
+                    },
+                    "📌"
+                }
+            }
+            if let Some(omitted) = props.node.omitted {
+                span {
+                    class: "ml-2 px-1 text-xs rounded border border-yellow-500 text-yellow-700",
+                    title: "{omitted.marker()}",
+                    "truncated"
+                }
+            }
         }
         if props.node.node_type == TreeNodeType::Folder && *props.node.is_expanded.read() {
                 ul {
@@ -495,6 +605,7 @@ pub fn FileTreeNodeDisplay(props: FileTreeNodeDisplayProps) -> Element {
                             key: "{child_node.id}",
                     node: child_node.clone(),
                     selected_paths: props.selected_paths,
+                    pinned_paths: props.pinned_paths,
                         }
                     }
                 }
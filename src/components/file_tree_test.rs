@@ -1,8 +1,9 @@
 #![cfg(test)]
 use crate::components::file_tree::{
-    build_tree_from_file_info, convert_blueprint_to_file_tree_node_recursive, FileTreeNode,
-    FileTreeNodeBlueprint, NodeSelectionState, TreeNodeType,
+    build_tree_from_file_info, convert_blueprint_to_file_tree_node_recursive, render_ascii_tree,
+    FileTreeNode, FileTreeNodeBlueprint, NodeSelectionState, TreeNodeType,
 };
+use crate::encoding::TextEncoding;
 use crate::fs_utils::FileInfo;
 use dioxus::prelude::*;
 use futures_util::FutureExt;
@@ -22,6 +23,12 @@ fn create_file_info(path_str: &str, workspace_root_for_test: &Path) -> FileInfo
         path: absolute_path,
         size: 0,        // Not relevant for tree structure
         token_count: 0, // Not relevant for tree structure
+        line_count: 0,  // Not relevant for tree structure
+        counted: false,
+        is_binary: false,
+        encoding: TextEncoding::Utf8,
+        truncated: None,
+        modified: None,
     }
 }
 
@@ -34,6 +41,34 @@ fn test_build_tree_empty() {
     assert!(tree.is_empty(), "Tree should be empty for no input files");
 }
 
+#[test]
+fn test_render_ascii_tree_marks_omitted_files() {
+    let workspace_root = Path::new("/test_ws");
+    let files = vec![
+        create_file_info("src/main.rs", workspace_root),
+        create_file_info("src/components/button.rs", workspace_root),
+        create_file_info("README.md", workspace_root),
+        create_file_info("src/components/mod.rs", workspace_root),
+    ];
+    let mut selected_paths = HashSet::new();
+    selected_paths.insert(workspace_root.join("src/main.rs"));
+    selected_paths.insert(workspace_root.join("src/components/button.rs"));
+
+    let tree = build_tree_from_file_info(&files, &selected_paths, workspace_root);
+    let rendered = render_ascii_tree("test_ws", &tree);
+
+    assert_eq!(
+        rendered,
+        "test_ws/\n\
+         ├── README.md (omitted)\n\
+         └── src/\n    \
+         ├── components/\n    \
+         │   ├── button.rs\n    \
+         │   └── mod.rs (omitted)\n    \
+         └── main.rs\n"
+    );
+}
+
 #[test]
 fn test_build_tree_flat_list() {
     let workspace_root = Path::new("/test_ws");
@@ -465,6 +500,7 @@ mod story_10_tests {
         build_tree_from_file_info, convert_blueprint_to_file_tree_node_recursive, FileTreeNode,
         NodeSelectionState, TreeNodeType,
     };
+    use crate::encoding::TextEncoding;
     use crate::fs_utils::FileInfo;
 
     // Helper to create a FileTreeNode (File type) for testing
@@ -485,6 +521,9 @@ mod story_10_tests {
             is_expanded: Signal::new_in_scope(false, scope_id),
             selection_state: Signal::new_in_scope(selection_state, scope_id),
             depth,
+            is_binary: false,
+            encoding: TextEncoding::Utf8,
+            omitted: None,
         }
     }
 
@@ -508,6 +547,9 @@ mod story_10_tests {
             is_expanded: Signal::new_in_scope(is_expanded, scope_id),
             selection_state: Signal::new_in_scope(selection_state, scope_id),
             depth,
+            is_binary: false,
+            encoding: TextEncoding::Utf8,
+            omitted: None,
         }
     }
 
@@ -1140,4 +1182,34 @@ mod story_10_tests {
             vdom.render_immediate(&mut NoOpMutations);
         }
     }
+
+    #[test]
+    fn test_binary_files_do_not_affect_folder_selection() {
+        fn app_binary_files() -> Element {
+            rsx! { div {} }
+        }
+        let mut vdom = VirtualDom::new(app_binary_files);
+        vdom.rebuild_in_place();
+
+        vdom.in_runtime(|| {
+            let workspace_root = Path::new("/test_ws_binary");
+            let mut image = create_file_info("assets/logo.png", workspace_root);
+            image.is_binary = true;
+            let files = vec![create_file_info("assets/style.css", workspace_root), image];
+            let selected: HashSet<PathBuf> = [workspace_root.join("assets/style.css")]
+                .into_iter()
+                .collect();
+
+            let blueprints = build_tree_from_file_info(&files, &selected, workspace_root);
+            let folder =
+                convert_blueprint_to_file_tree_node_recursive(blueprints[0].clone(), ScopeId::ROOT);
+
+            assert_eq!(*folder.selection_state.read(), NodeSelectionState::Selected);
+            assert_eq!(
+                folder.collect_selectable_file_paths_recursive(),
+                vec![workspace_root.join("assets/style.css")]
+            );
+            assert_eq!(folder.collect_all_file_paths_recursive().len(), 2);
+        });
+    }
 }
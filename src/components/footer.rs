@@ -5,7 +5,10 @@ use dioxus::prelude::*;
 use std::collections::HashSet;
 use std::path::PathBuf;
 
-use crate::fs_utils::FileInfo;
+use crate::budget::{PackDecision, PackPlan};
+use crate::components::BudgetBar;
+use crate::fs_utils::{FileInfo, LineNumberCost};
+use crate::models::{format_usd, BudgetLevel, ModelPrice, TargetModel};
 use crate::tokenizer::TokenEstimator;
 
 #[derive(Props, Clone, PartialEq)]
@@ -13,6 +16,45 @@ pub struct FooterProps {
     files: Vec<FileInfo>,
     selected_files: Signal<HashSet<PathBuf>>,
     current_estimator: TokenEstimator,
+    /// Model whose context window the selection is measured against.
+    target_model: TargetModel,
+    /// Price of the target model from the price table, if listed.
+    #[props(default)]
+    price: Option<ModelPrice>,
+    /// Cost of the line-number prefixes when line numbering is enabled.
+    #[props(default)]
+    line_numbers: Option<LineNumberCost>,
+    /// Budget packing plan, shown next to the total when a budget is set.
+    #[props(default)]
+    pack_plan: Option<PackPlan>,
+    /// Exact tokens of the formatted bundle, once counted.
+    #[props(default)]
+    bundle_tokens: Option<usize>,
+}
+
+/// prices.json holds one rate per model, so long-context surcharges are not
+/// part of the estimate.
+const TIERED_PRICING_NOTE: &str =
+    "Tiered pricing, e.g. a higher rate above 200k tokens, is ignored; prices.json is reloaded when edited";
+
+/// Sums the tokens of the selected files as they will be copied: truncated
+/// files count their kept lines only, plus line-number prefixes if enabled.
+pub fn selected_token_total(
+    files: &[FileInfo],
+    selected: &HashSet<PathBuf>,
+    line_numbers: Option<&LineNumberCost>,
+) -> usize {
+    files
+        .iter()
+        .filter(|file| selected.contains(&file.path))
+        .map(|file| {
+            let (tokens, lines) = match file.truncated {
+                Some(truncation) => (truncation.tokens, truncation.kept_lines),
+                None => (file.token_count, file.line_count),
+            };
+            tokens + line_numbers.map_or(0, |cost| cost.tokens(lines))
+        })
+        .sum::<usize>()
 }
 
 #[component]
@@ -21,20 +63,24 @@ pub fn Footer(props: FooterProps) -> Element {
         files,
         selected_files,
         current_estimator,
+        target_model,
+        price,
+        line_numbers,
+        pack_plan,
+        bundle_tokens,
     } = props;
 
     // Calculate total tokens for selected files
     let total_tokens = use_memo(move || {
         let selected = selected_files.read();
-        files
-            .iter()
-            .filter(|file| selected.contains(&file.path))
-            .map(|file| file.token_count)
-            .sum::<usize>()
+        selected_token_total(&files, &selected, line_numbers.as_ref())
     });
 
     let total = *total_tokens.read();
-    let is_over_limit = total > 32_000;
+    // The bundle is what the model receives; fall back to the file sum until it is counted
+    let used = bundle_tokens.unwrap_or(total);
+    let is_over_limit = target_model.budget_level(used) == BudgetLevel::Over;
+    let availability = current_estimator.availability();
 
     rsx! {
         div {
@@ -43,14 +89,29 @@ pub fn Footer(props: FooterProps) -> Element {
                 class: "flex justify-between items-center max-w-7xl mx-auto",
                 div {
                     class: "flex items-center space-x-2",
-                    span {
-                        class: if is_over_limit { "text-red-500 font-medium" } else { "text-light-foreground font-medium" },
-                        "Total tokens: {total}"
+                    if let Err(e) = &availability {
+                        span {
+                            class: "text-red-500 font-medium",
+                            title: "{e}",
+                            "Total tokens: unavailable ({e.summary()})"
+                        }
+                    } else {
+                        span {
+                            class: if is_over_limit { "text-red-500 font-medium" } else { "text-light-foreground font-medium" },
+                            "Total tokens: {total}"
+                        }
+                        if let Some(bundle) = bundle_tokens {
+                            span {
+                                class: "text-sm text-light-secondary-text",
+                                title: "Tokens of the copied bundle, including headers, separators and paths",
+                                "(bundle: {bundle})"
+                            }
+                        }
                     }
                     if is_over_limit {
                         span {
                             class: "text-red-500",
-                            title: "Token count exceeds 32k limit",
+                            title: "Bundle exceeds the {target_model.name} input budget",
                             // Warning icon from Heroicons
                             svg {
                                 xmlns: "http://www.w3.org/2000/svg",
@@ -64,6 +125,32 @@ pub fn Footer(props: FooterProps) -> Element {
                         }
                     }
                 }
+                if availability.is_ok() {
+                    BudgetBar { used, model: target_model.clone() }
+                    if let Some(price) = price {
+                        span {
+                            class: "text-sm text-light-foreground",
+                            title: match price.cached_input_cost(used) {
+                                Some(cached) => format!("{} if served from the prompt cache; ${}/M input tokens. {}", format_usd(cached), price.input, TIERED_PRICING_NOTE),
+                                None => format!("${}/M input tokens. {}", price.input, TIERED_PRICING_NOTE),
+                            },
+                            "≈ {format_usd(price.input_cost(used))} input"
+                        }
+                    }
+                }
+                if let Some(plan) = pack_plan {
+                    div {
+                        class: "text-sm text-light-foreground",
+                        title: "{plan.count(PackDecision::Full)} full, {plan.count(PackDecision::Truncated)} truncated, {plan.count(PackDecision::Omitted)} omitted",
+                        "Packed: {plan.total_tokens} / {plan.budget} tokens"
+                        if plan.count(PackDecision::Truncated) + plan.count(PackDecision::Omitted) > 0 {
+                            span {
+                                class: "ml-2 text-yellow-700",
+                                "({plan.count(PackDecision::Truncated)} truncated, {plan.count(PackDecision::Omitted)} omitted)"
+                            }
+                        }
+                    }
+                }
                 div {
                     class: "text-sm text-light-secondary-text",
                     "Estimation via {current_estimator.name()}"
@@ -76,6 +163,8 @@ pub fn Footer(props: FooterProps) -> Element {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::encoding::TextEncoding;
+    use crate::fs_utils::{Omitted, Truncation};
     use std::collections::HashSet;
     use std::path::PathBuf;
 
@@ -87,18 +176,36 @@ mod tests {
                 path: PathBuf::from("/test/file1.txt"),
                 size: 100,
                 token_count: 10,
+                line_count: 0,
+                counted: true,
+                is_binary: false,
+                encoding: TextEncoding::Utf8,
+                truncated: None,
+                modified: None,
             },
             FileInfo {
                 name: "file2.txt".to_string(),
                 path: PathBuf::from("/test/file2.txt"),
                 size: 200,
                 token_count: 20,
+                line_count: 0,
+                counted: true,
+                is_binary: false,
+                encoding: TextEncoding::Utf8,
+                truncated: None,
+                modified: None,
             },
             FileInfo {
                 name: "file3.txt".to_string(),
                 path: PathBuf::from("/test/file3.txt"),
                 size: 300,
                 token_count: 30,
+                line_count: 0,
+                counted: true,
+                is_binary: false,
+                encoding: TextEncoding::Utf8,
+                truncated: None,
+                modified: None,
             },
         ];
 
@@ -114,5 +221,64 @@ mod tests {
             .sum();
 
         assert_eq!(expected_total, 30); // Verify our test data is correct
+        assert_eq!(selected_token_total(&files, &selected, None), 30);
+    }
+
+    #[test]
+    fn test_footer_token_sum_with_line_numbers() {
+        let files = vec![FileInfo {
+            name: "file1.txt".to_string(),
+            path: PathBuf::from("/test/file1.txt"),
+            size: 100,
+            token_count: 10,
+            line_count: 2,
+            counted: true,
+            is_binary: false,
+            encoding: TextEncoding::Utf8,
+            truncated: None,
+            modified: None,
+        }];
+        let selected: HashSet<PathBuf> = files.iter().map(|f| f.path.clone()).collect();
+
+        // "   1 | " + "   2 | " = 14 chars = 3 tokens with Char/4
+        assert_eq!(
+            selected_token_total(
+                &files,
+                &selected,
+                Some(&LineNumberCost::new(4, &TokenEstimator::CharDiv4))
+            ),
+            13
+        );
+    }
+
+    #[test]
+    fn test_footer_token_sum_with_truncation() {
+        let files = vec![FileInfo {
+            name: "generated.rs".to_string(),
+            path: PathBuf::from("/test/generated.rs"),
+            size: 2_000_000,
+            token_count: 500_000,
+            line_count: 40_000,
+            counted: true,
+            is_binary: false,
+            encoding: TextEncoding::Utf8,
+            truncated: Some(Truncation {
+                tokens: 900,
+                kept_lines: 2,
+                omitted: Omitted::Lines(39_998),
+            }),
+            modified: None,
+        }];
+        let selected: HashSet<PathBuf> = files.iter().map(|f| f.path.clone()).collect();
+
+        assert_eq!(selected_token_total(&files, &selected, None), 900);
+        assert_eq!(
+            selected_token_total(
+                &files,
+                &selected,
+                Some(&LineNumberCost::new(4, &TokenEstimator::CharDiv4))
+            ),
+            903
+        );
     }
 }
@@ -11,18 +11,36 @@ fn test_footer_token_sum() {
             path: PathBuf::from("/test/file1.txt"),
             size: 100,
             token_count: 10,
+            line_count: 0,
+            counted: true,
+            is_binary: false,
+            encoding: crate::encoding::TextEncoding::Utf8,
+            truncated: None,
+            modified: None,
         },
         FileInfo {
             name: "file2.txt".to_string(),
             path: PathBuf::from("/test/file2.txt"),
             size: 200,
             token_count: 20,
+            line_count: 0,
+            counted: true,
+            is_binary: false,
+            encoding: crate::encoding::TextEncoding::Utf8,
+            truncated: None,
+            modified: None,
         },
         FileInfo {
             name: "file3.txt".to_string(),
             path: PathBuf::from("/test/file3.txt"),
             size: 300,
             token_count: 30,
+            line_count: 0,
+            counted: true,
+            is_binary: false,
+            encoding: crate::encoding::TextEncoding::Utf8,
+            truncated: None,
+            modified: None,
         },
     ];
 
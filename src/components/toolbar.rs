@@ -3,9 +3,13 @@
 use dioxus::prelude::*;
 use std::path::PathBuf;
 
-use crate::components::CopyButton;
-use crate::settings::Settings;
-use crate::tokenizer::TokenEstimator;
+use crate::budget::PackPlan;
+use crate::bundle_order::OrderPolicy;
+use crate::components::{CopyButton, CustomTokenizerControls, TruncationControls};
+use crate::fs_utils::{FileInfo, TruncationPolicy};
+use crate::models::TargetModel;
+use crate::output_format::{OutputFormat, OutputTemplate};
+use crate::tokenizer::{CustomTokenizer, TokenEstimator, MODEL_ALIASES};
 use std::collections::HashSet;
 
 #[derive(Props, Clone, PartialEq)]
@@ -13,8 +17,41 @@ pub struct ToolbarProps {
     on_select_all: EventHandler<()>,
     on_deselect_all: EventHandler<()>,
     on_estimator_change: EventHandler<TokenEstimator>,
+    on_output_format_change: EventHandler<OutputFormat>,
     has_files: bool,
     current_estimator: TokenEstimator,
+    /// Tokenizer file of the current estimator, if it is file-backed.
+    tokenizer_path: Option<PathBuf>,
+    on_tokenizer_path_change: EventHandler<(TokenEstimator, Option<PathBuf>)>,
+    custom_tokenizers: Vec<CustomTokenizer>,
+    on_custom_tokenizer_add: EventHandler<CustomTokenizer>,
+    on_custom_tokenizer_remove: EventHandler<String>,
+    current_output_format: OutputFormat,
+    templates: Vec<OutputTemplate>,
+    on_include_tree_change: EventHandler<bool>,
+    include_tree: bool,
+    non_ignored_files: Signal<HashSet<String>>,
+    all_files: Vec<FileInfo>,
+    workspace_root: PathBuf,
+    on_line_numbers_change: EventHandler<(bool, usize)>,
+    line_numbers: bool,
+    line_number_width: usize,
+    on_token_budget_change: EventHandler<(bool, usize)>,
+    budget_enabled: bool,
+    token_budget: usize,
+    pack_plan: Option<PackPlan>,
+    on_truncation_change: EventHandler<(bool, TruncationPolicy)>,
+    truncation_enabled: bool,
+    truncation: TruncationPolicy,
+    on_binary_placeholders_change: EventHandler<bool>,
+    binary_placeholders: bool,
+    on_order_policy_change: EventHandler<OrderPolicy>,
+    order_policy: OrderPolicy,
+    /// Built-in and custom models, and the name of the selected one.
+    models: Vec<TargetModel>,
+    target_model: String,
+    on_target_model_change: EventHandler<String>,
+    manual_order: Vec<PathBuf>,
     selected_files: Signal<HashSet<PathBuf>>,
 }
 
@@ -24,28 +61,44 @@ pub fn Toolbar(props: ToolbarProps) -> Element {
         on_select_all,
         on_deselect_all,
         on_estimator_change,
+        on_output_format_change,
         has_files,
         current_estimator,
+        tokenizer_path,
+        on_tokenizer_path_change,
+        custom_tokenizers,
+        on_custom_tokenizer_add,
+        on_custom_tokenizer_remove,
+        current_output_format,
+        templates,
+        on_include_tree_change,
+        include_tree,
+        non_ignored_files,
+        all_files,
+        workspace_root,
+        on_line_numbers_change,
+        line_numbers,
+        line_number_width,
+        on_token_budget_change,
+        budget_enabled,
+        token_budget,
+        pack_plan,
+        on_truncation_change,
+        truncation_enabled,
+        truncation,
+        on_binary_placeholders_change,
+        binary_placeholders,
+        on_order_policy_change,
+        order_policy,
+        models,
+        target_model,
+        on_target_model_change,
+        manual_order,
         selected_files,
     } = props;
 
-    let config_dir = dirs_next::config_dir()
-        .unwrap_or_else(|| PathBuf::from("."))
-        .join("context-loader");
-    let settings_file = config_dir.join("settings.json");
-    let mut settings = use_signal(|| Settings::new(settings_file));
     let mut copy_status = use_signal(|| None::<Result<(), String>>);
 
-    // Load settings on mount
-    use_effect(move || {
-        let settings_file = settings.read().config_path.clone().unwrap();
-        spawn(async move {
-            if let Ok(loaded_settings) = Settings::load(&settings_file).await {
-                settings.set(loaded_settings);
-            }
-        });
-    });
-
     // Reset copy status after 3 seconds
     use_effect(move || {
         if copy_status.read().is_some() {
@@ -57,20 +110,6 @@ pub fn Toolbar(props: ToolbarProps) -> Element {
         }
     });
 
-    let on_estimator_select = move |estimator: TokenEstimator| {
-        let on_estimator_change = on_estimator_change.clone();
-        let mut settings = settings.clone();
-        spawn(async move {
-            on_estimator_change.call(estimator.clone());
-            let mut current_settings = settings.read().clone();
-            current_settings.set_token_estimator(estimator);
-            if let Err(e) = current_settings.save().await {
-                log::error!("Failed to save settings: {}", e);
-            }
-            settings.set(current_settings);
-        });
-    };
-
     let on_copy_result = move |result: Result<(), String>| {
         copy_status.set(Some(result));
     };
@@ -95,13 +134,199 @@ pub fn Toolbar(props: ToolbarProps) -> Element {
                 value: "{current_estimator}",
                 onchange: move |evt| {
                     if let Ok(estimator) = evt.value().parse::<TokenEstimator>() {
-                        on_estimator_select(estimator);
+                        on_estimator_change.call(estimator);
+                    }
+                },
+                for estimator in TokenEstimator::all() {
+                    option { value: "{estimator}", "{estimator.name()}" }
+                }
+                if !custom_tokenizers.is_empty() {
+                    optgroup {
+                        label: "Custom",
+                        for tokenizer in custom_tokenizers.iter() {
+                            option { value: "{tokenizer.estimator()}", "{tokenizer.name}" }
+                        }
+                    }
+                }
+                optgroup {
+                    label: "By model",
+                    for (model, estimator) in MODEL_ALIASES {
+                        option { value: "{model}", "{model} ({estimator.name()})" }
+                    }
+                }
+            }
+
+            if let Some(extension) = current_estimator.tokenizer_file_extension() {
+                button {
+                    class: "px-3 py-2 text-sm bg-light-background border border-light-border text-light-foreground rounded",
+                    title: "Choose the local tokenizer file (.{extension}) for {current_estimator.name()}",
+                    onclick: {
+                        let current_estimator = current_estimator.clone();
+                        move |_| {
+                            if let Some(path) = rfd::FileDialog::new().add_filter("Tokenizer", &[extension]).pick_file() {
+                                on_tokenizer_path_change.call((current_estimator.clone(), Some(path)));
+                            }
+                        }
+                    },
+                    match tokenizer_path.as_ref().and_then(|path| path.file_name()) {
+                        Some(name) => rsx! { "{name.to_string_lossy()}" },
+                        None => rsx! { "Choose tokenizer file" },
+                    }
+                }
+            }
+            if let Err(e) = current_estimator.availability() {
+                div {
+                    class: "px-3 py-1 text-sm font-medium text-red-700 bg-red-100 rounded-md",
+                    title: "{e}",
+                    "{e.summary()}"
+                }
+            }
+
+            CustomTokenizerControls {
+                current_estimator: current_estimator.clone(),
+                on_add: on_custom_tokenizer_add,
+                on_remove: on_custom_tokenizer_remove,
+            }
+
+            // Output format dropdown
+            select {
+                class: "px-4 py-2 bg-light-background border border-light-border text-light-foreground rounded",
+                value: "{current_output_format}",
+                onchange: move |evt| {
+                    if let Ok(format) = evt.value().parse::<OutputFormat>() {
+                        on_output_format_change.call(format);
+                    }
+                },
+                for format in OutputFormat::BUILT_IN {
+                    option { value: "{format}", "{format.name()}" }
+                }
+                if !templates.is_empty() {
+                    optgroup {
+                        label: "Templates",
+                        for template in templates.iter() {
+                            option {
+                                value: "{OutputFormat::Custom(template.name.clone())}",
+                                "{template.name}"
+                            }
+                        }
+                    }
+                }
+            }
+
+            // Target model dropdown
+            select {
+                class: "px-4 py-2 bg-light-background border border-light-border text-light-foreground rounded",
+                title: "Model whose context window the bundle must fit",
+                value: "{target_model}",
+                onchange: move |evt| on_target_model_change.call(evt.value()),
+                for model in models.iter() {
+                    option { value: "{model.name}", "{model.name} ({model.context_window / 1000}k)" }
+                }
+            }
+
+            // File order dropdown
+            select {
+                class: "px-4 py-2 bg-light-background border border-light-border text-light-foreground rounded",
+                title: "Order of files in copied bundles",
+                value: "{order_policy}",
+                onchange: move |evt| {
+                    if let Ok(policy) = evt.value().parse::<OrderPolicy>() {
+                        on_order_policy_change.call(policy);
                     }
                 },
-                option { value: "CharDiv4", "Char/4 (Fast)" }
-                option { value: "Cl100k", "GPT-3/4 (cl100k)" }
-                option { value: "Llama2", "Llama2 BPE" }
-                option { value: "SentencePiece", "Gemini SentencePiece" }
+                for policy in OrderPolicy::ALL {
+                    option { value: "{policy}", "{policy.name()}" }
+                }
+            }
+
+            label {
+                class: "flex items-center space-x-2 text-sm text-light-foreground",
+                title: "Prepend the workspace layout to copied bundles",
+                input {
+                    "type": "checkbox",
+                    class: "form-checkbox rounded text-blue-500 focus:ring-blue-500 bg-light-background border-light-border",
+                    checked: include_tree,
+                    oninput: move |evt| {
+                        on_include_tree_change.call(evt.value().parse::<bool>().unwrap_or(false));
+                    },
+                }
+                span { "Include tree" }
+            }
+
+            label {
+                class: "flex items-center space-x-2 text-sm text-light-foreground",
+                title: "Prefix every copied line with its line number",
+                input {
+                    "type": "checkbox",
+                    class: "form-checkbox rounded text-blue-500 focus:ring-blue-500 bg-light-background border-light-border",
+                    checked: line_numbers,
+                    oninput: move |evt| {
+                        on_line_numbers_change.call((evt.value().parse::<bool>().unwrap_or(false), line_number_width));
+                    },
+                }
+                span { "Line numbers" }
+                input {
+                    "type": "number",
+                    class: "w-14 px-2 py-1 bg-light-background border border-light-border rounded disabled:opacity-50",
+                    title: "Line number width",
+                    min: "1",
+                    max: "10",
+                    value: "{line_number_width}",
+                    disabled: !line_numbers,
+                    onchange: move |evt| {
+                        if let Ok(width) = evt.value().parse::<usize>() {
+                            on_line_numbers_change.call((line_numbers, width.clamp(1, 10)));
+                        }
+                    },
+                }
+            }
+
+            label {
+                class: "flex items-center space-x-2 text-sm text-light-foreground",
+                title: "Note selected binary files in the bundle instead of dropping them",
+                input {
+                    "type": "checkbox",
+                    class: "form-checkbox rounded text-blue-500 focus:ring-blue-500 bg-light-background border-light-border",
+                    checked: binary_placeholders,
+                    oninput: move |evt| {
+                        on_binary_placeholders_change.call(evt.value().parse::<bool>().unwrap_or(false));
+                    },
+                }
+                span { "Binary placeholders" }
+            }
+
+            TruncationControls {
+                enabled: truncation_enabled,
+                policy: truncation,
+                on_change: on_truncation_change,
+            }
+
+            label {
+                class: "flex items-center space-x-2 text-sm text-light-foreground",
+                title: "Fit the selection into a token budget: pinned, smaller and newer files first",
+                input {
+                    "type": "checkbox",
+                    class: "form-checkbox rounded text-blue-500 focus:ring-blue-500 bg-light-background border-light-border",
+                    checked: budget_enabled,
+                    oninput: move |evt| {
+                        on_token_budget_change.call((evt.value().parse::<bool>().unwrap_or(false), token_budget));
+                    },
+                }
+                span { "Budget" }
+                input {
+                    "type": "number",
+                    class: "w-24 px-2 py-1 bg-light-background border border-light-border rounded disabled:opacity-50",
+                    title: "Token budget",
+                    min: "1",
+                    step: "1000",
+                    value: "{token_budget}",
+                    disabled: !budget_enabled,
+                    onchange: move |evt| {
+                        if let Ok(budget) = evt.value().parse::<usize>() {
+                            on_token_budget_change.call((budget_enabled, budget.max(1)));
+                        }
+                    },
+                }
             }
 
             if has_files {
@@ -119,6 +344,19 @@ pub fn Toolbar(props: ToolbarProps) -> Element {
 
                 CopyButton {
                     selected_files: selected_files.clone(),
+                    output_format: current_output_format.clone(),
+                    templates: templates.clone(),
+                    estimator: current_estimator.clone(),
+                    include_tree,
+                    non_ignored_files,
+                    all_files: all_files.clone(),
+                    workspace_root: workspace_root.clone(),
+                    line_numbers: line_numbers.then_some(line_number_width),
+                    binary_placeholders,
+                    truncation: truncation_enabled.then_some(truncation),
+                    packing: pack_plan.clone(),
+                    order_policy,
+                    manual_order: manual_order.clone(),
                     on_copy: on_copy_result
                 }
             }
@@ -0,0 +1,100 @@
+use crate::fs_utils::{FileLimit, TruncationPolicy};
+use dioxus::prelude::*;
+
+#[derive(Props, Clone, PartialEq)]
+pub struct TruncationControlsProps {
+    enabled: bool,
+    policy: TruncationPolicy,
+    on_change: EventHandler<(bool, TruncationPolicy)>,
+}
+
+const BYTES_PER_KB: u64 = 1024;
+
+/// Toolbar controls for the per-file cap: on/off, limit and unit, and how
+/// many head/tail lines survive truncation.
+#[component]
+pub fn TruncationControls(props: TruncationControlsProps) -> Element {
+    let TruncationControlsProps {
+        enabled,
+        policy,
+        on_change,
+    } = props;
+
+    let (limit_value, unit) = match policy.limit {
+        FileLimit::Bytes(bytes) => ((bytes / BYTES_PER_KB).max(1) as usize, "KB"),
+        FileLimit::Tokens(tokens) => (tokens, "tokens"),
+    };
+    let limit_for_unit = move |value: usize, unit: &str| match unit {
+        "KB" => FileLimit::Bytes(value as u64 * BYTES_PER_KB),
+        _ => FileLimit::Tokens(value),
+    };
+
+    rsx! {
+        div {
+            class: "flex items-center space-x-2 text-sm text-light-foreground",
+            title: "Keep only the first and last lines of files over the cap",
+            input {
+                "type": "checkbox",
+                class: "form-checkbox rounded text-blue-500 focus:ring-blue-500 bg-light-background border-light-border",
+                checked: enabled,
+                oninput: move |evt| {
+                    on_change.call((evt.value().parse::<bool>().unwrap_or(false), policy));
+                },
+            }
+            span { "Cap files at" }
+            input {
+                "type": "number",
+                class: "w-20 px-2 py-1 bg-light-background border border-light-border rounded disabled:opacity-50",
+                min: "1",
+                value: "{limit_value}",
+                disabled: !enabled,
+                onchange: move |evt| {
+                    if let Ok(value) = evt.value().parse::<usize>() {
+                        let limit = limit_for_unit(value.max(1), unit);
+                        on_change.call((enabled, TruncationPolicy { limit, ..policy }));
+                    }
+                },
+            }
+            select {
+                class: "px-2 py-1 bg-light-background border border-light-border rounded disabled:opacity-50",
+                value: "{unit}",
+                disabled: !enabled,
+                onchange: move |evt| {
+                    let limit = limit_for_unit(limit_value, &evt.value());
+                    on_change.call((enabled, TruncationPolicy { limit, ..policy }));
+                },
+                option { value: "tokens", "tokens" }
+                option { value: "KB", "KB" }
+            }
+            span { "keep" }
+            input {
+                "type": "number",
+                class: "w-16 px-2 py-1 bg-light-background border border-light-border rounded disabled:opacity-50",
+                title: "Lines kept from the start of the file",
+                min: "0",
+                value: "{policy.head_lines}",
+                disabled: !enabled,
+                onchange: move |evt| {
+                    if let Ok(head_lines) = evt.value().parse::<usize>() {
+                        on_change.call((enabled, TruncationPolicy { head_lines, ..policy }));
+                    }
+                },
+            }
+            span { "/" }
+            input {
+                "type": "number",
+                class: "w-16 px-2 py-1 bg-light-background border border-light-border rounded disabled:opacity-50",
+                title: "Lines kept from the end of the file",
+                min: "0",
+                value: "{policy.tail_lines}",
+                disabled: !enabled,
+                onchange: move |evt| {
+                    if let Ok(tail_lines) = evt.value().parse::<usize>() {
+                        on_change.call((enabled, TruncationPolicy { tail_lines, ..policy }));
+                    }
+                },
+            }
+            span { "lines" }
+        }
+    }
+}
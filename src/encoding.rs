@@ -0,0 +1,369 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Text encodings we can detect and transcode to UTF-8.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TextEncoding {
+    #[default]
+    Utf8,
+    Utf8Bom,
+    Utf16Le,
+    Utf16Be,
+    /// Also covers Latin-1, which it extends in the 0x80-0x9F range.
+    Windows1252,
+}
+
+impl fmt::Display for TextEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Utf8 => write!(f, "UTF-8"),
+            Self::Utf8Bom => write!(f, "UTF-8 BOM"),
+            Self::Utf16Le => write!(f, "UTF-16LE"),
+            Self::Utf16Be => write!(f, "UTF-16BE"),
+            Self::Windows1252 => write!(f, "Windows-1252"),
+        }
+    }
+}
+
+const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+const UTF16_LE_BOM: &[u8] = b"\xff\xfe";
+const UTF16_BE_BOM: &[u8] = b"\xfe\xff";
+
+/// Code points for bytes 0x80-0x9F in Windows-1252. The five undefined bytes
+/// map to the matching C1 control, as browsers do.
+const WINDOWS_1252_HIGH: [char; 32] = [
+    '\u{20ac}', '\u{81}', '\u{201a}', '\u{192}', '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2c6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8d}', '\u{17d}', '\u{8f}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2dc}', '\u{2122}', '\u{161}', '\u{203a}', '\u{153}', '\u{9d}', '\u{17e}', '\u{178}',
+];
+
+/// True if `bytes` starts with a UTF-16 byte order mark.
+pub fn has_utf16_bom(bytes: &[u8]) -> bool {
+    bytes.starts_with(UTF16_LE_BOM) || bytes.starts_with(UTF16_BE_BOM)
+}
+
+/// Detects the encoding from a BOM, falling back to UTF-8 when the bytes are
+/// valid UTF-8 and Windows-1252 otherwise.
+pub fn detect_encoding(bytes: &[u8]) -> TextEncoding {
+    detect(bytes, false)
+}
+
+/// Like `detect_encoding`, for the first bytes of a longer file: a multi-byte
+/// sequence cut off at the end of `prefix` still counts as UTF-8.
+pub fn detect_prefix_encoding(prefix: &[u8]) -> TextEncoding {
+    detect(prefix, true)
+}
+
+fn detect(bytes: &[u8], is_prefix: bool) -> TextEncoding {
+    if bytes.starts_with(UTF8_BOM) {
+        return TextEncoding::Utf8Bom;
+    }
+    if bytes.starts_with(UTF16_LE_BOM) {
+        return TextEncoding::Utf16Le;
+    }
+    if bytes.starts_with(UTF16_BE_BOM) {
+        return TextEncoding::Utf16Be;
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(_) => TextEncoding::Utf8,
+        Err(e) if is_prefix && e.error_len().is_none() => TextEncoding::Utf8,
+        Err(_) => TextEncoding::Windows1252,
+    }
+}
+
+/// Transcodes `bytes` to UTF-8, dropping any BOM. Malformed UTF-16 is
+/// replaced with U+FFFD rather than failing.
+pub fn decode_text(bytes: Vec<u8>) -> (String, TextEncoding) {
+    let encoding = detect_encoding(&bytes);
+    let text = match encoding {
+        // detect_encoding only picks UTF-8 for valid UTF-8
+        TextEncoding::Utf8 => String::from_utf8(bytes)
+            .unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned()),
+        TextEncoding::Utf8Bom => String::from_utf8_lossy(&bytes[UTF8_BOM.len()..]).into_owned(),
+        TextEncoding::Utf16Le => decode_utf16(&bytes[2..], u16::from_le_bytes),
+        TextEncoding::Utf16Be => decode_utf16(&bytes[2..], u16::from_be_bytes),
+        TextEncoding::Windows1252 => bytes.iter().map(|&b| windows_1252_char(b)).collect(),
+    };
+    (text, encoding)
+}
+
+fn decode_utf16(bytes: &[u8], to_unit: fn([u8; 2]) -> u16) -> String {
+    let units = bytes.chunks(2).map(|pair| match pair {
+        [a, b] => to_unit([*a, *b]),
+        // Odd trailing byte
+        _ => 0xfffd,
+    });
+    char::decode_utf16(units)
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+
+fn windows_1252_char(byte: u8) -> char {
+    match byte {
+        0x80..=0x9f => WINDOWS_1252_HIGH[(byte - 0x80) as usize],
+        _ => byte as char,
+    }
+}
+
+/// Bytes read from disk at a time when streaming a file.
+const STREAM_BLOCK_LEN: usize = 64 * 1024;
+
+/// Like `detect_encoding` for a whole file, without holding it in memory.
+pub fn detect_file_encoding(path: &Path) -> io::Result<TextEncoding> {
+    let mut file = File::open(path)?;
+    let mut block = vec![0; STREAM_BLOCK_LEN];
+    let mut pending = Vec::new();
+    let mut started = false;
+    loop {
+        let read = file.read(&mut block)?;
+        if read == 0 {
+            break;
+        }
+        pending.extend_from_slice(&block[..read]);
+        if !started {
+            // Wait for enough bytes to tell a BOM apart
+            if pending.len() < UTF8_BOM.len() {
+                continue;
+            }
+            started = true;
+            let encoding = detect_prefix_encoding(&pending);
+            if encoding != TextEncoding::Utf8 {
+                return Ok(encoding);
+            }
+        }
+        match std::str::from_utf8(&pending) {
+            Ok(_) => pending.clear(),
+            Err(e) if e.error_len().is_none() => {
+                pending.drain(..e.valid_up_to());
+            }
+            Err(_) => return Ok(TextEncoding::Windows1252),
+        }
+    }
+    Ok(detect_encoding(&pending))
+}
+
+/// Reads the file at `path` as text in pieces, passing each transcoded piece
+/// to `on_text`, so files of any size decode in bounded memory. The result
+/// matches `decode_text` on the whole file.
+pub fn read_text_streamed(path: &Path, mut on_text: impl FnMut(&str)) -> io::Result<TextEncoding> {
+    let encoding = detect_file_encoding(path)?;
+    let mut file = File::open(path)?;
+    let mut decoder = StreamDecoder::new(encoding);
+    let mut block = vec![0; STREAM_BLOCK_LEN];
+    let mut text = String::with_capacity(STREAM_BLOCK_LEN);
+    loop {
+        let read = file.read(&mut block)?;
+        if read == 0 {
+            break;
+        }
+        decoder.decode(&block[..read], &mut text);
+        on_text(&text);
+        text.clear();
+    }
+    decoder.finish(&mut text);
+    on_text(&text);
+    Ok(encoding)
+}
+
+/// Transcodes text in `encoding` to UTF-8 as it arrives in arbitrary pieces.
+/// A BOM at the start is dropped; a character cut off at the end of a piece
+/// is completed by the next one.
+pub struct StreamDecoder {
+    encoding: TextEncoding,
+    bom_left: usize,
+    pending: Vec<u8>,
+}
+
+impl StreamDecoder {
+    pub fn new(encoding: TextEncoding) -> Self {
+        let bom_left = match encoding {
+            TextEncoding::Utf8Bom => UTF8_BOM.len(),
+            TextEncoding::Utf16Le | TextEncoding::Utf16Be => UTF16_LE_BOM.len(),
+            TextEncoding::Utf8 | TextEncoding::Windows1252 => 0,
+        };
+        Self {
+            encoding,
+            bom_left,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Appends the decoded text of `bytes` to `out`.
+    pub fn decode(&mut self, bytes: &[u8], out: &mut String) {
+        let skipped = self.bom_left.min(bytes.len());
+        self.bom_left -= skipped;
+        self.pending.extend_from_slice(&bytes[skipped..]);
+        match self.encoding {
+            TextEncoding::Utf8 | TextEncoding::Utf8Bom => self.decode_utf8(out),
+            TextEncoding::Utf16Le => self.decode_utf16(out, u16::from_le_bytes),
+            TextEncoding::Utf16Be => self.decode_utf16(out, u16::from_be_bytes),
+            TextEncoding::Windows1252 => {
+                out.extend(self.pending.drain(..).map(windows_1252_char));
+            }
+        }
+    }
+
+    /// Appends whatever is left over, e.g. a truncated final character.
+    pub fn finish(mut self, out: &mut String) {
+        match self.encoding {
+            TextEncoding::Utf16Le => out.push_str(&decode_utf16(&self.pending, u16::from_le_bytes)),
+            TextEncoding::Utf16Be => out.push_str(&decode_utf16(&self.pending, u16::from_be_bytes)),
+            _ => out.push_str(&String::from_utf8_lossy(&self.pending)),
+        }
+        self.pending.clear();
+    }
+
+    fn decode_utf8(&mut self, out: &mut String) {
+        let mut rest = self.pending.as_slice();
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(text) => {
+                    out.push_str(text);
+                    rest = &[];
+                    break;
+                }
+                Err(e) => {
+                    let (valid, invalid) = rest.split_at(e.valid_up_to());
+                    // Checked by from_utf8 above
+                    out.push_str(std::str::from_utf8(valid).unwrap());
+                    match e.error_len() {
+                        Some(len) => {
+                            out.push(char::REPLACEMENT_CHARACTER);
+                            rest = &invalid[len..];
+                        }
+                        // Completed by the next piece
+                        None => {
+                            rest = invalid;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        let keep = rest.len();
+        self.pending.drain(..self.pending.len() - keep);
+    }
+
+    fn decode_utf16(&mut self, out: &mut String, to_unit: fn([u8; 2]) -> u16) {
+        let mut complete = self.pending.len() & !1;
+        // Hold back a high surrogate until its pair arrives
+        if complete >= 2 {
+            let last = to_unit([self.pending[complete - 2], self.pending[complete - 1]]);
+            if (0xd800..0xdc00).contains(&last) {
+                complete -= 2;
+            }
+        }
+        out.push_str(&decode_utf16(&self.pending[..complete], to_unit));
+        self.pending.drain(..complete);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_encoding() {
+        assert_eq!(detect_encoding(b""), TextEncoding::Utf8);
+        assert_eq!(detect_encoding("caf\u{e9}".as_bytes()), TextEncoding::Utf8);
+        assert_eq!(detect_encoding(b"\xef\xbb\xbfhi"), TextEncoding::Utf8Bom);
+        assert_eq!(detect_encoding(b"\xff\xfeh\0"), TextEncoding::Utf16Le);
+        assert_eq!(detect_encoding(b"\xfe\xff\0h"), TextEncoding::Utf16Be);
+        assert_eq!(detect_encoding(b"caf\xe9"), TextEncoding::Windows1252);
+        assert_eq!(detect_encoding(b"caf\xc3"), TextEncoding::Windows1252);
+        // A prefix that cuts "é" in half is still UTF-8
+        assert_eq!(detect_prefix_encoding(b"caf\xc3"), TextEncoding::Utf8);
+        assert_eq!(
+            detect_prefix_encoding(b"caf\xe9 "),
+            TextEncoding::Windows1252
+        );
+    }
+
+    #[test]
+    fn test_decode_text() {
+        let (text, encoding) = decode_text(b"\xef\xbb\xbfint x;".to_vec());
+        assert_eq!((text.as_str(), encoding), ("int x;", TextEncoding::Utf8Bom));
+
+        let (text, encoding) = decode_text(b"\xff\xfei\0n\0t\0 \0\xe9\0".to_vec());
+        assert_eq!(
+            (text.as_str(), encoding),
+            ("int \u{e9}", TextEncoding::Utf16Le)
+        );
+
+        let (text, encoding) = decode_text(b"\xfe\xff\0i\0n\0t\xd8\x3d\xde\x00".to_vec());
+        assert_eq!(
+            (text.as_str(), encoding),
+            ("int\u{1f600}", TextEncoding::Utf16Be)
+        );
+
+        // Windows-1252: é, € and the curly quotes around "x"
+        let (text, encoding) = decode_text(b"/* \xe9 \x80 \x93x\x94 */".to_vec());
+        assert_eq!(
+            (text.as_str(), encoding),
+            (
+                "/* \u{e9} \u{20ac} \u{201c}x\u{201d} */",
+                TextEncoding::Windows1252
+            )
+        );
+    }
+
+    /// Decodes `bytes` one byte at a time.
+    fn decode_bytewise(bytes: &[u8], encoding: TextEncoding) -> String {
+        let mut decoder = StreamDecoder::new(encoding);
+        let mut text = String::new();
+        for byte in bytes {
+            decoder.decode(std::slice::from_ref(byte), &mut text);
+        }
+        decoder.finish(&mut text);
+        text
+    }
+
+    #[test]
+    fn test_stream_decoder_matches_decode_text() {
+        let samples: [&[u8]; 7] = [
+            "caf\u{e9} \u{1f600}\n".as_bytes(),
+            b"\xef\xbb\xbfint x;",
+            b"\xff\xfei\0n\0t\0 \0\xe9\0",
+            b"\xfe\xff\0i\0n\0t\xd8\x3d\xde\x00",
+            b"/* \xe9 \x80 \x93x\x94 */",
+            b"\xff\xfe\x00\xd8a\0b",
+            b"\xef\xbb\xbfcut \xc3",
+        ];
+        for bytes in samples {
+            let (expected, encoding) = decode_text(bytes.to_vec());
+            assert_eq!(decode_bytewise(bytes, encoding), expected, "{:?}", bytes);
+        }
+    }
+
+    #[test]
+    fn test_streamed_file_matches_whole_file() {
+        let dir = tempfile::tempdir().unwrap();
+        // Valid UTF-8 for well over a block, then one Windows-1252 byte
+        let mut bytes = "\u{e9}t\u{e9}\n".repeat(STREAM_BLOCK_LEN).into_bytes();
+        bytes.extend_from_slice(b"\x80");
+        let path = dir.path().join("latin.txt");
+        std::fs::write(&path, &bytes).unwrap();
+
+        let mut streamed = String::new();
+        let encoding = read_text_streamed(&path, |text| streamed.push_str(text)).unwrap();
+        let (expected, expected_encoding) = decode_text(bytes);
+        assert_eq!(encoding, expected_encoding);
+        assert_eq!(encoding, TextEncoding::Windows1252);
+        assert!(streamed == expected);
+
+        std::fs::write(&path, "caf\u{e9}").unwrap();
+        assert_eq!(detect_file_encoding(&path).unwrap(), TextEncoding::Utf8);
+        std::fs::write(&path, b"\xfe\xff").unwrap();
+        assert_eq!(detect_file_encoding(&path).unwrap(), TextEncoding::Utf16Be);
+    }
+
+    #[test]
+    fn test_decode_malformed_utf16() {
+        let (text, _) = decode_text(b"\xff\xfe\x00\xd8a\0b".to_vec());
+        assert_eq!(text, "\u{fffd}a\u{fffd}");
+    }
+}
@@ -1,17 +1,24 @@
 // use ignore::WalkBuilder;
+use bytesize::ByteSize;
 use serde::{Deserialize, Serialize};
 // use std::collections::HashSet;
+use std::collections::{HashMap, VecDeque};
 use std::fs::File;
 use std::io::{self, BufReader, Read};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use std::time::SystemTime;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 // use tokio::fs;
-use tokio::sync::mpsc;
 use walkdir::WalkDir;
 
-// use crate::cache::TokenCache;
-use crate::tokenizer::{/*count_tokens,*/ TokenEstimator};
+use crate::budget::{PackDecision, PackPlan};
+use crate::cache::{CacheEntry, ContentInfo, TokenCache};
+use crate::encoding::{
+    decode_text, detect_encoding, detect_prefix_encoding, has_utf16_bom, read_text_streamed,
+    TextEncoding,
+};
+use crate::output_format::{language_for_path, BundleSummary, FileEntry, OutputFormatter};
+use crate::tokenizer::{/*count_tokens,*/ TokenEstimator, TokenStream};
 
 pub type ProgressCallback = Arc<Box<dyn Fn(usize, usize, String) + Send + Sync>>;
 
@@ -44,6 +51,114 @@ pub struct FileInfo {
     pub path: PathBuf,
     pub size: u64,
     pub token_count: usize,
+    /// Number of lines, filled in alongside `token_count`.
+    #[serde(default)]
+    pub line_count: usize,
+    /// Whether `token_count` and `line_count` are filled in; zero is a
+    /// valid count for empty files or an unavailable tokenizer.
+    #[serde(default)]
+    pub counted: bool,
+    /// Set by content sniffing; binary files are never tokenized.
+    #[serde(default)]
+    pub is_binary: bool,
+    /// Detected from the first few KB on listing, then from the whole file
+    /// once tokens are computed.
+    #[serde(default)]
+    pub encoding: TextEncoding,
+    /// Set when the file exceeds the per-file cap it was last counted with.
+    #[serde(default)]
+    pub truncated: Option<Truncation>,
+    /// Last modification time, as of listing or counting.
+    #[serde(default)]
+    pub modified: Option<SystemTime>,
+}
+
+/// A file's size after head/tail truncation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Truncation {
+    pub tokens: usize,
+    pub kept_lines: usize,
+    pub omitted: Omitted,
+}
+
+/// What a truncation cut from the middle of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Omitted {
+    Lines(usize),
+    /// A cut inside overlong lines, spanning `lines` line breaks.
+    Bytes {
+        bytes: u64,
+        lines: usize,
+    },
+}
+
+impl Omitted {
+    /// Line breaks cut out.
+    pub fn lines(&self) -> usize {
+        match *self {
+            Self::Lines(lines) | Self::Bytes { lines, .. } => lines,
+        }
+    }
+
+    /// The marker put in place of the omitted text.
+    pub fn marker(&self) -> String {
+        match *self {
+            Self::Lines(lines) => omission_marker(lines),
+            Self::Bytes { bytes, .. } => format!("… {} omitted …", ByteSize(bytes)),
+        }
+    }
+}
+
+/// Truncation keeps at most this many bytes per kept line, so files with few
+/// but huge lines (minified code, data dumps) are cut too.
+pub const KEPT_BYTES_PER_LINE: usize = 1_000;
+
+/// The unit of a per-file cap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileLimit {
+    Bytes(u64),
+    Tokens(usize),
+}
+
+/// Files over `limit` keep their first `head_lines` and last `tail_lines`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TruncationPolicy {
+    pub limit: FileLimit,
+    pub head_lines: usize,
+    pub tail_lines: usize,
+}
+
+impl Default for TruncationPolicy {
+    fn default() -> Self {
+        Self {
+            limit: FileLimit::Tokens(10_000),
+            head_lines: 200,
+            tail_lines: 50,
+        }
+    }
+}
+
+impl TruncationPolicy {
+    /// Whether `content`, which estimates to `tokens`, is over the cap.
+    pub fn exceeded_by(&self, content: &str, tokens: usize) -> bool {
+        self.exceeded(content.len(), tokens)
+    }
+
+    fn exceeded(&self, content_len: usize, tokens: usize) -> bool {
+        match self.limit {
+            FileLimit::Bytes(max) => content_len as u64 > max,
+            FileLimit::Tokens(max) => tokens > max,
+        }
+    }
+
+    /// Truncates `content` if it is over the cap. Returns the kept text and
+    /// what was omitted; see `truncate_lines`.
+    pub fn apply(&self, content: &str, tokens: usize) -> Option<(String, Omitted)> {
+        if !self.exceeded_by(content, tokens) {
+            return None;
+        }
+        truncate_lines(content, self.head_lines, self.tail_lines)
+    }
 }
 
 mod path_serde {
@@ -74,106 +189,337 @@ impl FileInfo {
             .map(|s| s.to_string_lossy().into_owned())
             .unwrap_or_default();
 
+        // Unreadable files stay listed as text and fail at copy time, as before
+        let sample = read_sample(&path).unwrap_or_default();
+
         Ok(FileInfo {
             name,
-            path,
             size: metadata.len(),
             token_count: 0,
+            line_count: 0,
+            counted: false,
+            is_binary: looks_binary(&sample),
+            encoding: if metadata.len() > sample.len() as u64 {
+                detect_prefix_encoding(&sample)
+            } else {
+                detect_encoding(&sample)
+            },
+            truncated: None,
+            modified: metadata.modified().ok(),
+            path,
         })
     }
 
-    pub fn with_tokens(path: PathBuf, estimator: &TokenEstimator) -> io::Result<Self> {
-        let mut info = Self::new(path)?;
-        info.token_count = estimator.estimate_file_tokens(&info.path)?;
-        Ok(info)
+    /// Reads the file once to fill in `token_count`, `line_count` and, when
+    /// a cap is given, `truncated`.
+    pub fn compute_tokens(
+        &mut self,
+        estimator: &TokenEstimator,
+        truncation: Option<&TruncationPolicy>,
+    ) -> io::Result<()> {
+        self.count_tokens(estimator, truncation).map(|_| ())
     }
-}
 
-pub async fn crawl(
-    dir: &Path,
-    estimator: &TokenEstimator,
-    progress_tx: Option<mpsc::Sender<(usize, usize)>>,
-) -> io::Result<Vec<FileInfo>> {
-    let mut files = Vec::new();
-    let mut total_files = 0;
-    let mut processed_files = 0;
+    /// `compute_tokens`, reusing the counts in `cache` for contents counted
+    /// before, here or in another workspace. The file's blake3 hash is only
+    /// taken again once its mtime changes. `tokenizer` is the estimator's
+    /// `cache::tokenizer_id`. Files over the cap are read again to truncate
+    /// them, tokenizing only the lines kept.
+    pub fn compute_tokens_cached(
+        &mut self,
+        estimator: &TokenEstimator,
+        tokenizer: &str,
+        truncation: Option<&TruncationPolicy>,
+        cache: &Mutex<TokenCache>,
+    ) -> io::Result<()> {
+        // Counts of a missing tokenizer are all zero, not worth keeping
+        if self.is_binary || estimator.availability().is_err() {
+            return self.compute_tokens(estimator, truncation);
+        }
 
-    println!("Starting crawl in directory: {}", dir.display());
+        let mtime = mtime_nanos(&self.path)?;
+        let known = cache.lock().unwrap().get_entry(&self.path).cloned();
+        let hash = match &known {
+            Some(entry) if entry.mtime == mtime => entry.hash.clone(),
+            _ => get_file_hash(&self.path)?,
+        };
+        let cached = {
+            let mut cache = cache.lock().unwrap();
+            let entry = CacheEntry {
+                mtime,
+                hash: hash.clone(),
+            };
+            if known.as_ref() != Some(&entry) {
+                cache.insert_entry(self.path.clone(), entry);
+            }
+            cache
+                .get_content(&hash)
+                .zip(cache.get_count(tokenizer, &hash))
+        };
+        if let Some((content, tokens)) = cached {
+            self.token_count = tokens;
+            self.line_count = content.line_count;
+            self.counted = true;
+            self.encoding = content.encoding;
+            self.truncated = None;
+            if let Some(policy) = truncation.filter(|p| p.exceeded(content.text_len, tokens)) {
+                self.truncate_counted(estimator, policy)?;
+            }
+            return Ok(());
+        }
 
-    // Check if this is a test directory (starts with .tmp)
-    let is_test_dir = dir.to_string_lossy().contains(".tmp");
+        let text_len = self.count_tokens(estimator, truncation)?;
+        // Edited while read: the counts may not be of the hashed contents
+        if mtime_nanos(&self.path)? != mtime {
+            return Ok(());
+        }
+        let content = ContentInfo {
+            line_count: self.line_count,
+            encoding: self.encoding,
+            text_len,
+        };
+        let mut cache = cache.lock().unwrap();
+        cache.insert_content(hash.clone(), content);
+        cache.insert_count(tokenizer, hash, self.token_count);
+        Ok(())
+    }
 
-    // First pass: count total files
-    for entry in WalkDir::new(dir)
-        .follow_links(true)
-        .into_iter()
-        .filter_entry(|e| {
-            if is_test_dir && e.path() == dir {
-                println!("Not filtering test directory root: {}", e.path().display());
-                return true;
+    /// `compute_tokens`, returning the length of the decoded text.
+    fn count_tokens(
+        &mut self,
+        estimator: &TokenEstimator,
+        truncation: Option<&TruncationPolicy>,
+    ) -> io::Result<usize> {
+        self.truncated = None;
+        if self.is_binary {
+            self.token_count = 0;
+            self.line_count = 0;
+            self.counted = true;
+            return Ok(0);
+        }
+        let metadata = std::fs::metadata(&self.path)?;
+        self.modified = metadata.modified().ok();
+        if metadata.len() > STREAMING_THRESHOLD {
+            return self.compute_tokens_streamed(estimator, truncation);
+        }
+        let (content, encoding) = decode_text(std::fs::read(&self.path)?);
+        self.encoding = encoding;
+        self.token_count = estimator.estimate_tokens(&content);
+        self.line_count = content.lines().count();
+        self.counted = true;
+        if let Some(policy) = truncation {
+            if let Some((kept, omitted)) = policy.apply(&content, self.token_count) {
+                self.truncated = Some(Truncation {
+                    tokens: estimator.estimate_tokens(&kept),
+                    kept_lines: self.line_count - omitted.lines(),
+                    omitted,
+                });
             }
+        }
+        Ok(content.len())
+    }
 
-            let is_hidden = is_hidden(e.path());
-            println!(
-                "Checking entry: {}, hidden: {}",
-                e.path().display(),
-                is_hidden
-            );
-            !is_hidden
-        })
-    {
-        match entry {
-            Ok(entry) => {
-                if entry.file_type().is_file() {
-                    println!("Found file: {}", entry.path().display());
-                    total_files += 1;
+    /// Fills in `truncated` for a file known to be over `policy`, reading it
+    /// as a stream and tokenizing only the head and tail lines it keeps.
+    fn truncate_counted(
+        &mut self,
+        estimator: &TokenEstimator,
+        policy: &TruncationPolicy,
+    ) -> io::Result<()> {
+        let mut lines = LineWindow::new(policy.head_lines, policy.tail_lines);
+        read_text_streamed(&self.path, |text| lines.push(text))?;
+        lines.finish();
+        if let Some((kept, omitted)) = lines.truncated() {
+            self.truncated = Some(Truncation {
+                tokens: estimator.estimate_tokens(&kept),
+                kept_lines: lines.count - omitted.lines(),
+                omitted,
+            });
+        }
+        Ok(())
+    }
+
+    /// `compute_tokens` for large files: decodes and tokenizes in pieces,
+    /// keeping only the head and tail lines a truncation needs.
+    fn compute_tokens_streamed(
+        &mut self,
+        estimator: &TokenEstimator,
+        truncation: Option<&TruncationPolicy>,
+    ) -> io::Result<usize> {
+        let (head, tail) = truncation.map_or((0, 0), |p| (p.head_lines, p.tail_lines));
+        let mut tokens = TokenStream::new(estimator);
+        let mut lines = LineWindow::new(head, tail);
+        let mut content_len = 0;
+        self.encoding = read_text_streamed(&self.path, |text| {
+            tokens.push(text);
+            lines.push(text);
+            content_len += text.len();
+        })?;
+        lines.finish();
+        self.token_count = tokens.finish();
+        self.line_count = lines.count;
+        self.counted = true;
+        if let Some(policy) = truncation {
+            if policy.exceeded(content_len, self.token_count) {
+                if let Some((kept, omitted)) = lines.truncated() {
+                    self.truncated = Some(Truncation {
+                        tokens: estimator.estimate_tokens(&kept),
+                        kept_lines: self.line_count - omitted.lines(),
+                        omitted,
+                    });
                 }
             }
-            Err(e) => {
-                eprintln!("Error walking directory: {}", e);
-            }
         }
+        Ok(content_len)
     }
+}
 
-    println!("Total files found: {}", total_files);
+/// Files larger than this are tokenized as a stream instead of read whole.
+const STREAMING_THRESHOLD: u64 = 16 * 1024 * 1024;
+
+/// Counts the lines of streamed text, keeping the first `head` and last
+/// `tail` of them for `truncate_lines`. Only the bytes a truncation can keep
+/// are held: `KEPT_BYTES_PER_LINE` per head line and about twice that per
+/// tail line, however long the lines are.
+struct LineWindow {
+    head_limit: usize,
+    tail_limit: usize,
+    count: usize,
+    /// Line breaks and bytes seen.
+    newlines: usize,
+    len: usize,
+    /// The start of the first `head_limit` lines, and their full length.
+    head: String,
+    head_len: usize,
+    /// The end of the text, and the lengths of its last `tail_limit` lines.
+    tail: String,
+    tail_lines: VecDeque<usize>,
+    in_line: bool,
+}
 
-    // Second pass: process files
-    for entry in WalkDir::new(dir)
-        .follow_links(true)
-        .into_iter()
-        .filter_entry(|e| {
-            if is_test_dir && e.path() == dir {
-                return true;
-            }
-            !is_hidden(e.path())
-        })
-    {
-        match entry {
-            Ok(entry) => {
-                if entry.file_type().is_file() {
-                    println!("Processing file: {}", entry.path().display());
-                    match FileInfo::with_tokens(entry.path().to_path_buf(), estimator) {
-                        Ok(info) => {
-                            files.push(info);
-                        }
-                        Err(e) => {
-                            eprintln!("Error processing file {}: {}", entry.path().display(), e);
-                        }
-                    }
-                    processed_files += 1;
-                    if let Some(tx) = &progress_tx {
-                        let _ = tx.send((processed_files, total_files)).await;
+impl LineWindow {
+    fn new(head_limit: usize, tail_limit: usize) -> Self {
+        Self {
+            head_limit,
+            tail_limit,
+            count: 0,
+            newlines: 0,
+            len: 0,
+            head: String::new(),
+            head_len: 0,
+            tail: String::new(),
+            tail_lines: VecDeque::new(),
+            in_line: false,
+        }
+    }
+
+    fn head_max(&self) -> usize {
+        self.head_limit.saturating_mul(KEPT_BYTES_PER_LINE)
+    }
+
+    fn tail_max(&self) -> usize {
+        self.tail_limit.saturating_mul(KEPT_BYTES_PER_LINE)
+    }
+
+    fn push(&mut self, text: &str) {
+        self.len += text.len();
+        for piece in text.split_inclusive('\n') {
+            if !self.in_line {
+                self.in_line = true;
+                if self.tail_limit > 0 {
+                    self.tail_lines.push_back(0);
+                    if self.tail_lines.len() > self.tail_limit {
+                        self.tail_lines.pop_front();
                     }
                 }
             }
-            Err(e) => {
-                eprintln!("Error walking directory: {}", e);
+            if self.count < self.head_limit {
+                // Nothing after a cut, so the head stays a prefix
+                if self.head.len() == self.head_len {
+                    let room = self.head_max() - self.head.len();
+                    self.head
+                        .push_str(&piece[..floor_char_boundary(piece, room)]);
+                }
+                self.head_len += piece.len();
+            }
+            if let Some(line) = self.tail_lines.back_mut() {
+                *line += piece.len();
+            }
+            if piece.ends_with('\n') {
+                self.newlines += 1;
+                self.end_line();
+            }
+        }
+
+        if self.tail_limit > 0 {
+            self.tail.push_str(text);
+            // Keeps a few bytes over the budget so a cut on a char boundary fits
+            let keep = self.tail_max() + 4;
+            if self.tail.len() > 2 * keep {
+                let cut = ceil_char_boundary(&self.tail, self.tail.len() - keep);
+                self.tail.drain(..cut);
             }
         }
     }
 
-    println!("Processed {} files", processed_files);
-    Ok(files)
+    /// Ends a last line without a trailing newline.
+    fn finish(&mut self) {
+        if self.in_line {
+            self.end_line();
+        }
+    }
+
+    fn end_line(&mut self) {
+        self.count += 1;
+        self.in_line = false;
+    }
+
+    /// Same as `truncate_lines` on the whole text.
+    fn truncated(&self) -> Option<(String, Omitted)> {
+        let head = self.head.as_str();
+        let tail_len = self.tail_lines.iter().sum::<usize>();
+        let keep = tail_len.min(self.tail_max());
+        let tail = &self.tail[ceil_char_boundary(&self.tail, self.tail.len() - keep)..];
+        // The head is a prefix of the text and the tail a suffix
+        if head.len() + tail.len() >= self.len {
+            return None;
+        }
+
+        let omitted = if head.len() == self.head_len && keep == tail_len {
+            Omitted::Lines(self.count - self.head_limit - self.tail_limit)
+        } else {
+            let kept_newlines = head.matches('\n').count() + tail.matches('\n').count();
+            Omitted::Bytes {
+                bytes: (self.len - head.len() - tail.len()) as u64,
+                lines: self.newlines - kept_newlines,
+            }
+        };
+        let mut kept = head.to_string();
+        if !kept.is_empty() && !kept.ends_with('\n') {
+            kept.push('\n');
+        }
+        kept.push_str(&omitted.marker());
+        kept.push('\n');
+        kept.push_str(tail);
+        Some((kept, omitted))
+    }
+}
+
+fn floor_char_boundary(text: &str, mut index: usize) -> usize {
+    if index >= text.len() {
+        return text.len();
+    }
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+fn ceil_char_boundary(text: &str, mut index: usize) -> usize {
+    while !text.is_char_boundary(index) {
+        index += 1;
+    }
+    index
 }
 
 pub async fn read_children(dir: &Path) -> Vec<FileInfo> {
@@ -224,10 +570,96 @@ pub fn get_file_hash(path: &Path) -> io::Result<String> {
     Ok(hasher.finalize().to_hex().to_string())
 }
 
+/// How many leading bytes are inspected to classify a file.
+const SNIFF_LEN: usize = 8192;
+
+/// Above this share of invalid UTF-8 bytes a file is treated as binary.
+const MAX_INVALID_UTF8_RATIO: f64 = 0.3;
+
+/// Signatures of common binary formats, including ones whose first bytes
+/// (e.g. `%PDF`) would otherwise pass as text.
+const BINARY_MAGIC: &[&[u8]] = &[
+    b"\x89PNG\r\n\x1a\n",
+    b"\xff\xd8\xff",
+    b"GIF87a",
+    b"GIF89a",
+    b"%PDF-",
+    b"PK\x03\x04",
+    b"\x1f\x8b",
+    b"\x7fELF",
+    b"\xca\xfe\xba\xbe",
+    b"\xcf\xfa\xed\xfe",
+    b"\xce\xfa\xed\xfe",
+    b"\0asm",
+    b"7z\xbc\xaf\x27\x1c",
+    b"\xfd7zXZ\0",
+    b"Rar!\x1a\x07",
+    b"SQLite format 3\0",
+    b"OggS",
+    b"fLaC",
+];
+
+/// Reads the first few KB of a file for sniffing.
+fn read_sample(path: &Path) -> io::Result<Vec<u8>> {
+    let mut buffer = Vec::with_capacity(SNIFF_LEN);
+    File::open(path)?
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut buffer)?;
+    Ok(buffer)
+}
+
+/// True for content with a known binary signature, a NUL byte, or mostly
+/// invalid UTF-8. UTF-16 with a BOM is text despite its NUL bytes.
+pub fn looks_binary(bytes: &[u8]) -> bool {
+    let sample = &bytes[..bytes.len().min(SNIFF_LEN)];
+    if has_utf16_bom(sample) {
+        return false;
+    }
+    if BINARY_MAGIC.iter().any(|magic| sample.starts_with(magic)) {
+        return true;
+    }
+    if sample.contains(&0) {
+        return true;
+    }
+    invalid_utf8_ratio(sample) > MAX_INVALID_UTF8_RATIO
+}
+
+fn invalid_utf8_ratio(bytes: &[u8]) -> f64 {
+    if bytes.is_empty() {
+        return 0.0;
+    }
+    let mut invalid = 0;
+    let mut rest = bytes;
+    while let Err(e) = std::str::from_utf8(rest) {
+        match e.error_len() {
+            Some(len) => {
+                invalid += len;
+                rest = &rest[e.valid_up_to() + len..];
+            }
+            // A multi-byte sequence cut off by the sniff window
+            None => break,
+        }
+    }
+    invalid as f64 / bytes.len() as f64
+}
+
+/// Placeholder content emitted for a binary file, e.g. "binary file, 34.0 KB, omitted".
+pub fn binary_placeholder(size: u64) -> String {
+    format!("binary file, {}, omitted", ByteSize(size))
+}
+
 pub fn get_file_mtime(path: &Path) -> io::Result<SystemTime> {
     Ok(std::fs::metadata(path)?.modified()?)
 }
 
+/// `get_file_mtime` as nanoseconds since the Unix epoch, as cached.
+pub(crate) fn mtime_nanos(path: &Path) -> io::Result<u64> {
+    let mtime = get_file_mtime(path)?;
+    Ok(mtime
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos() as u64))
+}
+
 async fn count_files(dir: &Path) -> usize {
     let mut count = 0;
     for entry in WalkDir::new(dir)
@@ -244,8 +676,114 @@ async fn count_files(dir: &Path) -> usize {
     count
 }
 
-pub async fn concat_files(paths: &[PathBuf]) -> io::Result<String> {
-    let mut result = String::new();
+/// Everything besides the file list that shapes the copied bundle.
+pub struct BundleOptions<'a> {
+    pub formatter: &'a dyn OutputFormatter,
+    pub estimator: &'a TokenEstimator,
+    /// Pre-rendered ASCII directory tree placed before the first file.
+    pub tree: Option<String>,
+    /// Prefix every line with its right-aligned number, padded to this width.
+    pub line_numbers: Option<usize>,
+    /// Emit a one-line placeholder for binary files instead of dropping them.
+    pub binary_placeholders: bool,
+    /// Per-file cap; oversized files keep only their head and tail lines.
+    pub truncation: Option<TruncationPolicy>,
+    /// Budget packing decisions; omitted files are skipped and truncated ones
+    /// cut to the plan's head/tail lines.
+    pub packing: Option<PackPlan>,
+}
+
+impl<'a> BundleOptions<'a> {
+    pub fn new(formatter: &'a dyn OutputFormatter, estimator: &'a TokenEstimator) -> Self {
+        Self {
+            formatter,
+            estimator,
+            tree: None,
+            line_numbers: None,
+            binary_placeholders: false,
+            truncation: None,
+            packing: None,
+        }
+    }
+}
+
+pub fn concat_files(paths: &[PathBuf], options: &BundleOptions<'_>) -> io::Result<String> {
+    Ok(bundle_pieces(paths, options)?.concat())
+}
+
+/// The bundle `concat_files` copies, as the consecutive pieces rendered by
+/// the formatter: header, tree, then each separator and file, then footer.
+pub fn bundle_pieces(paths: &[PathBuf], options: &BundleOptions<'_>) -> io::Result<Vec<String>> {
+    bundle_pieces_cached(paths, options, &mut RenderedFiles::default())
+}
+
+/// The options that shape one rendered file; a change drops every file
+/// `RenderedFiles` holds.
+#[derive(Clone, PartialEq)]
+struct RenderKey {
+    estimator: TokenEstimator,
+    uses_tokens: bool,
+    line_numbers: Option<usize>,
+    binary_placeholders: bool,
+    truncation: Option<TruncationPolicy>,
+    packed_lines: Option<(usize, usize)>,
+}
+
+impl RenderKey {
+    fn new(options: &BundleOptions<'_>) -> Self {
+        Self {
+            estimator: options.estimator.clone(),
+            uses_tokens: options.formatter.uses_tokens(),
+            line_numbers: options.line_numbers,
+            binary_placeholders: options.binary_placeholders,
+            truncation: options.truncation,
+            packed_lines: options
+                .packing
+                .as_ref()
+                .map(|plan| (plan.head_lines, plan.tail_lines)),
+        }
+    }
+}
+
+/// A file's language, tokens and content as rendered into the bundle, or
+/// `None` for a binary file that is left out.
+type Rendered = Option<(&'static str, usize, String)>;
+
+struct RenderedFile {
+    modified: SystemTime,
+    decision: PackDecision,
+    rendered: Rendered,
+}
+
+/// Files of the latest bundle as `bundle_pieces_cached` rendered them, so a
+/// file is only read again once its mtime or its pack decision changes.
+#[derive(Default)]
+pub struct RenderedFiles {
+    key: Option<RenderKey>,
+    files: HashMap<PathBuf, RenderedFile>,
+}
+
+impl RenderedFiles {
+    pub fn clear(&mut self) {
+        self.key = None;
+        self.files.clear();
+    }
+}
+
+/// `bundle_pieces`, reusing files in `rendered` that have not changed since
+/// they were last rendered.
+pub fn bundle_pieces_cached(
+    paths: &[PathBuf],
+    options: &BundleOptions<'_>,
+    rendered: &mut RenderedFiles,
+) -> io::Result<Vec<String>> {
+    let key = RenderKey::new(options);
+    if rendered.key.as_ref() != Some(&key) {
+        rendered.files.clear();
+        rendered.key = Some(key);
+    }
+    let formatter = options.formatter;
+    let mut pieces = Vec::with_capacity(2 * paths.len() + 3);
     let mut first = true;
 
     // Find common parent directory for relative paths
@@ -279,33 +817,225 @@ pub async fn concat_files(paths: &[PathBuf]) -> io::Result<String> {
         PathBuf::new()
     };
 
+    // Read everything first so bundle-level values (file count, total tokens)
+    // are known before the header is rendered.
+    let mut loaded = Vec::with_capacity(paths.len());
     for path in paths {
-        // Add separator newlines for subsequent files (before the header)
-        if !first {
-            result.push_str("\n\n");
+        let decision = options
+            .packing
+            .as_ref()
+            .map(|plan| plan.decision(path))
+            .unwrap_or(PackDecision::Full);
+        if decision == PackDecision::Omitted {
+            continue;
         }
-
-        // Always add the header for the current file
-        result.push_str("@@@ "); // Use new marker
         let rel_path = path.strip_prefix(&common_parent).unwrap_or(path);
+        let mut display_path = String::new();
         if !rel_path.has_root() && !rel_path.to_string_lossy().starts_with("./") {
-            result.push_str("./");
+            display_path.push_str("./");
         }
-        result.push_str(&rel_path.to_string_lossy());
-        result.push_str(" @@@\n\n"); // Use new marker and add newlines after
+        display_path.push_str(&rel_path.to_string_lossy());
+
+        let modified = std::fs::metadata(path)?.modified().ok();
+        let cached = rendered
+            .files
+            .get(path)
+            .filter(|file| Some(file.modified) == modified && file.decision == decision);
+        let file = match cached {
+            Some(file) => file.rendered.clone(),
+            None => {
+                let file = render_file(path, decision, options)?;
+                if let Some(modified) = modified {
+                    rendered.files.insert(
+                        path.clone(),
+                        RenderedFile {
+                            modified,
+                            decision,
+                            rendered: file.clone(),
+                        },
+                    );
+                }
+                file
+            }
+        };
+        if let Some((lang, tokens, content)) = file {
+            loaded.push((display_path, lang, tokens, content));
+        }
+    }
+    // Only files of the latest bundle are kept
+    rendered.files.retain(|path, _| paths.contains(path));
+
+    let summary = BundleSummary {
+        file_count: loaded.len(),
+        total_tokens: loaded.iter().map(|(_, _, tokens, _)| tokens).sum(),
+    };
 
-        // Always add the content
-        let file = File::open(path)?;
-        let mut reader = BufReader::new(file);
-        let mut content = String::new();
-        reader.read_to_string(&mut content)?;
-        result.push_str(&content);
+    pieces.push(formatter.header(&summary));
+    if let Some(tree) = &options.tree {
+        pieces.push(formatter.tree(tree));
+    }
+    for (display_path, lang, tokens, content) in &loaded {
+        // Add the format's separator for subsequent files (before the header)
+        if !first {
+            pieces.push(formatter.separator());
+        }
+
+        pieces.push(formatter.file(&FileEntry {
+            path: display_path,
+            lang,
+            tokens: *tokens,
+            content,
+        }));
 
         // Mark that we are no longer on the first file
         first = false;
     }
+    pieces.push(formatter.footer(&summary));
+
+    Ok(pieces)
+}
+
+/// Reads one file and renders it as the bundle holds it: numbered, cut by
+/// the cap or the pack plan, with its tokens if the formatter shows them.
+fn render_file(
+    path: &Path,
+    decision: PackDecision,
+    options: &BundleOptions<'_>,
+) -> io::Result<Rendered> {
+    let uses_tokens = options.formatter.uses_tokens();
+    // Sniff first so binaries are never read whole
+    if looks_binary(&read_sample(path)?) {
+        if !options.binary_placeholders {
+            return Ok(None);
+        }
+        let placeholder = binary_placeholder(std::fs::metadata(path)?.len());
+        let tokens = if uses_tokens {
+            options.estimator.estimate_tokens(&placeholder)
+        } else {
+            0
+        };
+        return Ok(Some(("", tokens, placeholder)));
+    }
+    let mut bytes = Vec::new();
+    BufReader::new(File::open(path)?).read_to_end(&mut bytes)?;
+    let (mut content, _) = decode_text(bytes);
+    let over_cap = options.truncation.filter(|policy| {
+        let tokens = match policy.limit {
+            FileLimit::Tokens(_) => options.estimator.estimate_tokens(&content),
+            FileLimit::Bytes(_) => 0,
+        };
+        policy.exceeded_by(&content, tokens)
+    });
+    // Number before cutting so the tail keeps its original line numbers
+    if let Some(width) = options.line_numbers {
+        content = number_lines(&content, width);
+    }
+    // A file the cap already cut is not cut again by packing
+    let cut = match (over_cap, decision, &options.packing) {
+        (Some(policy), _, _) => Some((policy.head_lines, policy.tail_lines)),
+        (None, PackDecision::Truncated, Some(plan)) => Some((plan.head_lines, plan.tail_lines)),
+        _ => None,
+    };
+    if let Some((kept, _)) = cut.and_then(|(head, tail)| truncate_lines(&content, head, tail)) {
+        content = kept;
+    }
+
+    let tokens = if uses_tokens {
+        options.estimator.estimate_tokens(&content)
+    } else {
+        0
+    };
+    Ok(Some((
+        language_for_path(path).unwrap_or(""),
+        tokens,
+        content,
+    )))
+}
+
+/// Keeps the first `head` and last `tail` lines with an omission marker in
+/// between, or returns `None` if that would not omit anything. The head and
+/// tail are also cut to `KEPT_BYTES_PER_LINE` bytes per line, so a file of a
+/// few overlong lines is cut by bytes instead.
+pub fn truncate_lines(content: &str, head: usize, tail: usize) -> Option<(String, Omitted)> {
+    let mut lines = LineWindow::new(head, tail);
+    lines.push(content);
+    lines.finish();
+    lines.truncated()
+}
+
+pub fn omission_marker(omitted: usize) -> String {
+    let digits = omitted.to_string();
+    let mut grouped = String::with_capacity(digits.len() + digits.len() / 3);
+    for (idx, digit) in digits.chars().enumerate() {
+        if idx > 0 && (digits.len() - idx).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    let noun = if omitted == 1 { "line" } else { "lines" };
+    format!("… {} {} omitted …", grouped, noun)
+}
+
+/// Prefixes each line with its 1-based number, right-aligned to `width`
+/// (wider numbers simply grow), e.g. `  12 | let x = 1;`.
+pub fn number_lines(content: &str, width: usize) -> String {
+    let mut numbered = String::with_capacity(content.len() + content.len() / 4);
+    for (idx, line) in content.split_inclusive('\n').enumerate() {
+        numbered.push_str(&line_number_prefix(idx + 1, width));
+        numbered.push_str(line);
+    }
+    numbered
+}
+
+fn line_number_prefix(line: usize, width: usize) -> String {
+    format!("{:>width$} | ", line, width = width)
+}
+
+/// Tokens added by `number_lines` for one prefix width and estimator.
+/// Prefixes with the same number of digits tokenize alike, so each digit
+/// count is measured once on a sample and every file is priced from that.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineNumberCost {
+    /// Average tokens of one prefix, indexed by digit count - 1.
+    per_prefix: Vec<f64>,
+}
+
+impl LineNumberCost {
+    const MAX_DIGITS: u32 = 12;
+    const SAMPLES: usize = 16;
+
+    pub fn new(width: usize, estimator: &TokenEstimator) -> Self {
+        let per_prefix = (1..=Self::MAX_DIGITS)
+            .map(|digits| {
+                let first = 10usize.pow(digits - 1);
+                // Cycles through 1..=9 for single digits
+                let prefixes: String = (0..Self::SAMPLES)
+                    .map(|i| line_number_prefix(first + i % (first * 9), width))
+                    .collect();
+                estimator.estimate_tokens(&prefixes) as f64 / Self::SAMPLES as f64
+            })
+            .collect();
+        Self { per_prefix }
+    }
 
-    Ok(result)
+    /// Tokens added to a file with `line_count` lines.
+    pub fn tokens(&self, line_count: usize) -> usize {
+        let mut total = 0.0;
+        let mut first = 1usize;
+        for (digits, cost) in self.per_prefix.iter().enumerate() {
+            if line_count < first {
+                break;
+            }
+            let last = match first.checked_mul(10) {
+                Some(next) if digits + 1 < self.per_prefix.len() => line_count.min(next - 1),
+                _ => line_count,
+            };
+            total += (last - first + 1) as f64 * cost;
+            first = last + 1;
+        }
+        // Guards against sums like 2.9999… flooring a whole count down
+        (total + 1e-6) as usize
+    }
 }
 
 pub async fn list_files(dir: &Path) -> io::Result<Vec<FileInfo>> {
@@ -399,6 +1129,8 @@ pub fn get_all_workspace_files(workspace_root_path: &Path) -> io::Result<Vec<Str
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::cache;
+    use crate::output_format::{HeadersFormatter, OutputTemplate, XmlFormatter};
     use std::fs;
     use std::io::{self, Write};
     use tempfile::tempdir;
@@ -425,7 +1157,7 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_file_info_with_tokens() {
+    async fn test_file_info_compute_tokens() {
         let dir = tempdir().unwrap();
         let file_path = dir.path().join("test.txt");
         tokio_fs::write(&file_path, "Hello, world!\n")
@@ -433,49 +1165,57 @@ mod tests {
             .unwrap();
 
         let estimator = TokenEstimator::CharDiv4;
-        let info = FileInfo::with_tokens(file_path.clone(), &estimator).unwrap();
+        let mut info = FileInfo::new(file_path.clone()).unwrap();
+        info.compute_tokens(&estimator, None).unwrap();
         assert_eq!(info.name, "test.txt");
         assert_eq!(info.path, file_path);
         assert_eq!(info.size, 14); // "Hello, world!\n" = 14 bytes
         assert_eq!(info.token_count, 3); // 14 chars / 4 ≈ 3 tokens (actual implementation rounds down)
+        assert_eq!(info.line_count, 1);
     }
 
+    /// Per-file counting cost on the worker pool with a shared BPE. Run with
+    /// `cargo test --release bench_count_cl100k -- --ignored --nocapture`.
     #[tokio::test]
-    async fn test_crawl_directory() {
+    #[ignore]
+    async fn bench_count_cl100k() {
+        const FILES: usize = 3_000;
         let dir = tempdir().unwrap();
-        println!("Test directory: {}", dir.path().display());
-
-        // Create test files
-        let file1_path = dir.path().join("file1.txt");
-        println!("Creating file1: {}", file1_path.display());
-        tokio_fs::write(&file1_path, "Hello, world!\n")
-            .await
-            .unwrap();
-        assert!(file1_path.exists(), "file1.txt was not created");
-
-        let file2_path = dir.path().join("file2.txt");
-        println!("Creating file2: {}", file2_path.display());
-        tokio_fs::write(&file2_path, "Another test file\n")
-            .await
-            .unwrap();
-        assert!(file2_path.exists(), "file2.txt was not created");
-
-        // Verify files are readable
-        let content1 = tokio_fs::read_to_string(&file1_path).await.unwrap();
-        let content2 = tokio_fs::read_to_string(&file2_path).await.unwrap();
-        assert_eq!(content1, "Hello, world!\n");
-        assert_eq!(content2, "Another test file\n");
-
-        let estimator = TokenEstimator::CharDiv4;
-        let files = crawl(dir.path(), &estimator, None).await.unwrap();
+        for i in 0..FILES {
+            let content = format!("fn item_{i}() -> usize {{\n    {i} * 2 // doubled\n}}\n");
+            tokio_fs::write(dir.path().join(format!("file_{i}.rs")), content.repeat(20))
+                .await
+                .unwrap();
+        }
 
-        println!(
-            "Found files: {:?}",
-            files.iter().map(|f| &f.name).collect::<Vec<_>>()
+        let build_start = std::time::Instant::now();
+        tiktoken_rs::cl100k_base().unwrap();
+        let build_cost = build_start.elapsed();
+
+        let paths: Vec<PathBuf> = (0..FILES)
+            .map(|i| dir.path().join(format!("file_{i}.rs")))
+            .collect();
+        let start = std::time::Instant::now();
+        let mut results = crate::worker_pool::spawn(paths, |path: PathBuf| {
+            let mut info = FileInfo::new(path)?;
+            info.compute_tokens(&TokenEstimator::Cl100k, None)?;
+            io::Result::Ok(info)
+        });
+        let mut files = Vec::with_capacity(FILES);
+        while let Some(info) = results.recv().await {
+            files.push(info.unwrap());
+        }
+        let elapsed = start.elapsed();
+
+        assert_eq!(files.len(), FILES);
+        assert!(files.iter().all(|f| f.token_count > 0));
+        eprintln!(
+            "counted {} files in {:?}: {:?} per file (building cl100k once takes {:?})",
+            FILES,
+            elapsed,
+            elapsed / FILES as u32,
+            build_cost
         );
-        assert_eq!(files.len(), 2);
-        assert!(files.iter().any(|f| f.name == "file1.txt"));
-        assert!(files.iter().any(|f| f.name == "file2.txt"));
     }
 
     #[tokio::test]
@@ -499,7 +1239,11 @@ mod tests {
             .unwrap();
 
         let paths = vec![file1_path.clone(), file2_path.clone()];
-        let result = concat_files(&paths).await.unwrap();
+        let result = concat_files(
+            &paths,
+            &BundleOptions::new(&HeadersFormatter, &TokenEstimator::CharDiv4),
+        )
+        .unwrap();
 
         assert!(result.contains("Hello, world!"));
         assert!(result.contains("Another test file"));
@@ -540,7 +1284,11 @@ mod tests {
             .unwrap();
 
         let paths = vec![file1_path.clone(), file2_path.clone(), file3_path.clone()];
-        let result = concat_files(&paths).await.unwrap();
+        let result = concat_files(
+            &paths,
+            &BundleOptions::new(&HeadersFormatter, &TokenEstimator::CharDiv4),
+        )
+        .unwrap();
 
         // Define expected parts
         let expected_header_1 = "@@@ ./file1.txt @@@\n\n"; // Header for first file
@@ -567,6 +1315,595 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_rendered_files_follow_mtime() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        fs::write(&path, "one\n").unwrap();
+        let mtime = fs::metadata(&path).unwrap().modified().unwrap();
+        let paths = vec![path.clone()];
+        let options = BundleOptions::new(&HeadersFormatter, &TokenEstimator::CharDiv4);
+        let mut rendered = RenderedFiles::default();
+        let bundle = |rendered: &mut RenderedFiles| {
+            bundle_pieces_cached(&paths, &options, rendered)
+                .unwrap()
+                .concat()
+        };
+        assert!(bundle(&mut rendered).contains("one"));
+
+        // Same mtime: the file is not read again
+        fs::write(&path, "two\n").unwrap();
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(mtime).unwrap();
+        assert!(bundle(&mut rendered).contains("one"));
+
+        file.set_modified(mtime + std::time::Duration::from_secs(60))
+            .unwrap();
+        assert!(bundle(&mut rendered).contains("two"));
+
+        // Other options render every file again, even at the same mtime
+        fs::write(&path, "three\n").unwrap();
+        file.set_modified(mtime + std::time::Duration::from_secs(60))
+            .unwrap();
+        let mut numbered = BundleOptions::new(&HeadersFormatter, &TokenEstimator::CharDiv4);
+        numbered.line_numbers = Some(2);
+        let pieces = bundle_pieces_cached(&paths, &numbered, &mut rendered).unwrap();
+        assert!(pieces.concat().contains(" 1 | three"));
+    }
+
+    #[tokio::test]
+    async fn test_concat_files_xml() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let file1_path = dir_path.join("file1.txt");
+        tokio_fs::write(&file1_path, "Content of file 1.\n")
+            .await
+            .unwrap();
+
+        let file2_path = dir_path.join("file2.html");
+        tokio_fs::write(&file2_path, "<p>done</p></file>")
+            .await
+            .unwrap();
+
+        let paths = vec![file1_path.clone(), file2_path.clone()];
+        let result = concat_files(
+            &paths,
+            &BundleOptions::new(&XmlFormatter, &TokenEstimator::CharDiv4),
+        )
+        .unwrap();
+
+        assert_eq!(
+            result,
+            "<file path=\"./file1.txt\">\nContent of file 1.\n</file>\n\
+             <file path=\"./file2.html\">\n<p>done</p>&lt;/file>\n</file>"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_concat_files_template() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let file1_path = dir_path.join("main.rs");
+        tokio_fs::write(&file1_path, "fn main() {}").await.unwrap();
+        let file2_path = dir_path.join("notes.txt");
+        tokio_fs::write(&file2_path, "12345678").await.unwrap();
+
+        let template = OutputTemplate {
+            name: "Test".to_string(),
+            header: "{count} files, {tokens} tokens\n".to_string(),
+            tree: "{tree}---\n".to_string(),
+            file: "## {path} ({lang}, {tokens})\n{content}".to_string(),
+            separator: "\n--\n".to_string(),
+            footer: "\nEOF".to_string(),
+        };
+
+        let paths = vec![file1_path, file2_path];
+        let mut options = BundleOptions::new(&template, &TokenEstimator::CharDiv4);
+        options.tree = Some("ws/\n".to_string());
+        let result = concat_files(&paths, &options).unwrap();
+
+        assert_eq!(
+            result,
+            "2 files, 5 tokens\n\
+             ws/\n---\n\
+             ## ./main.rs (rust, 3)\nfn main() {}\n--\n\
+             ## ./notes.txt (, 2)\n12345678\nEOF"
+        );
+    }
+
+    #[test]
+    fn test_number_lines() {
+        assert_eq!(number_lines("a\nb\n", 3), "  1 | a\n  2 | b\n");
+        assert_eq!(number_lines("a\n\nc", 1), "1 | a\n2 | \n3 | c");
+        assert_eq!(number_lines("", 4), "");
+
+        let long: String = (0..10).map(|i| format!("{}\n", i)).collect();
+        let numbered = number_lines(&long, 1);
+        assert!(numbered.ends_with("10 | 9\n"));
+    }
+
+    #[test]
+    fn test_line_number_cost() {
+        let cost = LineNumberCost::new(4, &TokenEstimator::CharDiv4);
+        assert_eq!(cost.tokens(0), 0);
+        // "   1 | " + "   2 | " = 14 chars
+        assert_eq!(cost.tokens(2), 3);
+        // 1,000 prefixes of 7 chars
+        assert_eq!(cost.tokens(1_000), 1_750);
+        // Past the width the prefix grows: "12345 | " is 8 chars
+        let wide = (1..=12_345)
+            .map(|line| line_number_prefix(line, 4))
+            .collect::<String>();
+        assert_eq!(
+            cost.tokens(12_345),
+            TokenEstimator::CharDiv4.estimate_tokens(&wide)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_concat_files_line_numbers() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("main.rs");
+        tokio_fs::write(&file_path, "fn main() {\n}\n")
+            .await
+            .unwrap();
+
+        let estimator = TokenEstimator::CharDiv4;
+        let mut options = BundleOptions::new(&HeadersFormatter, &estimator);
+        options.line_numbers = Some(2);
+        let result = concat_files(&[file_path], &options).unwrap();
+
+        assert_eq!(result, "@@@ ./main.rs @@@\n\n 1 | fn main() {\n 2 | }\n");
+    }
+
+    #[test]
+    fn test_looks_binary() {
+        assert!(!looks_binary(b""));
+        assert!(!looks_binary("fn main() {}\n// caf\u{e9}\n".as_bytes()));
+        assert!(looks_binary(b"hello\0world"));
+        assert!(looks_binary(b"\x89PNG\r\n\x1a\nrest"));
+        assert!(looks_binary(b"%PDF-1.7\n%text-like header\n"));
+        assert!(looks_binary(&[0xc3, 0x28, 0xa0, 0xa1, 0x80, 0x81, b'a']));
+        // A few stray Latin-1 bytes are not enough to call a file binary
+        assert!(!looks_binary(b"caf\xe9 au lait, cr\xe8me br\xfbl\xe9e\n"));
+        // A multi-byte character split by the sniff window is not invalid
+        let mut cut = vec![b'a'; SNIFF_LEN - 1];
+        cut.extend_from_slice("\u{e9}".as_bytes());
+        assert!(!looks_binary(&cut));
+    }
+
+    #[test]
+    fn test_file_info_marks_binary_files() {
+        let dir = tempdir().unwrap();
+        let image_path = dir.path().join("logo.png");
+        fs::write(&image_path, b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").unwrap();
+
+        let mut info = FileInfo::new(image_path).unwrap();
+        assert!(info.is_binary);
+        info.compute_tokens(&TokenEstimator::CharDiv4, None)
+            .unwrap();
+        assert_eq!(info.token_count, 0);
+        assert_eq!(info.line_count, 0);
+
+        let text_path = dir.path().join("notes.txt");
+        fs::write(&text_path, "plain text\n").unwrap();
+        assert!(!FileInfo::new(text_path).unwrap().is_binary);
+    }
+
+    #[test]
+    fn test_streamed_count_matches_whole_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("data.log");
+        let content: String = (0..500)
+            .map(|i| format!("{i}: caf\u{e9} request ok\n"))
+            .collect::<String>()
+            + "no trailing newline";
+        std::fs::write(&path, &content).unwrap();
+        let estimator = TokenEstimator::Cl100k;
+        let policy = TruncationPolicy {
+            limit: FileLimit::Bytes(1_000),
+            head_lines: 20,
+            tail_lines: 5,
+        };
+
+        for truncation in [None, Some(&policy)] {
+            let mut whole = FileInfo::new(path.clone()).unwrap();
+            whole.compute_tokens(&estimator, truncation).unwrap();
+            let mut streamed = FileInfo::new(path.clone()).unwrap();
+            streamed
+                .compute_tokens_streamed(&estimator, truncation)
+                .unwrap();
+            assert_eq!(streamed, whole);
+        }
+        let mut streamed = FileInfo::new(path).unwrap();
+        streamed
+            .compute_tokens_streamed(&estimator, Some(&policy))
+            .unwrap();
+        assert_eq!(streamed.line_count, 501);
+        assert_eq!(streamed.truncated.unwrap().omitted, Omitted::Lines(476));
+    }
+
+    #[test]
+    fn test_empty_file_is_counted() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("empty.rs");
+        fs::write(&path, "").unwrap();
+
+        let mut info = FileInfo::new(path).unwrap();
+        assert!(!info.counted);
+        info.compute_tokens(&TokenEstimator::CharDiv4, None)
+            .unwrap();
+        assert!(info.counted);
+        assert_eq!(info.token_count, 0);
+    }
+
+    #[test]
+    fn test_truncate_lines() {
+        let content: String = (1..=10).map(|n| format!("line {}\n", n)).collect();
+        let (kept, omitted) = truncate_lines(&content, 2, 1).unwrap();
+        assert_eq!(omitted, Omitted::Lines(7));
+        assert_eq!(kept, "line 1\nline 2\n… 7 lines omitted …\nline 10\n");
+
+        assert!(truncate_lines(&content, 5, 5).is_none());
+        assert_eq!(omission_marker(1), "… 1 line omitted …");
+        assert_eq!(omission_marker(1243), "… 1,243 lines omitted …");
+        assert_eq!(omission_marker(1_000_000), "… 1,000,000 lines omitted …");
+    }
+
+    #[test]
+    fn test_truncate_long_lines() {
+        // One minified line: too few lines to cut, so bytes are cut instead
+        let minified = "é".repeat(5_000);
+        let (kept, omitted) = truncate_lines(&minified, 1, 1).unwrap();
+        assert_eq!(
+            omitted,
+            Omitted::Bytes {
+                bytes: 10_000 - 2_000,
+                lines: 0
+            }
+        );
+        assert_eq!(
+            kept,
+            format!(
+                "{}\n… 8.0 KB omitted …\n{}",
+                "é".repeat(500),
+                "é".repeat(500)
+            )
+        );
+
+        // A long head line is cut to its byte budget along with the lines after it
+        let content = format!("{}\nshort\nlast\n", "x".repeat(3_000));
+        let (kept, omitted) = truncate_lines(&content, 1, 1).unwrap();
+        assert_eq!(
+            omitted,
+            Omitted::Bytes {
+                bytes: 2_007,
+                lines: 2
+            }
+        );
+        assert!(kept.ends_with("\n… 2.0 KB omitted …\nlast\n"));
+
+        assert!(truncate_lines("short\n", 1, 1).is_none());
+    }
+
+    #[test]
+    fn test_line_window_holds_only_kept_bytes() {
+        // A single 2 MB line, streamed in chunks
+        let line = "é".repeat(1_000_000);
+        let mut window = LineWindow::new(2, 1);
+        let mut rest = line.as_str();
+        while !rest.is_empty() {
+            let (chunk, next) = rest.split_at(floor_char_boundary(rest, 4_097));
+            window.push(chunk);
+            rest = next;
+        }
+        window.finish();
+        assert!(window.head.len() <= 2 * KEPT_BYTES_PER_LINE);
+        assert!(window.tail.len() <= 2 * (KEPT_BYTES_PER_LINE + 4) + 4_097);
+
+        let (kept, omitted) = window.truncated().unwrap();
+        assert_eq!(Some((kept.clone(), omitted)), truncate_lines(&line, 2, 1));
+        assert_eq!(
+            kept,
+            format!(
+                "{}\n… 2.0 MB omitted …\n{}",
+                "é".repeat(1_000),
+                "é".repeat(500)
+            )
+        );
+    }
+
+    #[test]
+    fn test_compute_tokens_with_truncation() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("generated.rs");
+        let content: String = (1..=100)
+            .map(|n| format!("const X{}: u32 = {};\n", n, n))
+            .collect();
+        fs::write(&file_path, &content).unwrap();
+
+        let estimator = TokenEstimator::CharDiv4;
+        let policy = TruncationPolicy {
+            limit: FileLimit::Bytes(1024),
+            head_lines: 3,
+            tail_lines: 2,
+        };
+        let mut info = FileInfo::new(file_path).unwrap();
+        info.compute_tokens(&estimator, Some(&policy)).unwrap();
+        let truncated = info.truncated.unwrap();
+        assert_eq!(truncated.omitted, Omitted::Lines(95));
+        assert_eq!(truncated.kept_lines, 5);
+        assert!(truncated.tokens < info.token_count / 10);
+
+        let generous = TruncationPolicy {
+            limit: FileLimit::Tokens(info.token_count),
+            ..policy
+        };
+        info.compute_tokens(&estimator, Some(&generous)).unwrap();
+        assert_eq!(info.truncated, None);
+    }
+
+    #[test]
+    fn test_compute_tokens_cached() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("lib.rs");
+        fs::write(&path, "pub fn answer() -> u32 {\n    42\n}\n").unwrap();
+        let estimator = TokenEstimator::Cl100k;
+        let tokenizer = cache::tokenizer_id(&estimator, None);
+        let cache = Mutex::new(TokenCache::default());
+        let cached_count = |count: Option<usize>| {
+            let mut cache = cache.lock().unwrap();
+            let entry = cache.get_entry(&path).unwrap().clone();
+            if let Some(count) = count {
+                cache.insert_count(&tokenizer, entry.hash.clone(), count);
+            }
+            let tokens = cache.get_count(&tokenizer, &entry.hash).unwrap();
+            (entry, tokens)
+        };
+
+        let mut info = FileInfo::new(path.clone()).unwrap();
+        info.compute_tokens_cached(&estimator, &tokenizer, None, &cache)
+            .unwrap();
+        let counted = info.token_count;
+        assert!(counted > 0);
+        assert_eq!(cached_count(None).1, counted);
+
+        // A planted count proves the file is not read again
+        cached_count(Some(999));
+        info.compute_tokens_cached(&estimator, &tokenizer, None, &cache)
+            .unwrap();
+        assert_eq!(info.token_count, 999);
+
+        // Touched but identical: the hash matches and the new mtime is kept
+        let before = cached_count(None).0.mtime;
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + std::time::Duration::from_secs(60))
+            .unwrap();
+        info.compute_tokens_cached(&estimator, &tokenizer, None, &cache)
+            .unwrap();
+        assert_eq!(info.token_count, 999);
+        assert_ne!(cached_count(None).0.mtime, before);
+
+        // Over the cap, the file is read again to truncate it but the cached
+        // total is kept
+        let policy = TruncationPolicy {
+            limit: FileLimit::Tokens(10),
+            head_lines: 1,
+            tail_lines: 1,
+        };
+        info.compute_tokens_cached(&estimator, &tokenizer, Some(&policy), &cache)
+            .unwrap();
+        assert_eq!(info.token_count, 999);
+        let truncated = info.truncated.unwrap();
+        assert_eq!(truncated.kept_lines, 2);
+        let mut whole = info.clone();
+        whole.compute_tokens(&estimator, Some(&policy)).unwrap();
+        assert_eq!(whole.truncated, Some(truncated));
+
+        // Edited: counted again
+        fs::write(&path, "pub fn answer() -> u32 {\n    4242 + 4242\n}\n").unwrap();
+        file.set_modified(SystemTime::now() + std::time::Duration::from_secs(120))
+            .unwrap();
+        info.compute_tokens_cached(&estimator, &tokenizer, None, &cache)
+            .unwrap();
+        assert!(info.token_count > counted);
+        assert_eq!(cached_count(None).1, info.token_count);
+    }
+
+    #[test]
+    fn test_cached_counts_survive_switching_estimators() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("notes.md");
+        fs::write(&path, "# Notes\n\nSwitching tokenizers keeps counts.\n").unwrap();
+        let cache = Mutex::new(TokenCache::default());
+        let cl100k = TokenEstimator::Cl100k;
+        let chars = TokenEstimator::CharDiv4;
+        let cl100k_id = cache::tokenizer_id(&cl100k, None);
+        let chars_id = cache::tokenizer_id(&chars, None);
+
+        let mut info = FileInfo::new(path.clone()).unwrap();
+        info.compute_tokens_cached(&cl100k, &cl100k_id, None, &cache)
+            .unwrap();
+        let cl100k_count = info.token_count;
+        info.compute_tokens_cached(&chars, &chars_id, None, &cache)
+            .unwrap();
+        let chars_count = info.token_count;
+        assert_ne!(cl100k_count, chars_count);
+
+        // Both counts are kept, so switching back is a lookup
+        let hash = cache.lock().unwrap().get_entry(&path).unwrap().hash.clone();
+        cache
+            .lock()
+            .unwrap()
+            .insert_count(&cl100k_id, hash.clone(), 999);
+        info.compute_tokens_cached(&cl100k, &cl100k_id, None, &cache)
+            .unwrap();
+        assert_eq!(info.token_count, 999);
+        assert_eq!(
+            cache.lock().unwrap().get_count(&chars_id, &hash),
+            Some(chars_count)
+        );
+    }
+
+    #[test]
+    fn test_identical_files_share_counts() {
+        let dir = tempdir().unwrap();
+        let original = dir.path().join("app").join("util.rs");
+        let copy = dir.path().join("app-worktree").join("util.rs");
+        for path in [&original, &copy] {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "pub fn double(x: u32) -> u32 {\n    x * 2\n}\n").unwrap();
+        }
+        let estimator = TokenEstimator::Cl100k;
+        let tokenizer = cache::tokenizer_id(&estimator, None);
+        let cache = Mutex::new(TokenCache::default());
+
+        let mut info = FileInfo::new(original.clone()).unwrap();
+        info.compute_tokens_cached(&estimator, &tokenizer, None, &cache)
+            .unwrap();
+        let hash = cache
+            .lock()
+            .unwrap()
+            .get_entry(&original)
+            .unwrap()
+            .hash
+            .clone();
+        cache
+            .lock()
+            .unwrap()
+            .insert_count(&tokenizer, hash.clone(), 999);
+
+        // The copy is hashed, not read, and finds the planted count
+        let mut info = FileInfo::new(copy.clone()).unwrap();
+        info.compute_tokens_cached(&estimator, &tokenizer, None, &cache)
+            .unwrap();
+        assert_eq!(info.token_count, 999);
+        assert_eq!(info.line_count, 3);
+        assert_eq!(cache.lock().unwrap().get_entry(&copy).unwrap().hash, hash);
+    }
+
+    #[tokio::test]
+    async fn test_concat_files_truncation() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("big.txt");
+        tokio_fs::write(&file_path, "a\nb\nc\nd\ne\n")
+            .await
+            .unwrap();
+
+        let estimator = TokenEstimator::CharDiv4;
+        let mut options = BundleOptions::new(&HeadersFormatter, &estimator);
+        options.line_numbers = Some(1);
+        options.truncation = Some(TruncationPolicy {
+            limit: FileLimit::Bytes(4),
+            head_lines: 1,
+            tail_lines: 1,
+        });
+        let result = concat_files(&[file_path], &options).unwrap();
+
+        assert_eq!(
+            result,
+            "@@@ ./big.txt @@@\n\n1 | a\n… 3 lines omitted …\n5 | e\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_concat_files_packing() {
+        let dir = tempdir().unwrap();
+        let kept_path = dir.path().join("kept.txt");
+        let cut_path = dir.path().join("cut.txt");
+        let dropped_path = dir.path().join("dropped.txt");
+        tokio_fs::write(&kept_path, "kept\n").await.unwrap();
+        tokio_fs::write(&cut_path, "1\n2\n3\n4\n").await.unwrap();
+        tokio_fs::write(&dropped_path, "dropped\n").await.unwrap();
+
+        let estimator = TokenEstimator::CharDiv4;
+        let mut options = BundleOptions::new(&HeadersFormatter, &estimator);
+        options.packing = Some(PackPlan {
+            budget: 10,
+            decisions: [
+                (cut_path.clone(), PackDecision::Truncated),
+                (dropped_path.clone(), PackDecision::Omitted),
+            ]
+            .into_iter()
+            .collect(),
+            total_tokens: 10,
+            head_lines: 1,
+            tail_lines: 1,
+        });
+        let result = concat_files(&[kept_path, cut_path.clone(), dropped_path], &options).unwrap();
+
+        assert_eq!(
+            result,
+            "@@@ ./kept.txt @@@\n\nkept\n\n\n@@@ ./cut.txt @@@\n\n1\n… 2 lines omitted …\n4\n"
+        );
+
+        // Over the cap as well: cut once, not again by packing
+        options.truncation = Some(TruncationPolicy {
+            limit: FileLimit::Bytes(4),
+            head_lines: 1,
+            tail_lines: 1,
+        });
+        let result = concat_files(&[cut_path], &options).unwrap();
+        assert_eq!(result, "@@@ ./cut.txt @@@\n\n1\n… 2 lines omitted …\n4\n");
+    }
+
+    #[tokio::test]
+    async fn test_legacy_encodings() {
+        let dir = tempdir().unwrap();
+        let latin1_path = dir.path().join("vendor.c");
+        let utf16_path = dir.path().join("notes.txt");
+        tokio_fs::write(&latin1_path, b"/* \xa9 Soci\xe9t\xe9 */\n")
+            .await
+            .unwrap();
+        tokio_fs::write(&utf16_path, b"\xff\xfeo\0k\0\n\0")
+            .await
+            .unwrap();
+
+        let estimator = TokenEstimator::CharDiv4;
+        let mut latin1 = FileInfo::new(latin1_path.clone()).unwrap();
+        latin1.compute_tokens(&estimator, None).unwrap();
+        assert!(!latin1.is_binary);
+        assert_eq!(latin1.encoding, TextEncoding::Windows1252);
+        assert_eq!(latin1.line_count, 1);
+        let mut utf16 = FileInfo::new(utf16_path.clone()).unwrap();
+        utf16.compute_tokens(&estimator, None).unwrap();
+        assert!(!utf16.is_binary);
+        assert_eq!(utf16.encoding, TextEncoding::Utf16Le);
+
+        let options = BundleOptions::new(&HeadersFormatter, &estimator);
+        let result = concat_files(&[latin1_path, utf16_path], &options).unwrap();
+        assert_eq!(
+            result,
+            "@@@ ./vendor.c @@@\n\n/* \u{a9} Soci\u{e9}t\u{e9} */\n\n\n@@@ ./notes.txt @@@\n\nok\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_concat_files_binary() {
+        let dir = tempdir().unwrap();
+        let text_path = dir.path().join("a.txt");
+        let binary_path = dir.path().join("b.bin");
+        tokio_fs::write(&text_path, "text").await.unwrap();
+        tokio_fs::write(&binary_path, vec![0u8; 34_000])
+            .await
+            .unwrap();
+        let paths = vec![text_path, binary_path];
+
+        let estimator = TokenEstimator::CharDiv4;
+        let mut options = BundleOptions::new(&HeadersFormatter, &estimator);
+        let result = concat_files(&paths, &options).unwrap();
+        assert_eq!(result, "@@@ ./a.txt @@@\n\ntext");
+
+        options.binary_placeholders = true;
+        let result = concat_files(&paths, &options).unwrap();
+        assert_eq!(
+            result,
+            "@@@ ./a.txt @@@\n\ntext\n\n@@@ ./b.bin @@@\n\nbinary file, 34.0 KB, omitted"
+        );
+    }
+
     // Helper to create a basic file structure for testing
     fn setup_test_directory() -> io::Result<tempfile::TempDir> {
         let dir = tempdir()?;
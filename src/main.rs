@@ -4,23 +4,38 @@ use dioxus::prelude::*;
 use dioxus_desktop::muda;
 use dioxus_desktop::use_muda_event_handler;
 use dioxus_desktop::{Config, LogicalSize, WindowBuilder};
-use std::collections::HashSet;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tracing_subscriber::EnvFilter;
 use tracing_subscriber::{fmt, prelude::*};
 
+mod budget;
+mod bundle_order;
+mod bundle_tokens;
 mod cache;
+mod cache_log;
 mod components;
+mod encoding;
 mod fs_utils;
 mod gitignore_handler;
+mod models;
+mod output_format;
+mod sentencepiece;
 mod settings;
 mod tokenizer;
+mod worker_pool;
 mod workspace_event_handler;
 
-use components::{FileTree, Footer, Toolbar};
-use fs_utils::FileInfo;
+use bundle_order::{order_paths, OrderPolicy};
+use bundle_tokens::BundleTokenCounter;
+use cache::TokenCache;
+use components::{BundleList, FileTree, Footer, Toolbar};
+use fs_utils::{BundleOptions, FileInfo, LineNumberCost, RenderedFiles, TruncationPolicy};
+use output_format::{OutputFormat, OutputTemplate};
 use settings::Settings;
-use tokenizer::TokenEstimator;
+use tokenizer::{CustomTokenizer, TokenEstimator};
 
 // Define constant for max recent workspaces
 const MAX_RECENTS: usize = 5;
@@ -33,6 +48,15 @@ struct MenuIds {
     clear_recents: muda::MenuId,
 }
 
+/// Counted files applied to the `files` signal at once.
+const COUNT_BATCH: usize = 64;
+
+/// Quiet time after a change before the bundle is recounted.
+const BUNDLE_DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// How often prices.json is checked for edits.
+const PRICES_POLL: Duration = Duration::from_secs(2);
+
 fn create_menu(settings: &Settings) -> (muda::Menu, MenuIds) {
     // Create menu items
     let open_item = muda::MenuItem::new("Open...", true, None);
@@ -178,6 +202,32 @@ fn create_menu(settings: &Settings) -> (muda::Menu, MenuIds) {
     )
 }
 
+/// Serializes settings saves, so the file always ends up holding the
+/// latest settings whatever order the writes would finish in.
+static SETTINGS_SAVE: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Applies `change` to the settings and saves them in the background.
+/// Every settings change goes through here, so a handler never saves a
+/// stale copy over the fields another one just changed.
+fn update_settings(mut settings: Signal<Settings>, change: impl FnOnce(&mut Settings)) {
+    settings.with_mut(change);
+    spawn(async move {
+        let _save = SETTINGS_SAVE.lock().await;
+        let latest = settings.peek().clone();
+        if let Err(e) = latest.save().await {
+            log::error!("Failed to save settings: {}", e);
+        }
+    });
+}
+
+/// Runs blocking work such as parsing tokenizer files, which can take
+/// seconds, on tokio's blocking pool so the window stays responsive.
+async fn load_off_ui_thread(load: impl FnOnce() + Send + 'static) {
+    if let Err(e) = tokio::task::spawn_blocking(load).await {
+        log::error!("Background load failed: {}", e);
+    }
+}
+
 #[derive(Props, Clone, PartialEq)]
 struct AppProps {
     menu_ids: MenuIds,
@@ -195,6 +245,273 @@ fn App() -> Element {
     let mut current_workspace = use_signal(|| None::<PathBuf>);
     let mut selected_files = use_signal(|| HashSet::new());
     let mut files = use_signal(|| Vec::<FileInfo>::new());
+    let mut templates = use_signal(Vec::<OutputTemplate>::new);
+    let mut prices = use_signal(models::PriceTable::new);
+    let mut manual_order = use_signal(Vec::<PathBuf>::new);
+    let mut pinned_files = use_signal(HashSet::<PathBuf>::new);
+    // Relative paths the root .gitignore keeps, listed once per workspace
+    let mut non_ignored_files = use_signal(HashSet::<String>::new);
+
+    let mut counting_task = use_signal(|| None::<Task>);
+    let bundle_counter = use_signal(|| Arc::new(Mutex::new(BundleTokenCounter::default())));
+    let rendered_files = use_signal(|| Arc::new(Mutex::new(RenderedFiles::default())));
+    // Bumped when counts are cleared so the token effect recounts them
+    let mut stale_counts = use_signal(|| 0usize);
+    let count_config = use_memo(move || {
+        let settings = settings.read();
+        let estimator = settings.get_token_estimator();
+        let tokenizer = cache::tokenizer_id(&estimator, settings.get_tokenizer_path(&estimator));
+        (estimator, tokenizer, settings.get_truncation())
+    });
+
+    // Token counts of the open workspace, kept across sessions; None while
+    // loading
+    let mut token_cache = use_signal(|| None::<Arc<Mutex<TokenCache>>>);
+    let mut cache_task = use_signal(|| None::<Task>);
+
+    // Writes new cached counts to disk in the background
+    let save_token_cache = move || {
+        let unsaved = token_cache
+            .peek()
+            .as_ref()
+            .and_then(|cache| cache.lock().unwrap().take_unsaved());
+        if let Some(unsaved) = unsaved {
+            spawn(async move {
+                if let Err(e) = unsaved.save().await {
+                    log::error!("Failed to save token cache: {}", e);
+                }
+            });
+        }
+    };
+
+    // Load the token cache of each workspace as it opens
+    use_effect(move || {
+        let workspace = current_workspace.read().clone();
+        // Keep the counts of the workspace being closed
+        save_token_cache();
+        token_cache.set(None);
+        if let Some(task) = cache_task.write().take() {
+            task.cancel();
+        }
+        let Some(root) = workspace else {
+            return;
+        };
+        let task = spawn(async move {
+            let cache = match TokenCache::new(&root).await {
+                Ok(cache) => cache,
+                Err(e) => {
+                    log::error!("Failed to load token cache: {}", e);
+                    TokenCache::empty(root)
+                }
+            };
+            token_cache.set(Some(Arc::new(Mutex::new(cache))));
+        });
+        cache_task.set(Some(task));
+    });
+
+    // Keep the counts for the next session when the app exits
+    use_drop(move || {
+        let Some(cache) = token_cache.peek().clone() else {
+            return;
+        };
+        let unsaved = cache.lock().unwrap().take_unsaved();
+        if let Some(unsaved) = unsaved {
+            if let Err(e) = unsaved.save_blocking() {
+                log::error!("Failed to save token cache: {}", e);
+            }
+        }
+    });
+
+    // Drops every token count, e.g. after the tokenizer or the cap changed;
+    // the selected files are then recounted in the background
+    let mut clear_token_counts = move || {
+        // Batches still in flight were counted with the old settings
+        if let Some(task) = counting_task.write().take() {
+            task.cancel();
+        }
+        files.with_mut(|list| {
+            for file in list.iter_mut() {
+                file.token_count = 0;
+                file.counted = false;
+                file.truncated = None;
+            }
+        });
+        stale_counts += 1;
+        bundle_counter.peek().lock().unwrap().invalidate();
+        rendered_files.peek().lock().unwrap().clear();
+    };
+
+    // Priced once per prefix width and estimator rather than per file
+    let line_number_cost = use_memo(move || {
+        let settings = settings.read();
+        settings
+            .get_line_numbers()
+            .map(|width| LineNumberCost::new(width, &settings.get_token_estimator()))
+    });
+
+    // Fit the selection into the token budget, if one is set
+    let pack_plan = use_memo(move || {
+        let settings = settings.read();
+        let estimator = settings.get_token_estimator();
+        if estimator.availability().is_err() {
+            return None;
+        }
+        settings.get_token_budget().map(|budget| {
+            budget::pack_selection(
+                &files.read(),
+                &selected_files.read(),
+                &pinned_files.read(),
+                budget,
+                &settings.truncation,
+                line_number_cost.read().as_ref(),
+                &estimator,
+            )
+        })
+    });
+
+    // The pack plan without its running total, so the bundle is recounted
+    // when a decision changes rather than whenever another count arrives
+    let bundle_packing = use_memo(move || {
+        pack_plan.read().clone().map(|plan| budget::PackPlan {
+            total_tokens: 0,
+            ..plan
+        })
+    });
+
+    // Tokens of the bundle exactly as it would be copied, headers and
+    // separators included; recounted in the background once changes settle.
+    // The file list is only peeked at: counts arriving do not change the
+    // bundle, and edited files are re-rendered by mtime.
+    let mut bundle_tokens = use_signal(|| None::<usize>);
+    let mut bundle_task = use_signal(|| None::<Task>);
+    use_effect(move || {
+        let selected = selected_files.read().clone();
+        let settings = settings.read().clone();
+        let manual = manual_order.read().clone();
+        let non_ignored = non_ignored_files.read().clone();
+        let packing = bundle_packing.read().clone();
+        let workspace_root = current_workspace.read().clone().unwrap_or_default();
+        let formatter = settings.get_output_format().formatter(&templates.read());
+
+        if let Some(task) = bundle_task.write().take() {
+            task.cancel();
+        }
+        let estimator = settings.get_token_estimator();
+        let formatter = match formatter {
+            Ok(formatter) if !selected.is_empty() && estimator.availability().is_ok() => formatter,
+            _ => {
+                bundle_tokens.set(None);
+                return;
+            }
+        };
+
+        let counter = bundle_counter.peek().clone();
+        let rendered = rendered_files.peek().clone();
+        let task = spawn(async move {
+            // Cancelled by the next change before any file is read
+            tokio::time::sleep(BUNDLE_DEBOUNCE).await;
+            let all_files = files.peek().clone();
+            let mut result = worker_pool::spawn(vec![()], move |()| {
+                let mut options = BundleOptions::new(formatter.as_ref(), &estimator);
+                if settings.get_include_tree() {
+                    options.tree = Some(components::copy_button::bundle_tree(
+                        &all_files,
+                        &selected,
+                        &workspace_root,
+                        &non_ignored,
+                    ));
+                }
+                options.line_numbers = settings.get_line_numbers();
+                options.binary_placeholders = settings.get_binary_placeholders();
+                options.truncation = settings.get_truncation();
+                options.packing = packing.clone();
+                let paths =
+                    order_paths(&selected, settings.get_order_policy(), &all_files, &manual);
+                let mut rendered = rendered.lock().unwrap();
+                let pieces = fs_utils::bundle_pieces_cached(&paths, &options, &mut rendered)
+                    .map_err(|e| e.to_string())?;
+                let tokens = counter.lock().unwrap().count(&pieces, &estimator);
+                Ok::<usize, String>(tokens)
+            });
+            match result.recv().await {
+                Some(Ok(tokens)) => bundle_tokens.set(Some(tokens)),
+                Some(Err(e)) => {
+                    log::error!("Failed to count bundle tokens: {}", e);
+                    bundle_tokens.set(None);
+                }
+                None => {}
+            }
+        });
+        bundle_task.set(Some(task));
+    });
+
+    // Restore persisted settings (estimator, output format, ...) and user
+    // output templates once on mount
+    use_hook(move || {
+        spawn(async move {
+            let settings_path = settings.peek().config_path.clone();
+            if let Some(settings_path) = settings_path {
+                match Settings::load(&settings_path).await {
+                    Ok(loaded_settings) => {
+                        let to_load = loaded_settings.clone();
+                        settings.set(loaded_settings);
+                        load_off_ui_thread(move || {
+                            for estimator in TokenEstimator::FILE_BACKED {
+                                let path = to_load.get_tokenizer_path(&estimator);
+                                if let Err(e) = tokenizer::load_tokenizer_file(&estimator, path) {
+                                    log::warn!("{} tokenizer unavailable: {}", estimator, e);
+                                }
+                            }
+                            for custom in to_load.get_custom_tokenizers() {
+                                if let Err(e) = tokenizer::load_custom_tokenizer(custom) {
+                                    log::warn!("{} tokenizer unavailable: {}", custom.name, e);
+                                }
+                            }
+                        })
+                        .await;
+                        // Counting waited for the tokenizer files
+                        clear_token_counts();
+                    }
+                    Err(e) => log::error!("Failed to load settings: {}", e),
+                }
+                let config_dir = settings_path.parent().unwrap_or(Path::new("."));
+                match output_format::load_templates(&output_format::templates_dir(config_dir)).await
+                {
+                    Ok(loaded_templates) => templates.set(loaded_templates),
+                    Err(e) => log::error!("Failed to load output templates: {}", e),
+                }
+            }
+        });
+    });
+
+    // Load prices, then pick up edits to prices.json while the App is
+    // mounted; the poll stops when the App unmounts
+    use_future(move || async move {
+        let Some(settings_path) = settings.peek().config_path.clone() else {
+            return;
+        };
+        let prices_path = models::prices_path(settings_path.parent().unwrap_or(Path::new(".")));
+        let mut seen = models::prices_modified(&prices_path).await;
+        match models::load_prices(&prices_path).await {
+            Ok(loaded_prices) => prices.set(loaded_prices),
+            Err(e) => {
+                log::error!("Failed to load prices, using defaults: {}", e);
+                prices.set(models::default_prices());
+            }
+        }
+        loop {
+            tokio::time::sleep(PRICES_POLL).await;
+            let modified = models::prices_modified(&prices_path).await;
+            if modified == seen {
+                continue;
+            }
+            seen = modified;
+            match models::load_prices(&prices_path).await {
+                Ok(loaded_prices) => prices.set(loaded_prices),
+                Err(e) => log::error!("Failed to reload prices, keeping the old ones: {}", e),
+            }
+        }
+    });
 
     // Load file list (without tokens) when workspace changes
     use_effect(move || {
@@ -202,23 +519,49 @@ fn App() -> Element {
             let mut files_signal = files.clone();
             let mut selected_files_signal = selected_files.clone();
             let workspace_path_for_handler = path.clone();
+            manual_order.set(settings.peek().get_manual_order(&path).to_vec());
+            pinned_files.set(
+                settings
+                    .peek()
+                    .get_pinned_files(&path)
+                    .cloned()
+                    .unwrap_or_default(),
+            );
 
             spawn(async move {
+                let mut binary_files = HashSet::new();
                 match fs_utils::list_files(&path).await {
-                    Ok(list) => files_signal.set(list),
+                    Ok(list) => {
+                        binary_files = list
+                            .iter()
+                            .filter(|f| f.is_binary)
+                            .map(|f| f.path.clone())
+                            .collect();
+                        files_signal.set(list);
+                    }
                     Err(e) => log::error!("Failed to list workspace files: {}", e),
                 }
 
+                // Walking the workspace and parsing .gitignore is blocking IO
                 let workspace_path_str = workspace_path_for_handler.to_string_lossy().into_owned();
-                match crate::workspace_event_handler::handle_workspace_opened(workspace_path_str) {
-                    Ok(initially_selected_relative_paths) => {
+                let opened = tokio::task::spawn_blocking(move || {
+                    crate::workspace_event_handler::handle_workspace_opened(workspace_path_str)
+                        .map_err(|e| e.to_string())
+                })
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|opened| opened);
+                match opened {
+                    Ok(opened) => {
                         let workspace_root = workspace_path_for_handler;
-                        let initial_selection_absolute: HashSet<PathBuf> =
-                            initially_selected_relative_paths
-                                .into_iter()
-                                .map(|rel_path| workspace_root.join(rel_path))
-                                .collect();
-
+                        let initial_selection_absolute: HashSet<PathBuf> = opened
+                            .selected
+                            .into_iter()
+                            .map(|rel_path| workspace_root.join(rel_path))
+                            .filter(|path| !binary_files.contains(path))
+                            .collect();
+
+                        non_ignored_files.set(opened.non_ignored);
                         selected_files_signal.set(initial_selection_absolute);
                         log::info!("Initial file selection complete based on .gitignore.");
                     }
@@ -227,6 +570,7 @@ fn App() -> Element {
                             "Failed to determine initial file selection: {}. Resetting selection.",
                             e
                         );
+                        non_ignored_files.set(HashSet::new());
                         selected_files_signal.set(HashSet::new());
                     }
                 }
@@ -234,28 +578,69 @@ fn App() -> Element {
         } else {
             files.set(Vec::new());
             selected_files.set(HashSet::new());
+            non_ignored_files.set(HashSet::new());
+            manual_order.set(Vec::new());
+            pinned_files.set(HashSet::new());
         }
     });
 
-    // Lazily compute token counts only for selected files
+    // Persist pins of the open workspace as they change in the file tree
     use_effect(move || {
-        let selected = selected_files.read().clone();
-        if selected.is_empty() {
+        let pinned = pinned_files.read().clone();
+        let Some(workspace) = current_workspace.peek().clone() else {
+            return;
+        };
+        let saved = settings.peek().get_pinned_files(&workspace).cloned();
+        if saved.unwrap_or_default() == pinned {
             return;
         }
+        update_settings(settings, |s| s.set_pinned_files(&workspace, pinned));
+    });
 
-        let mut files_signal = files.clone();
-        let estimator = settings.read().get_token_estimator();
+    // Lazily compute token counts only for selected files, on the worker
+    // pool so the window stays responsive; counts stream in as they finish.
+    // Unchanged files take their counts from the token cache.
+    use_effect(move || {
+        let selected = selected_files.read().clone();
+        let (estimator, tokenizer, truncation) = count_config();
+        let cache = token_cache.read().clone();
+        stale_counts.read();
 
-        spawn(async move {
-            let mut list = files_signal.read().clone();
-            let mut updated = false;
-            for file in &mut list {
-                if selected.contains(&file.path) && file.token_count == 0 {
-                    match estimator.estimate_file_tokens(&file.path) {
-                        Ok(tokens) => {
-                            file.token_count = tokens;
-                            updated = true;
+        // A newer selection or tokenizer supersedes counting in progress
+        if let Some(task) = counting_task.write().take() {
+            task.cancel();
+        }
+        // Counting waits for the token cache to load, and for the tokenizer:
+        // counts of a missing one would all be zero
+        let Some(cache) = cache else {
+            return;
+        };
+        if estimator.availability().is_err() {
+            return;
+        }
+        let to_count: Vec<FileInfo> = files
+            .peek()
+            .iter()
+            .filter(|f| selected.contains(&f.path) && !f.counted && !f.is_binary)
+            .cloned()
+            .collect();
+        if to_count.is_empty() {
+            return;
+        }
+
+        let mut results = worker_pool::spawn(to_count, move |mut file: FileInfo| {
+            let result =
+                file.compute_tokens_cached(&estimator, &tokenizer, truncation.as_ref(), &cache);
+            (file, result)
+        });
+        let task = spawn(async move {
+            let mut batch = Vec::with_capacity(COUNT_BATCH);
+            while results.recv_many(&mut batch, COUNT_BATCH).await > 0 {
+                let mut counted = HashMap::new();
+                for (file, result) in batch.drain(..) {
+                    match result {
+                        Ok(()) => {
+                            counted.insert(file.path.clone(), file);
                         }
                         Err(e) => log::error!(
                             "Failed to estimate tokens for {}: {}",
@@ -264,11 +649,16 @@ fn App() -> Element {
                         ),
                     }
                 }
-            }
-            if updated {
-                files_signal.set(list);
+                files.with_mut(|list| {
+                    for file in list.iter_mut() {
+                        if let Some(updated) = counted.remove(&file.path) {
+                            *file = updated;
+                        }
+                    }
+                });
             }
         });
+        counting_task.set(Some(task));
     });
 
     // Handle menu events
@@ -277,14 +667,7 @@ fn App() -> Element {
             if let Some(path) = rfd::FileDialog::new().pick_folder() {
                 println!("Opening workspace: {:?}", path);
                 current_workspace.set(Some(path.clone()));
-                spawn(async move {
-                    let mut current_settings_data = settings.read().clone();
-                    current_settings_data.add_recent_workspace(path.clone());
-                    if let Err(e) = current_settings_data.save().await {
-                        log::error!("Failed to save settings: {}", e);
-                    }
-                    settings.set(current_settings_data);
-                });
+                update_settings(settings, |s| s.add_recent_workspace(path));
             }
         } else if menu_ids.recent_items.iter().any(|id| *id == event.id) {
             let index = menu_ids
@@ -295,23 +678,9 @@ fn App() -> Element {
             let path = settings.read().get_recent_workspaces()[index].clone();
             println!("Opening recent workspace: {:?}", path);
             current_workspace.set(Some(path.clone()));
-            spawn(async move {
-                let mut current_settings_data = settings.read().clone();
-                current_settings_data.add_recent_workspace(path.clone());
-                if let Err(e) = current_settings_data.save().await {
-                    log::error!("Failed to save settings: {}", e);
-                }
-                settings.set(current_settings_data);
-            });
+            update_settings(settings, |s| s.add_recent_workspace(path));
         } else if event.id == menu_ids.clear_recents {
-            spawn(async move {
-                let mut current_settings_data = settings.read().clone();
-                current_settings_data.clear_recent_workspaces();
-                if let Err(e) = current_settings_data.save().await {
-                    log::error!("Failed to save settings: {}", e);
-                }
-                settings.set(current_settings_data);
-            });
+            update_settings(settings, |s| s.clear_recent_workspaces());
         }
     });
 
@@ -330,47 +699,154 @@ fn App() -> Element {
                     Toolbar {
                         has_files: !files.read().is_empty(),
                         on_select_all: move |_| {
-                            let all_paths: HashSet<PathBuf> = files.read().iter().map(|f| f.path.clone()).collect();
+                            let all_paths: HashSet<PathBuf> = files
+                                .read()
+                                .iter()
+                                .filter(|f| !f.is_binary)
+                                .map(|f| f.path.clone())
+                                .collect();
                             selected_files.set(all_paths);
                         },
                         on_deselect_all: move |_| {
                             selected_files.set(HashSet::new());
                         },
                         on_estimator_change: move |estimator: TokenEstimator| {
+                            update_settings(settings, |s| s.set_token_estimator(estimator));
+                            // Recount with the new estimator; its cached
+                            // counts are reused
+                            clear_token_counts();
+                        },
+                        on_output_format_change: move |format: OutputFormat| {
+                            update_settings(settings, |s| s.set_output_format(format));
+                        },
+                        current_estimator: settings.read().get_token_estimator(),
+                        tokenizer_path: {
+                            let settings = settings.read();
+                            settings.get_tokenizer_path(&settings.get_token_estimator()).map(Path::to_path_buf)
+                        },
+                        on_tokenizer_path_change: move |(estimator, path): (TokenEstimator, Option<PathBuf>)| {
                             spawn(async move {
-                                let mut current_settings_data = settings.read().clone();
-                                current_settings_data.set_token_estimator(estimator.clone());
-                                if let Err(e) = current_settings_data.save().await {
-                                    log::error!("Failed to save settings: {}", e);
-                                }
-                                settings.set(current_settings_data);
-                                // Recompute tokens with new estimator
-                                if let Some(path) = current_workspace.read().clone() {
-                                    match fs_utils::crawl(&path, &estimator, None).await {
-                                        Ok(list) => files.set(list),
-                                        Err(e) => log::error!("Failed to crawl workspace: {}", e),
+                                let to_load = estimator.clone();
+                                let to_load_path = path.clone();
+                                load_off_ui_thread(move || {
+                                    if let Err(e) = tokenizer::load_tokenizer_file(&to_load, to_load_path.as_deref()) {
+                                        log::error!("Failed to load {} tokenizer: {}", to_load, e);
                                     }
+                                })
+                                .await;
+                                update_settings(settings, |s| s.set_tokenizer_path(&estimator, path));
+                                // Counts from the previous tokenizer file are stale
+                                if estimator == settings.read().get_token_estimator() {
+                                    clear_token_counts();
                                 }
                             });
                         },
-                        current_estimator: settings.read().get_token_estimator(),
+                        custom_tokenizers: settings.read().get_custom_tokenizers().to_vec(),
+                        on_custom_tokenizer_add: move |custom: CustomTokenizer| {
+                            spawn(async move {
+                                let estimator = custom.estimator();
+                                let to_load = custom.clone();
+                                load_off_ui_thread(move || {
+                                    if let Err(e) = tokenizer::load_custom_tokenizer(&to_load) {
+                                        log::error!("Failed to load {} tokenizer: {}", to_load.name, e);
+                                    }
+                                })
+                                .await;
+                                update_settings(settings, |s| {
+                                    s.add_custom_tokenizer(custom);
+                                    s.set_token_estimator(estimator);
+                                });
+                                clear_token_counts();
+                            });
+                        },
+                        on_custom_tokenizer_remove: move |name: String| {
+                            let previous_estimator = settings.read().get_token_estimator();
+                            update_settings(settings, |s| s.remove_custom_tokenizer(&name));
+                            tokenizer::unload_custom_tokenizer(&name);
+                            if settings.read().get_token_estimator() != previous_estimator {
+                                clear_token_counts();
+                            }
+                        },
+                        current_output_format: settings.read().get_output_format(),
+                        templates: templates.read().clone(),
+                        on_include_tree_change: move |include_tree: bool| {
+                            update_settings(settings, |s| s.set_include_tree(include_tree));
+                        },
+                        include_tree: settings.read().get_include_tree(),
+                        non_ignored_files,
+                        all_files: files.read().clone(),
+                        workspace_root: current_workspace.read().clone().unwrap_or_default(),
+                        on_line_numbers_change: move |(enabled, width): (bool, usize)| {
+                            update_settings(settings, |s| s.set_line_numbers(enabled, width));
+                        },
+                        line_numbers: settings.read().line_numbers,
+                        line_number_width: settings.read().line_number_width,
+                        on_token_budget_change: move |(enabled, budget): (bool, usize)| {
+                            update_settings(settings, |s| s.set_token_budget(enabled, budget));
+                        },
+                        budget_enabled: settings.read().budget_enabled,
+                        token_budget: settings.read().token_budget,
+                        pack_plan: pack_plan.read().clone(),
+                        on_truncation_change: move |(enabled, policy): (bool, TruncationPolicy)| {
+                            update_settings(settings, |s| s.set_truncation(enabled, policy));
+                            // Re-apply the cap
+                            clear_token_counts();
+                        },
+                        truncation_enabled: settings.read().truncation_enabled,
+                        truncation: settings.read().truncation,
+                        on_binary_placeholders_change: move |enabled: bool| {
+                            update_settings(settings, |s| s.set_binary_placeholders(enabled));
+                        },
+                        binary_placeholders: settings.read().get_binary_placeholders(),
+                        on_order_policy_change: move |policy: OrderPolicy| {
+                            update_settings(settings, |s| s.set_order_policy(policy));
+                        },
+                        order_policy: settings.read().get_order_policy(),
+                        models: settings.read().get_models(),
+                        target_model: settings.read().get_target_model().name,
+                        on_target_model_change: move |name: String| {
+                            update_settings(settings, |s| s.set_target_model(name));
+                        },
+                        manual_order: manual_order.read().clone(),
                         selected_files: selected_files.clone(),
                     }
-                    // File list scrollable area
                     div {
-                        class: "flex-1 overflow-auto p-4",
-                        FileTree {
-                            all_files: files.read().clone(),
-                            selected_paths: selected_files.clone(),
-                            on_select_all: |_| {},
-                            on_deselect_all: |_| {},
-                            workspace_root: current_workspace.read().clone().expect("Workspace root must exist when FileTree is rendered")
+                        class: "flex flex-1 overflow-hidden",
+                        // File list scrollable area
+                        div {
+                            class: "flex-1 overflow-auto p-4",
+                            FileTree {
+                                all_files: files.read().clone(),
+                                selected_paths: selected_files.clone(),
+                                on_select_all: |_| {},
+                                on_deselect_all: |_| {},
+                                workspace_root: current_workspace.read().clone().expect("Workspace root must exist when FileTree is rendered"),
+                                pinned_paths: pinned_files,
+                            }
+                        }
+                        if settings.read().get_order_policy() == OrderPolicy::Manual {
+                            BundleList {
+                                paths: order_paths(&selected_files.read(), OrderPolicy::Manual, &files.read(), &manual_order.read()),
+                                workspace_root: current_workspace.read().clone().unwrap_or_default(),
+                                on_reorder: move |order: Vec<PathBuf>| {
+                                    manual_order.set(order.clone());
+                                    let Some(workspace) = current_workspace.read().clone() else {
+                                        return;
+                                    };
+                                    update_settings(settings, |s| s.set_manual_order(&workspace, order));
+                                },
+                            }
                         }
                     }
                     Footer {
                         files: files.read().clone(),
                         selected_files: selected_files.clone(),
                         current_estimator: settings.read().get_token_estimator(),
+                        target_model: settings.read().get_target_model(),
+                        price: prices.read().get(&settings.read().get_target_model().name).copied(),
+                        line_numbers: line_number_cost.read().clone(),
+                        pack_plan: pack_plan.read().clone(),
+                        bundle_tokens: *bundle_tokens.read(),
                     }
                 }
             } else {
@@ -429,3 +905,66 @@ fn main() {
         .with_context(menu_ids)
         .launch(App);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dioxus::dioxus_core::{NoOpMutations, VirtualDom};
+
+    #[derive(Props, Clone, PartialEq)]
+    struct PinThenToggleProps {
+        settings_file: PathBuf,
+        workspace: PathBuf,
+    }
+
+    /// Pins a file, then flips a toolbar setting, the way App does.
+    #[component]
+    fn PinThenToggle(props: PinThenToggleProps) -> Element {
+        let PinThenToggleProps {
+            settings_file,
+            workspace,
+        } = props;
+        let settings = use_signal(|| Settings::new(settings_file));
+        use_hook(move || {
+            let pinned = HashSet::from([workspace.join("main.rs")]);
+            update_settings(settings, |s| s.set_pinned_files(&workspace, pinned));
+            update_settings(settings, |s| s.set_include_tree(true));
+        });
+        rsx! {}
+    }
+
+    #[tokio::test]
+    async fn test_toolbar_change_keeps_pins() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let settings_file = temp_dir.path().join("settings.json");
+        let workspace = temp_dir.path().join("workspace");
+
+        let mut vdom = VirtualDom::new_with_props(
+            PinThenToggle,
+            PinThenToggleProps {
+                settings_file: settings_file.clone(),
+                workspace: workspace.clone(),
+            },
+        );
+        vdom.rebuild_in_place();
+
+        // Both saves are queued; run the dom until the last one lands
+        let mut saved = None;
+        for _ in 0..100 {
+            let _ = tokio::time::timeout(Duration::from_millis(20), vdom.wait_for_work()).await;
+            vdom.render_immediate(&mut NoOpMutations);
+            if let Ok(loaded) = Settings::load(&settings_file).await {
+                if loaded.get_include_tree() {
+                    saved = Some(loaded);
+                    break;
+                }
+            }
+        }
+
+        let saved = saved.expect("settings were never saved");
+        assert_eq!(
+            saved.get_pinned_files(&workspace),
+            Some(&HashSet::from([workspace.join("main.rs")]))
+        );
+    }
+}
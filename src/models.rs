@@ -0,0 +1,302 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// A model bundles are prepared for. Custom models are defined in the
+/// `custom_models` list of settings.json.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TargetModel {
+    pub name: String,
+    /// Tokens the model accepts, prompt and answer together.
+    pub context_window: usize,
+    /// Tokens kept free for the model's answer.
+    pub output_reserve: usize,
+}
+
+impl TargetModel {
+    /// Tokens left for the bundle once the output reserve is set aside.
+    pub fn input_budget(&self) -> usize {
+        self.context_window.saturating_sub(self.output_reserve)
+    }
+
+    /// How full the input budget is with `used` tokens.
+    pub fn budget_level(&self, used: usize) -> BudgetLevel {
+        let budget = self.input_budget();
+        if used > budget {
+            BudgetLevel::Over
+        } else if used as f64 >= budget as f64 * WARNING_RATIO {
+            BudgetLevel::Warning
+        } else {
+            BudgetLevel::Ok
+        }
+    }
+}
+
+/// Share of the input budget from which the budget bar warns.
+pub const WARNING_RATIO: f64 = 0.8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetLevel {
+    Ok,
+    /// At least `WARNING_RATIO` of the input budget is used.
+    Warning,
+    Over,
+}
+
+pub const DEFAULT_MODEL: &str = "gpt-4o";
+
+/// Built-in models: name, context window and recommended output reserve.
+const BUILT_IN: [(&str, usize, usize); 12] = [
+    ("gpt-4o", 128_000, 16_384),
+    ("gpt-4o-mini", 128_000, 16_384),
+    ("gpt-4.1", 1_047_576, 32_768),
+    ("gpt-4.1-mini", 1_047_576, 32_768),
+    ("o3", 200_000, 100_000),
+    ("o4-mini", 200_000, 100_000),
+    ("gpt-4-turbo", 128_000, 4_096),
+    ("claude-sonnet-4", 200_000, 16_384),
+    ("claude-3.5-sonnet", 200_000, 8_192),
+    ("gemini-2.5-pro", 1_048_576, 65_536),
+    ("gemini-1.5-pro", 2_097_152, 8_192),
+    ("llama-3.1-70b", 128_000, 4_096),
+];
+
+/// The built-in models, in picker order.
+pub fn built_in_models() -> Vec<TargetModel> {
+    BUILT_IN
+        .iter()
+        .map(|&(name, context_window, output_reserve)| TargetModel {
+            name: name.to_string(),
+            context_window,
+            output_reserve,
+        })
+        .collect()
+}
+
+/// Built-in models followed by `custom` ones; a custom model replaces the
+/// built-in of the same name.
+pub fn all_models(custom: &[TargetModel]) -> Vec<TargetModel> {
+    let mut models: Vec<TargetModel> = built_in_models()
+        .into_iter()
+        .filter(|model| !custom.iter().any(|c| c.name == model.name))
+        .collect();
+    models.extend(custom.iter().cloned());
+    models
+}
+
+pub fn find_model(name: &str, custom: &[TargetModel]) -> Option<TargetModel> {
+    custom
+        .iter()
+        .find(|model| model.name == name)
+        .cloned()
+        .or_else(|| {
+            built_in_models()
+                .into_iter()
+                .find(|model| model.name == name)
+        })
+}
+
+/// USD per million input tokens for one model.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ModelPrice {
+    pub input: f64,
+    /// Price of input served from the provider's prompt cache, if offered.
+    #[serde(default)]
+    pub cached_input: Option<f64>,
+}
+
+impl ModelPrice {
+    pub fn input_cost(&self, tokens: usize) -> f64 {
+        tokens as f64 * self.input / 1_000_000.0
+    }
+
+    pub fn cached_input_cost(&self, tokens: usize) -> Option<f64> {
+        self.cached_input
+            .map(|price| tokens as f64 * price / 1_000_000.0)
+    }
+}
+
+/// Prices by model name, as stored in `prices.json`.
+pub type PriceTable = BTreeMap<String, ModelPrice>;
+
+/// List prices written to a new `prices.json`: name, input and cached input.
+/// Users keep the file current; the app never overwrites it.
+const DEFAULT_PRICES: [(&str, f64, Option<f64>); 11] = [
+    ("gpt-4o", 2.50, Some(1.25)),
+    ("gpt-4o-mini", 0.15, Some(0.075)),
+    ("gpt-4.1", 2.00, Some(0.50)),
+    ("gpt-4.1-mini", 0.40, Some(0.10)),
+    ("o3", 2.00, Some(0.50)),
+    ("o4-mini", 1.10, Some(0.275)),
+    ("gpt-4-turbo", 10.00, None),
+    ("claude-sonnet-4", 3.00, Some(0.30)),
+    ("claude-3.5-sonnet", 3.00, Some(0.30)),
+    ("gemini-2.5-pro", 1.25, Some(0.31)),
+    ("gemini-1.5-pro", 1.25, Some(0.3125)),
+];
+
+pub fn default_prices() -> PriceTable {
+    DEFAULT_PRICES
+        .iter()
+        .map(|&(name, input, cached_input)| {
+            let price = ModelPrice {
+                input,
+                cached_input,
+            };
+            (name.to_string(), price)
+        })
+        .collect()
+}
+
+/// The price table, next to `settings.json`.
+pub fn prices_path(config_dir: &Path) -> PathBuf {
+    config_dir.join("prices.json")
+}
+
+/// Loads the price table at `path`. A missing file is created with the
+/// default prices so there is something to edit.
+pub async fn load_prices(path: &Path) -> io::Result<PriceTable> {
+    match tokio::fs::read_to_string(path).await {
+        Ok(json) => {
+            serde_json::from_str(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let prices = default_prices();
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            let json = serde_json::to_string_pretty(&prices)?;
+            tokio::fs::write(path, json).await?;
+            Ok(prices)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// When the price table at `path` was last written, if it exists.
+pub async fn prices_modified(path: &Path) -> Option<SystemTime> {
+    tokio::fs::metadata(path)
+        .await
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+/// Formats a dollar amount with two decimals, or enough to show a cost
+/// below one cent, e.g. "$1.25" or "$0.0042".
+pub fn format_usd(amount: f64) -> String {
+    if amount == 0.0 || amount >= 0.01 {
+        format!("${:.2}", amount)
+    } else {
+        format!("${:.4}", amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model(name: &str, context_window: usize, output_reserve: usize) -> TargetModel {
+        TargetModel {
+            name: name.to_string(),
+            context_window,
+            output_reserve,
+        }
+    }
+
+    #[test]
+    fn test_built_in_models() {
+        let models = built_in_models();
+        assert!(models.iter().any(|m| m.name == DEFAULT_MODEL));
+        for m in &models {
+            assert!(m.output_reserve < m.context_window, "{}", m.name);
+        }
+        let gpt4o = find_model("gpt-4o", &[]).unwrap();
+        assert_eq!(gpt4o.input_budget(), 128_000 - 16_384);
+        assert!(find_model("gpt-5-unreleased", &[]).is_none());
+    }
+
+    #[test]
+    fn test_custom_models_override_built_ins() {
+        let custom = vec![
+            model("gpt-4o", 64_000, 4_000),
+            model("in-house", 32_000, 2_000),
+        ];
+        assert_eq!(
+            find_model("gpt-4o", &custom).unwrap().context_window,
+            64_000
+        );
+        assert_eq!(
+            find_model("in-house", &custom).unwrap().input_budget(),
+            30_000
+        );
+
+        let models = all_models(&custom);
+        assert_eq!(models.len(), BUILT_IN.len() + 1);
+        assert_eq!(models.iter().filter(|m| m.name == "gpt-4o").count(), 1);
+        assert_eq!(models.last().unwrap().name, "in-house");
+    }
+
+    #[test]
+    fn test_budget_level() {
+        let m = model("small", 12_000, 2_000);
+        assert_eq!(m.budget_level(0), BudgetLevel::Ok);
+        assert_eq!(m.budget_level(7_999), BudgetLevel::Ok);
+        assert_eq!(m.budget_level(8_000), BudgetLevel::Warning);
+        assert_eq!(m.budget_level(10_000), BudgetLevel::Warning);
+        assert_eq!(m.budget_level(10_001), BudgetLevel::Over);
+        // A reserve larger than the window leaves no room at all
+        assert_eq!(
+            model("odd", 1_000, 2_000).budget_level(1),
+            BudgetLevel::Over
+        );
+    }
+
+    #[test]
+    fn test_costs() {
+        let price = ModelPrice {
+            input: 2.50,
+            cached_input: Some(1.25),
+        };
+        assert_eq!(price.input_cost(400_000), 1.0);
+        assert_eq!(price.cached_input_cost(400_000), Some(0.5));
+        let uncached = ModelPrice {
+            input: 10.0,
+            cached_input: None,
+        };
+        assert_eq!(uncached.cached_input_cost(1_000), None);
+
+        assert_eq!(format_usd(1.0), "$1.00");
+        assert_eq!(format_usd(0.0125), "$0.01");
+        assert_eq!(format_usd(0.00042), "$0.0004");
+        assert_eq!(format_usd(0.0), "$0.00");
+    }
+
+    #[tokio::test]
+    async fn test_load_prices() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = prices_path(&temp_dir.path().join("context-loader"));
+
+        // Created with the defaults on first load
+        assert_eq!(prices_modified(&path).await, None);
+        let prices = load_prices(&path).await.unwrap();
+        assert_eq!(prices, default_prices());
+        assert!(prices_modified(&path).await.is_some());
+
+        tokio::fs::write(
+            &path,
+            r#"{"in-house": {"input": 0.5}, "gpt-4o": {"input": 2.0, "cached_input": 1.0}}"#,
+        )
+        .await
+        .unwrap();
+        let prices = load_prices(&path).await.unwrap();
+        assert_eq!(prices.len(), 2);
+        assert_eq!(prices["in-house"].cached_input, None);
+        assert_eq!(prices["gpt-4o"].input, 2.0);
+
+        tokio::fs::write(&path, "not json").await.unwrap();
+        let err = load_prices(&path).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,656 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// One file as handed to an `OutputFormatter`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileEntry<'a> {
+    /// Path shown to the model, already relative to the bundle root (`./src/main.rs`).
+    pub path: &'a str,
+    /// Code fence language inferred from the extension, empty when unknown.
+    pub lang: &'a str,
+    /// Token count of `content`; only computed when `uses_tokens` is true.
+    pub tokens: usize,
+    pub content: &'a str,
+}
+
+/// Bundle-level values available to `header` and `footer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BundleSummary {
+    pub file_count: usize,
+    pub total_tokens: usize,
+}
+
+/// Renders the pieces `concat_files` stitches together:
+/// `header`, the optional directory `tree`, then each `file` joined by
+/// `separator`, then `footer`.
+pub trait OutputFormatter: Send + Sync {
+    fn header(&self, _summary: &BundleSummary) -> String {
+        String::new()
+    }
+
+    /// Wraps the ASCII directory tree (which ends with a newline).
+    fn tree(&self, tree: &str) -> String {
+        format!("{}\n", tree)
+    }
+
+    fn file(&self, entry: &FileEntry) -> String;
+
+    fn separator(&self) -> String;
+
+    fn footer(&self, _summary: &BundleSummary) -> String {
+        String::new()
+    }
+
+    /// Whether the formatter renders token counts. Lets `concat_files` skip
+    /// tokenizing the bundle when nobody reads the result.
+    fn uses_tokens(&self) -> bool {
+        false
+    }
+}
+
+/// `@@@ ./path @@@` header followed by the raw content.
+pub struct HeadersFormatter;
+
+impl OutputFormatter for HeadersFormatter {
+    fn file(&self, entry: &FileEntry) -> String {
+        format!("@@@ {} @@@\n\n{}", entry.path, entry.content)
+    }
+
+    fn separator(&self) -> String {
+        "\n\n".to_string()
+    }
+}
+
+/// `<file path="./path">…</file>` blocks, preferred by Claude-style prompts.
+pub struct XmlFormatter;
+
+impl OutputFormatter for XmlFormatter {
+    fn tree(&self, tree: &str) -> String {
+        format!("<directory_tree>\n{}</directory_tree>\n", tree)
+    }
+
+    fn file(&self, entry: &FileEntry) -> String {
+        let mut block = format!("<file path=\"{}\">\n", escape_xml_attr(entry.path));
+        block.push_str(&escape_closing_file_tag(entry.content));
+        if !entry.content.is_empty() && !entry.content.ends_with('\n') {
+            block.push('\n');
+        }
+        block.push_str("</file>");
+        block
+    }
+
+    fn separator(&self) -> String {
+        "\n".to_string()
+    }
+}
+
+/// `### ./path` heading and a fenced code block tagged with the file's language.
+pub struct MarkdownFormatter;
+
+impl OutputFormatter for MarkdownFormatter {
+    fn tree(&self, tree: &str) -> String {
+        let fence = "`".repeat(fence_length(tree));
+        format!("### Directory tree\n\n{}text\n{}{}\n\n", fence, tree, fence)
+    }
+
+    fn file(&self, entry: &FileEntry) -> String {
+        let fence = "`".repeat(fence_length(entry.content));
+        let mut block = format!("### {}\n\n{}{}\n", entry.path, fence, entry.lang);
+        block.push_str(entry.content);
+        if !entry.content.is_empty() && !entry.content.ends_with('\n') {
+            block.push('\n');
+        }
+        block.push_str(&fence);
+        block
+    }
+
+    fn separator(&self) -> String {
+        "\n\n".to_string()
+    }
+}
+
+/// A user-defined wrapper loaded from `<config dir>/templates/*.json`:
+///
+/// ```json
+/// {
+///   "name": "Team docs",
+///   "header": "Project files ({count} files, {tokens} tokens)\n\n",
+///   "tree": "Layout:\n{tree}\n",
+///   "file": "== {path} ({lang}, {tokens} tokens) ==\n{content}",
+///   "separator": "\n\n",
+///   "footer": "\n\nEnd of files."
+/// }
+/// ```
+///
+/// `file` understands `{path}`, `{lang}`, `{tokens}` and `{content}`; `header`
+/// and `footer` understand `{count}` and `{tokens}` (bundle total); `tree`
+/// understands `{tree}`. `{{` and `}}` produce literal braces and unknown
+/// placeholders are kept verbatim.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutputTemplate {
+    pub name: String,
+    #[serde(default)]
+    pub header: String,
+    #[serde(default = "default_template_tree")]
+    pub tree: String,
+    pub file: String,
+    #[serde(default = "default_template_separator")]
+    pub separator: String,
+    #[serde(default)]
+    pub footer: String,
+}
+
+fn default_template_tree() -> String {
+    "{tree}\n".to_string()
+}
+
+fn default_template_separator() -> String {
+    "\n\n".to_string()
+}
+
+impl OutputFormatter for OutputTemplate {
+    fn header(&self, summary: &BundleSummary) -> String {
+        render_summary(&self.header, summary)
+    }
+
+    fn tree(&self, tree: &str) -> String {
+        render_placeholders(&self.tree, |name| {
+            (name == "tree").then(|| tree.to_string())
+        })
+    }
+
+    fn file(&self, entry: &FileEntry) -> String {
+        render_placeholders(&self.file, |name| match name {
+            "path" => Some(entry.path.to_string()),
+            "lang" => Some(entry.lang.to_string()),
+            "tokens" => Some(entry.tokens.to_string()),
+            "content" => Some(entry.content.to_string()),
+            _ => None,
+        })
+    }
+
+    fn separator(&self) -> String {
+        self.separator.clone()
+    }
+
+    fn footer(&self, summary: &BundleSummary) -> String {
+        render_summary(&self.footer, summary)
+    }
+
+    fn uses_tokens(&self) -> bool {
+        [&self.header, &self.file, &self.footer]
+            .iter()
+            .any(|section| section.contains("{tokens}"))
+    }
+}
+
+fn render_summary(section: &str, summary: &BundleSummary) -> String {
+    render_placeholders(section, |name| match name {
+        "count" => Some(summary.file_count.to_string()),
+        "tokens" => Some(summary.total_tokens.to_string()),
+        _ => None,
+    })
+}
+
+/// Single-pass `{name}` substitution, so placeholders appearing inside file
+/// content are never expanded a second time.
+fn render_placeholders(template: &str, lookup: impl Fn(&str) -> Option<String>) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(idx) = rest.find(['{', '}']) {
+        rendered.push_str(&rest[..idx]);
+        rest = &rest[idx..];
+        if rest.starts_with("{{") || rest.starts_with("}}") {
+            rendered.push_str(&rest[..1]);
+            rest = &rest[2..];
+            continue;
+        }
+        if rest.starts_with('{') {
+            if let Some(end) = rest.find('}') {
+                if let Some(value) = lookup(&rest[1..end]) {
+                    rendered.push_str(&value);
+                    rest = &rest[end + 1..];
+                    continue;
+                }
+            }
+        }
+        rendered.push_str(&rest[..1]);
+        rest = &rest[1..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// Directory holding user templates, next to `settings.json`.
+pub fn templates_dir(config_dir: &Path) -> PathBuf {
+    config_dir.join("templates")
+}
+
+/// Loads every `*.json` template in `dir`, sorted by name. A missing directory
+/// yields no templates; unreadable or invalid files are logged and skipped.
+pub async fn load_templates(dir: &Path) -> io::Result<Vec<OutputTemplate>> {
+    let mut templates = Vec::new();
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(templates),
+        Err(e) => return Err(e),
+    };
+
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        let parsed = tokio::fs::read_to_string(&path)
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|json| {
+                serde_json::from_str::<OutputTemplate>(&json).map_err(|e| e.to_string())
+            });
+        match parsed {
+            Ok(template) => templates.push(template),
+            Err(e) => log::error!("Skipping output template {}: {}", path.display(), e),
+        }
+    }
+
+    templates.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(templates)
+}
+
+/// The output format picked in the Toolbar and persisted in `Settings`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OutputFormat {
+    #[default]
+    Headers,
+    Xml,
+    Markdown,
+    /// A user template, referenced by its `name`.
+    Custom(String),
+}
+
+const CUSTOM_PREFIX: &str = "Custom:";
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Headers => write!(f, "Headers"),
+            Self::Xml => write!(f, "Xml"),
+            Self::Markdown => write!(f, "Markdown"),
+            Self::Custom(name) => write!(f, "{}{}", CUSTOM_PREFIX, name),
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Headers" => Ok(Self::Headers),
+            "Xml" => Ok(Self::Xml),
+            "Markdown" => Ok(Self::Markdown),
+            _ => match s.strip_prefix(CUSTOM_PREFIX) {
+                Some(name) if !name.is_empty() => Ok(Self::Custom(name.to_string())),
+                _ => Err(format!("Unknown output format: {}", s)),
+            },
+        }
+    }
+}
+
+impl OutputFormat {
+    /// The formats that ship with the app, in Toolbar order.
+    pub const BUILT_IN: [OutputFormat; 3] = [Self::Headers, Self::Xml, Self::Markdown];
+
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Headers => "@@@ headers",
+            Self::Xml => "XML tags",
+            Self::Markdown => "Markdown",
+            Self::Custom(name) => name,
+        }
+    }
+
+    /// Resolves the formatter for this format. Custom formats are looked up in
+    /// `templates` by name.
+    pub fn formatter(
+        &self,
+        templates: &[OutputTemplate],
+    ) -> Result<Box<dyn OutputFormatter>, String> {
+        match self {
+            Self::Headers => Ok(Box::new(HeadersFormatter)),
+            Self::Xml => Ok(Box::new(XmlFormatter)),
+            Self::Markdown => Ok(Box::new(MarkdownFormatter)),
+            Self::Custom(name) => templates
+                .iter()
+                .find(|template| &template.name == name)
+                .map(|template| Box::new(template.clone()) as Box<dyn OutputFormatter>)
+                .ok_or_else(|| format!("Output template '{}' not found", name)),
+        }
+    }
+}
+
+/// Extensions mapped to Markdown code fence languages. Mirrors the file types we
+/// ship icons for in `assets/file_icons`.
+const LANGUAGES: &[(&str, &str)] = &[
+    ("astro", "astro"),
+    ("c", "c"),
+    ("h", "c"),
+    ("cc", "cpp"),
+    ("cpp", "cpp"),
+    ("cxx", "cpp"),
+    ("hh", "cpp"),
+    ("hpp", "cpp"),
+    ("coffee", "coffeescript"),
+    ("css", "css"),
+    ("dart", "dart"),
+    ("sql", "sql"),
+    ("diff", "diff"),
+    ("patch", "diff"),
+    ("dockerfile", "dockerfile"),
+    ("ex", "elixir"),
+    ("exs", "elixir"),
+    ("heex", "heex"),
+    ("elm", "elm"),
+    ("erl", "erlang"),
+    ("hrl", "erlang"),
+    ("fs", "fsharp"),
+    ("fsi", "fsharp"),
+    ("fsx", "fsharp"),
+    ("gleam", "gleam"),
+    ("go", "go"),
+    ("graphql", "graphql"),
+    ("gql", "graphql"),
+    ("hs", "haskell"),
+    ("hcl", "hcl"),
+    ("htm", "html"),
+    ("html", "html"),
+    ("java", "java"),
+    ("cjs", "javascript"),
+    ("js", "javascript"),
+    ("mjs", "javascript"),
+    ("jsx", "jsx"),
+    ("jl", "julia"),
+    ("kt", "kotlin"),
+    ("kts", "kotlin"),
+    ("lua", "lua"),
+    ("luau", "luau"),
+    ("md", "markdown"),
+    ("metal", "metal"),
+    ("nim", "nim"),
+    ("nix", "nix"),
+    ("ipynb", "json"),
+    ("json", "json"),
+    ("ml", "ocaml"),
+    ("mli", "ocaml"),
+    ("php", "php"),
+    ("prisma", "prisma"),
+    ("py", "python"),
+    ("r", "r"),
+    ("rb", "ruby"),
+    ("roc", "roc"),
+    ("rs", "rust"),
+    ("sass", "sass"),
+    ("scss", "scss"),
+    ("scala", "scala"),
+    ("swift", "swift"),
+    ("tcl", "tcl"),
+    ("bash", "bash"),
+    ("fish", "fish"),
+    ("sh", "bash"),
+    ("zsh", "zsh"),
+    ("tf", "terraform"),
+    ("tfvars", "terraform"),
+    ("toml", "toml"),
+    ("cts", "typescript"),
+    ("mts", "typescript"),
+    ("ts", "typescript"),
+    ("tsx", "tsx"),
+    ("v", "v"),
+    ("vue", "vue"),
+    ("vy", "vyper"),
+    ("wgsl", "wgsl"),
+    ("yaml", "yaml"),
+    ("yml", "yaml"),
+    ("zig", "zig"),
+];
+
+/// Infers a code fence language from the file extension (or the file name for
+/// extension-less files such as `Dockerfile`).
+pub fn language_for_path(path: &Path) -> Option<&'static str> {
+    let key = path
+        .extension()
+        .or_else(|| path.file_name())?
+        .to_string_lossy()
+        .to_ascii_lowercase();
+    LANGUAGES
+        .iter()
+        .find(|(ext, _)| *ext == key)
+        .map(|(_, lang)| *lang)
+}
+
+/// A fence must be longer than any backtick run inside the content, otherwise
+/// an embedded "```" would close the block early.
+fn fence_length(content: &str) -> usize {
+    let mut longest = 0;
+    let mut current = 0;
+    for c in content.chars() {
+        if c == '`' {
+            current += 1;
+            longest = longest.max(current);
+        } else {
+            current = 0;
+        }
+    }
+    (longest + 1).max(3)
+}
+
+fn escape_xml_attr(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Content is kept verbatim except for closing `</file` sequences, which would
+/// otherwise end the block early. Matching is case-insensitive like most XML-ish
+/// parsers used by LLM tooling.
+fn escape_closing_file_tag(content: &str) -> String {
+    const NEEDLE: &str = "</file";
+    let lower = content.to_ascii_lowercase();
+    let mut escaped = String::with_capacity(content.len());
+    let mut last = 0;
+    for (idx, _) in lower.match_indices(NEEDLE) {
+        escaped.push_str(&content[last..idx]);
+        escaped.push_str("&lt;");
+        last = idx + 1; // skip the '<'
+    }
+    escaped.push_str(&content[last..]);
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn entry<'a>(path: &'a str, content: &'a str) -> FileEntry<'a> {
+        FileEntry {
+            path,
+            lang: language_for_path(Path::new(path)).unwrap_or(""),
+            tokens: 0,
+            content,
+        }
+    }
+
+    #[test]
+    fn test_output_format_round_trip() {
+        let mut formats = OutputFormat::BUILT_IN.to_vec();
+        formats.push(OutputFormat::Custom("Team docs".to_string()));
+        for format in formats {
+            assert_eq!(format.to_string().parse::<OutputFormat>().unwrap(), format);
+        }
+        assert!("Unknown".parse::<OutputFormat>().is_err());
+        assert!("Custom:".parse::<OutputFormat>().is_err());
+    }
+
+    #[test]
+    fn test_xml_format_file() {
+        let block = XmlFormatter.file(&entry("./src/main.rs", "fn main() {}"));
+        assert_eq!(
+            block,
+            "<file path=\"./src/main.rs\">\nfn main() {}\n</file>"
+        );
+    }
+
+    #[test]
+    fn test_xml_escapes_closing_tags_and_attributes() {
+        let block = XmlFormatter.file(&entry("./a \"b\" & c.xml", "x</file>y</FILE >\n"));
+        assert_eq!(
+            block,
+            "<file path=\"./a &quot;b&quot; &amp; c.xml\">\nx&lt;/file>y&lt;/FILE >\n</file>"
+        );
+    }
+
+    #[test]
+    fn test_markdown_format_file() {
+        let block = MarkdownFormatter.file(&entry("./src/main.rs", "fn main() {}\n"));
+        assert_eq!(block, "### ./src/main.rs\n\n```rust\nfn main() {}\n```");
+
+        let block = MarkdownFormatter.file(&entry("./notes.unknown", "plain"));
+        assert_eq!(block, "### ./notes.unknown\n\n```\nplain\n```");
+    }
+
+    #[test]
+    fn test_markdown_fence_adapts_to_content() {
+        let content = "Example:\n```rust\nlet x = 1;\n```\n";
+        let block = MarkdownFormatter.file(&entry("./README.md", content));
+        assert_eq!(
+            block,
+            "### ./README.md\n\n````markdown\nExample:\n```rust\nlet x = 1;\n```\n````"
+        );
+        assert_eq!(fence_length("no ticks"), 3);
+        assert_eq!(fence_length("`````"), 6);
+    }
+
+    #[test]
+    fn test_tree_wrapping() {
+        let tree = "ws/\n└── a.rs\n";
+        assert_eq!(HeadersFormatter.tree(tree), "ws/\n└── a.rs\n\n");
+        assert_eq!(
+            XmlFormatter.tree(tree),
+            "<directory_tree>\nws/\n└── a.rs\n</directory_tree>\n"
+        );
+        assert_eq!(
+            MarkdownFormatter.tree(tree),
+            "### Directory tree\n\n```text\nws/\n└── a.rs\n```\n\n"
+        );
+    }
+
+    #[test]
+    fn test_language_for_path() {
+        assert_eq!(language_for_path(Path::new("src/lib.rs")), Some("rust"));
+        assert_eq!(language_for_path(Path::new("App.TSX")), Some("tsx"));
+        assert_eq!(
+            language_for_path(Path::new("Dockerfile")),
+            Some("dockerfile")
+        );
+        assert_eq!(language_for_path(Path::new("LICENSE")), None);
+    }
+
+    #[test]
+    fn test_template_placeholders() {
+        let template = OutputTemplate {
+            name: "t".to_string(),
+            header: "{count} files / {tokens} tokens\n".to_string(),
+            tree: "<<{tree}>>".to_string(),
+            file: "[{path}|{lang}|{tokens}] {{literal}} {unknown}\n{content}".to_string(),
+            separator: "\n--\n".to_string(),
+            footer: "\nend".to_string(),
+        };
+        assert!(template.uses_tokens());
+
+        let mut file = entry("./src/lib.rs", "let s = \"{path}\";");
+        file.tokens = 7;
+        assert_eq!(
+            template.file(&file),
+            "[./src/lib.rs|rust|7] {literal} {unknown}\nlet s = \"{path}\";"
+        );
+
+        let summary = BundleSummary {
+            file_count: 2,
+            total_tokens: 12,
+        };
+        assert_eq!(template.header(&summary), "2 files / 12 tokens\n");
+        assert_eq!(template.tree("ws/\n"), "<<ws/\n>>");
+        assert_eq!(template.footer(&summary), "\nend");
+        assert_eq!(template.separator(), "\n--\n");
+    }
+
+    #[test]
+    fn test_formatter_resolves_custom_templates() {
+        let template = OutputTemplate {
+            name: "Team docs".to_string(),
+            header: String::new(),
+            tree: default_template_tree(),
+            file: "{path}".to_string(),
+            separator: default_template_separator(),
+            footer: String::new(),
+        };
+        assert!(!template.uses_tokens());
+
+        let templates = vec![template];
+        let formatter = OutputFormat::Custom("Team docs".to_string())
+            .formatter(&templates)
+            .unwrap();
+        assert_eq!(formatter.file(&entry("./a.txt", "")), "./a.txt");
+
+        assert!(OutputFormat::Custom("Missing".to_string())
+            .formatter(&templates)
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_load_templates() {
+        let dir = tempdir().unwrap();
+        assert!(load_templates(&dir.path().join("missing"))
+            .await
+            .unwrap()
+            .is_empty());
+
+        tokio::fs::write(
+            dir.path().join("b.json"),
+            r#"{"name":"Beta","file":"{path}\n{content}"}"#,
+        )
+        .await
+        .unwrap();
+        tokio::fs::write(
+            dir.path().join("a.json"),
+            r#"{"name":"Alpha","header":"h","file":"{content}","separator":"","footer":"f"}"#,
+        )
+        .await
+        .unwrap();
+        tokio::fs::write(dir.path().join("broken.json"), "{not json")
+            .await
+            .unwrap();
+        tokio::fs::write(dir.path().join("notes.txt"), "ignored")
+            .await
+            .unwrap();
+
+        let templates = load_templates(dir.path()).await.unwrap();
+        let names: Vec<_> = templates.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, vec!["Alpha", "Beta"]);
+        assert_eq!(templates[1].separator, "\n\n");
+        assert_eq!(templates[1].header, "");
+        assert_eq!(templates[1].tree, "{tree}\n");
+    }
+}
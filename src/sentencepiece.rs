@@ -0,0 +1,501 @@
+//! Loads SentencePiece `.model` files (as shipped with Gemini-family models)
+//! into a `tokenizers::Tokenizer`. The file is a `ModelProto` protobuf; only
+//! the fields that affect tokenization are read.
+
+use std::collections::HashMap;
+use std::path::Path;
+use tokenizers::models::bpe::BPE;
+use tokenizers::models::unigram::Unigram;
+use tokenizers::normalizers::replace::{Replace, ReplacePattern};
+use tokenizers::normalizers::{NormalizerWrapper, Precompiled, Sequence};
+use tokenizers::pre_tokenizers::metaspace::{Metaspace, PrependScheme};
+use tokenizers::{AddedToken, Tokenizer};
+
+/// Replaces spaces in SentencePiece pieces.
+const SPACE_SYMBOL: char = '\u{2581}';
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PieceType {
+    Normal,
+    Unknown,
+    Control,
+    UserDefined,
+    Unused,
+    Byte,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Piece {
+    piece: String,
+    score: f32,
+    kind: PieceType,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ModelType {
+    Unigram,
+    Bpe,
+}
+
+/// The parts of a `ModelProto` used for tokenization, with the proto defaults.
+#[derive(Debug, Clone, PartialEq)]
+struct Model {
+    pieces: Vec<Piece>,
+    model_type: ModelType,
+    byte_fallback: bool,
+    precompiled_charsmap: Vec<u8>,
+    add_dummy_prefix: bool,
+    remove_extra_whitespaces: bool,
+}
+
+/// Reads a SentencePiece `.model` file. Unigram and BPE models are supported.
+pub fn load_model(path: &Path) -> Result<Tokenizer, String> {
+    let bytes = std::fs::read(path).map_err(|e| e.to_string())?;
+    build_tokenizer(parse_model(&bytes)?)
+}
+
+fn build_tokenizer(model: Model) -> Result<Tokenizer, String> {
+    // Control and unused pieces never match text. Only counts matter, so
+    // leaving them out (and renumbering the rest) is harmless.
+    let pieces: Vec<&Piece> = model
+        .pieces
+        .iter()
+        .filter(|p| !matches!(p.kind, PieceType::Control | PieceType::Unused))
+        .collect();
+    let unk = pieces
+        .iter()
+        .position(|p| p.kind == PieceType::Unknown)
+        .ok_or("model has no unknown piece")?;
+
+    let mut tokenizer = match model.model_type {
+        ModelType::Unigram => {
+            let vocab: Vec<(String, f64)> = pieces
+                .iter()
+                .map(|p| (p.piece.clone(), p.score as f64))
+                .collect();
+            let unigram =
+                Unigram::from(vocab, Some(unk), model.byte_fallback).map_err(|e| e.to_string())?;
+            Tokenizer::new(unigram)
+        }
+        ModelType::Bpe => {
+            let vocab: HashMap<String, u32> = pieces
+                .iter()
+                .enumerate()
+                .map(|(id, p)| (p.piece.clone(), id as u32))
+                .collect();
+            let bpe = BPE::builder()
+                .vocab_and_merges(vocab, bpe_merges(&pieces))
+                .unk_token(pieces[unk].piece.clone())
+                .byte_fallback(model.byte_fallback)
+                .fuse_unk(true)
+                .build()
+                .map_err(|e| e.to_string())?;
+            Tokenizer::new(bpe)
+        }
+    };
+
+    let mut normalizers: Vec<NormalizerWrapper> = Vec::new();
+    if !model.precompiled_charsmap.is_empty() {
+        let precompiled =
+            Precompiled::from(&model.precompiled_charsmap).map_err(|e| e.to_string())?;
+        normalizers.push(precompiled.into());
+    }
+    if model.remove_extra_whitespaces {
+        let trim = Replace::new(ReplacePattern::Regex(r"\A +| +\z".into()), "");
+        let collapse = Replace::new(ReplacePattern::Regex(" {2,}".into()), " ");
+        normalizers.push(trim.map_err(|e| e.to_string())?.into());
+        normalizers.push(collapse.map_err(|e| e.to_string())?.into());
+    }
+    tokenizer.with_normalizer(Sequence::new(normalizers));
+
+    let prepend_scheme = if model.add_dummy_prefix {
+        PrependScheme::Always
+    } else {
+        PrependScheme::Never
+    };
+    tokenizer.with_pre_tokenizer(Metaspace::new(SPACE_SYMBOL, prepend_scheme, false));
+
+    // User-defined pieces always match as a whole, before the model runs
+    let user_defined: Vec<AddedToken> = model
+        .pieces
+        .iter()
+        .filter(|p| p.kind == PieceType::UserDefined)
+        .map(|p| AddedToken::from(p.piece.clone(), false))
+        .collect();
+    tokenizer.add_tokens(&user_defined);
+
+    Ok(tokenizer)
+}
+
+/// SentencePiece BPE merges the adjacent pair whose union scores highest.
+/// Expressed as ranked merges: every split of a piece into two known pieces,
+/// best-scoring union first.
+fn bpe_merges(pieces: &[&Piece]) -> Vec<(String, String)> {
+    let ids: HashMap<&str, usize> = pieces
+        .iter()
+        .enumerate()
+        .filter(|(_, p)| p.kind == PieceType::Normal || p.kind == PieceType::UserDefined)
+        .map(|(id, p)| (p.piece.as_str(), id))
+        .collect();
+
+    let mut merges: Vec<(usize, usize, usize)> = Vec::new();
+    for (id, piece) in pieces.iter().enumerate() {
+        if piece.kind != PieceType::Normal {
+            continue;
+        }
+        for (split, _) in piece.piece.char_indices().skip(1) {
+            let (left, right) = piece.piece.split_at(split);
+            if let (Some(&left), Some(&right)) = (ids.get(left), ids.get(right)) {
+                merges.push((id, left, right));
+            }
+        }
+    }
+    merges.sort_by(|a, b| {
+        pieces[b.0]
+            .score
+            .total_cmp(&pieces[a.0].score)
+            .then_with(|| a.cmp(b))
+    });
+    merges
+        .into_iter()
+        .map(|(_, left, right)| (pieces[left].piece.clone(), pieces[right].piece.clone()))
+        .collect()
+}
+
+fn parse_model(bytes: &[u8]) -> Result<Model, String> {
+    let mut model = Model {
+        pieces: Vec::new(),
+        model_type: ModelType::Unigram,
+        byte_fallback: false,
+        precompiled_charsmap: Vec::new(),
+        add_dummy_prefix: true,
+        remove_extra_whitespaces: true,
+    };
+
+    for field in ProtoReader::new(bytes) {
+        match field? {
+            (1, Value::Bytes(piece)) => model.pieces.push(parse_piece(piece)?),
+            (2, Value::Bytes(trainer_spec)) => {
+                for field in ProtoReader::new(trainer_spec) {
+                    match field? {
+                        (3, Value::Varint(1)) => model.model_type = ModelType::Unigram,
+                        (3, Value::Varint(2)) => model.model_type = ModelType::Bpe,
+                        (3, Value::Varint(other)) => {
+                            return Err(format!("unsupported model type {}", other))
+                        }
+                        (35, Value::Varint(flag)) => model.byte_fallback = flag != 0,
+                        _ => {}
+                    }
+                }
+            }
+            (3, Value::Bytes(normalizer_spec)) => {
+                for field in ProtoReader::new(normalizer_spec) {
+                    match field? {
+                        (2, Value::Bytes(charsmap)) => {
+                            model.precompiled_charsmap = charsmap.to_vec()
+                        }
+                        (3, Value::Varint(flag)) => model.add_dummy_prefix = flag != 0,
+                        (4, Value::Varint(flag)) => model.remove_extra_whitespaces = flag != 0,
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if model.pieces.is_empty() {
+        return Err("not a SentencePiece model: no pieces".to_string());
+    }
+    Ok(model)
+}
+
+fn parse_piece(bytes: &[u8]) -> Result<Piece, String> {
+    let mut piece = Piece {
+        piece: String::new(),
+        score: 0.0,
+        kind: PieceType::Normal,
+    };
+    for field in ProtoReader::new(bytes) {
+        match field? {
+            (1, Value::Bytes(text)) => {
+                piece.piece = String::from_utf8(text.to_vec()).map_err(|e| e.to_string())?
+            }
+            (2, Value::Fixed32(bits)) => piece.score = f32::from_bits(bits),
+            (3, Value::Varint(kind)) => {
+                piece.kind = match kind {
+                    1 => PieceType::Normal,
+                    2 => PieceType::Unknown,
+                    3 => PieceType::Control,
+                    4 => PieceType::UserDefined,
+                    5 => PieceType::Unused,
+                    6 => PieceType::Byte,
+                    _ => return Err(format!("unknown piece type {}", kind)),
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(piece)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Value<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+/// Iterates over the `(field number, value)` pairs of one protobuf message.
+struct ProtoReader<'a> {
+    bytes: &'a [u8],
+    failed: bool,
+}
+
+impl<'a> ProtoReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            bytes,
+            failed: false,
+        }
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = self.bytes.split_first().ok_or("truncated varint")?;
+            self.bytes = rest;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("varint too long".to_string())
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if len > self.bytes.len() {
+            return Err("truncated field".to_string());
+        }
+        let (value, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(value)
+    }
+
+    fn field(&mut self) -> Result<(u64, Value<'a>), String> {
+        let key = self.varint()?;
+        let value = match key & 0x7 {
+            0 => Value::Varint(self.varint()?),
+            1 => Value::Fixed64(u64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+            2 => {
+                let len = self.varint()? as usize;
+                Value::Bytes(self.take(len)?)
+            }
+            5 => Value::Fixed32(u32::from_le_bytes(self.take(4)?.try_into().unwrap())),
+            wire_type => return Err(format!("unsupported wire type {}", wire_type)),
+        };
+        Ok((key >> 3, value))
+    }
+}
+
+impl<'a> Iterator for ProtoReader<'a> {
+    type Item = Result<(u64, Value<'a>), String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.bytes.is_empty() || self.failed {
+            return None;
+        }
+        let field = self.field();
+        self.failed = field.is_err();
+        Some(field)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn varint(mut value: u64, out: &mut Vec<u8>) {
+        while value >= 0x80 {
+            out.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    fn bytes_field(field: u64, bytes: &[u8], out: &mut Vec<u8>) {
+        varint(field << 3 | 2, out);
+        varint(bytes.len() as u64, out);
+        out.extend_from_slice(bytes);
+    }
+
+    fn varint_field(field: u64, value: u64, out: &mut Vec<u8>) {
+        varint(field << 3, out);
+        varint(value, out);
+    }
+
+    /// Encodes a `ModelProto` with the given `(piece, score, type)` entries.
+    pub(crate) fn model_bytes(
+        pieces: &[(&str, f32, u64)],
+        model_type: u64,
+        add_dummy_prefix: bool,
+    ) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (text, score, kind) in pieces {
+            let mut piece = Vec::new();
+            bytes_field(1, text.as_bytes(), &mut piece);
+            varint(2 << 3 | 5, &mut piece);
+            piece.extend_from_slice(&score.to_bits().to_le_bytes());
+            varint_field(3, *kind, &mut piece);
+            bytes_field(1, &piece, &mut out);
+        }
+        let mut trainer_spec = Vec::new();
+        varint_field(3, model_type, &mut trainer_spec);
+        bytes_field(2, &trainer_spec, &mut out);
+        let mut normalizer_spec = Vec::new();
+        bytes_field(1, b"identity", &mut normalizer_spec);
+        varint_field(3, add_dummy_prefix as u64, &mut normalizer_spec);
+        bytes_field(3, &normalizer_spec, &mut out);
+        out
+    }
+
+    /// A small unigram model: "▁hello" is one piece, "world" is not.
+    pub(crate) fn unigram_model_bytes() -> Vec<u8> {
+        model_bytes(
+            &[
+                ("<unk>", 0.0, 2),
+                ("<s>", 0.0, 3),
+                ("</s>", 0.0, 3),
+                ("\u{2581}hello", -1.0, 1),
+                ("\u{2581}", -2.0, 1),
+                ("\u{2581}wor", -3.0, 1),
+                ("ld", -3.0, 1),
+                ("l", -5.0, 1),
+                ("d", -5.0, 1),
+                ("\u{2581}w", -4.0, 1),
+                ("or", -4.0, 1),
+                ("h", -6.0, 1),
+                ("e", -6.0, 1),
+                ("o", -6.0, 1),
+                ("w", -6.0, 1),
+                ("r", -6.0, 1),
+            ],
+            1,
+            true,
+        )
+    }
+
+    fn tokens(tokenizer: &Tokenizer, text: &str) -> Vec<String> {
+        tokenizer.encode(text, false).unwrap().get_tokens().to_vec()
+    }
+
+    #[test]
+    fn test_parse_model() {
+        let model = parse_model(&unigram_model_bytes()).unwrap();
+        assert_eq!(model.model_type, ModelType::Unigram);
+        assert_eq!(model.pieces.len(), 16);
+        assert_eq!(
+            model.pieces[3],
+            Piece {
+                piece: "\u{2581}hello".to_string(),
+                score: -1.0,
+                kind: PieceType::Normal,
+            }
+        );
+        assert_eq!(model.pieces[1].kind, PieceType::Control);
+        assert!(model.add_dummy_prefix);
+        assert!(model.remove_extra_whitespaces);
+        assert!(!model.byte_fallback);
+
+        assert!(parse_model(b"").is_err());
+        assert!(parse_model(b"\x0a\x05ab").is_err());
+    }
+
+    #[test]
+    fn test_unigram_tokens() {
+        let tokenizer = build_tokenizer(parse_model(&unigram_model_bytes()).unwrap()).unwrap();
+        // Best-scoring segmentation: ▁wor + ld (-6) beats ▁w + or + ld (-11)
+        assert_eq!(
+            tokens(&tokenizer, "hello world"),
+            vec!["\u{2581}hello", "\u{2581}wor", "ld"]
+        );
+        // Extra spaces are collapsed. Control pieces never match text, so
+        // "<s>" is a single fused unknown token
+        let encoding = tokenizer.encode("  hello   <s>", false).unwrap();
+        assert_eq!(encoding.get_tokens(), ["\u{2581}hello", "\u{2581}", "<s>"]);
+        assert_eq!(encoding.get_ids()[2], 0);
+    }
+
+    #[test]
+    fn test_bpe_tokens() {
+        let bytes = model_bytes(
+            &[
+                ("<unk>", 0.0, 2),
+                ("\u{2581}t", -1.0, 1),
+                ("he", -2.0, 1),
+                ("\u{2581}the", -3.0, 1),
+                ("er", -4.0, 1),
+                ("\u{2581}", -5.0, 1),
+                ("t", -5.0, 1),
+                ("h", -5.0, 1),
+                ("e", -5.0, 1),
+                ("r", -5.0, 1),
+            ],
+            2,
+            true,
+        );
+        let tokenizer = build_tokenizer(parse_model(&bytes).unwrap()).unwrap();
+        // ▁t and he merge first, then ▁the; "er" is never formed since "e"
+        // was already merged into "he"
+        assert_eq!(tokens(&tokenizer, "the"), vec!["\u{2581}the"]);
+        assert_eq!(tokens(&tokenizer, "there"), vec!["\u{2581}the", "r", "e"]);
+        assert_eq!(tokens(&tokenizer, "ex"), vec!["\u{2581}", "e", "<unk>"]);
+    }
+
+    #[test]
+    fn test_without_dummy_prefix() {
+        let bytes = model_bytes(
+            &[
+                ("<unk>", 0.0, 2),
+                ("hi", -1.0, 1),
+                ("\u{2581}hi", -1.0, 1),
+                ("\u{2581}", -2.0, 1),
+            ],
+            1,
+            false,
+        );
+        let tokenizer = build_tokenizer(parse_model(&bytes).unwrap()).unwrap();
+        assert_eq!(tokens(&tokenizer, "hi hi"), vec!["hi", "\u{2581}hi"]);
+    }
+
+    /// Checks a real model against ids sentencepiece itself produces. Point
+    /// `SENTENCEPIECE_TEST_MODEL` at Llama 2's tokenizer.model and run with
+    /// `--ignored`; the model is too large to ship with the tests.
+    #[test]
+    #[ignore]
+    fn test_llama2_model_ids() {
+        let path =
+            std::env::var("SENTENCEPIECE_TEST_MODEL").expect("SENTENCEPIECE_TEST_MODEL is not set");
+        let model = parse_model(&std::fs::read(path).unwrap()).unwrap();
+        assert_eq!(model.pieces.len(), 32_000);
+        assert_eq!(model.model_type, ModelType::Bpe);
+        let tokenizer = build_tokenizer(model.clone()).unwrap();
+
+        // Pieces are renumbered without control pieces; map back to the model's ids
+        let original: HashMap<&str, usize> = model
+            .pieces
+            .iter()
+            .enumerate()
+            .map(|(id, piece)| (piece.piece.as_str(), id))
+            .collect();
+        let ids = |text: &str| -> Vec<usize> {
+            tokens(&tokenizer, text)
+                .iter()
+                .map(|token| original[token.as_str()])
+                .collect()
+        };
+        assert_eq!(ids("Hello world"), vec![15043, 3186]);
+        assert_eq!(ids("Hello, world!"), vec![15043, 29892, 3186, 29991]);
+    }
+}
@@ -1,17 +1,90 @@
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 
-use crate::tokenizer::TokenEstimator;
+use crate::bundle_order::OrderPolicy;
+use crate::fs_utils::TruncationPolicy;
+use crate::models::{self, TargetModel};
+use crate::output_format::OutputFormat;
+use crate::tokenizer::{CustomTokenizer, TokenEstimator};
 use dirs_next::config_dir;
 
 const APP_NAME: &str = "context_loader";
 const SETTINGS_FILE: &str = "settings.json";
 const MAX_RECENT_WORKSPACES: usize = 5;
 
+fn default_line_number_width() -> usize {
+    4
+}
+
+fn default_token_budget() -> usize {
+    100_000
+}
+
+fn default_target_model() -> String {
+    models::DEFAULT_MODEL.to_string()
+}
+
+/// Choices remembered for one workspace.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct WorkspaceState {
+    /// Bundle order chosen under `OrderPolicy::Manual`.
+    #[serde(default)]
+    pub manual_order: Vec<PathBuf>,
+    /// Files packed first when a token budget is set.
+    #[serde(default)]
+    pub pinned: HashSet<PathBuf>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Settings {
     pub recent_workspaces: Vec<PathBuf>,
     pub token_estimator: TokenEstimator,
+    #[serde(default)]
+    pub output_format: OutputFormat,
+    /// Prepend an ASCII tree of the workspace to copied bundles.
+    #[serde(default)]
+    pub include_tree: bool,
+    /// Prefix every copied line with its number.
+    #[serde(default)]
+    pub line_numbers: bool,
+    #[serde(default = "default_line_number_width")]
+    pub line_number_width: usize,
+    #[serde(default)]
+    pub order_policy: OrderPolicy,
+    /// Emit "binary file, N KB, omitted" for selected binary files instead of
+    /// dropping them from the bundle.
+    #[serde(default)]
+    pub binary_placeholders: bool,
+    /// Cap oversized files when copying. The policy is kept while disabled so
+    /// re-enabling restores the previous limits.
+    #[serde(default)]
+    pub truncation_enabled: bool,
+    #[serde(default)]
+    pub truncation: TruncationPolicy,
+    /// Pack the selection into `token_budget` when copying.
+    #[serde(default)]
+    pub budget_enabled: bool,
+    #[serde(default = "default_token_budget")]
+    pub token_budget: usize,
+    /// Local `tokenizer.json` for the Llama2 estimator.
+    #[serde(default)]
+    pub llama2_tokenizer_path: Option<PathBuf>,
+    /// Local SentencePiece `.model` for the Gemini estimator.
+    #[serde(default)]
+    pub sentencepiece_model_path: Option<PathBuf>,
+    /// Tokenizer files registered by the user, offered as custom estimators.
+    #[serde(default)]
+    pub custom_tokenizers: Vec<CustomTokenizer>,
+    /// Name of the model whose context window the footer measures against.
+    #[serde(default = "default_target_model")]
+    pub target_model: String,
+    /// User-defined models, offered next to the built-in ones.
+    #[serde(default)]
+    pub custom_models: Vec<TargetModel>,
+    /// Per-workspace state, keyed by workspace root.
+    #[serde(default)]
+    pub workspaces: HashMap<PathBuf, WorkspaceState>,
     pub config_path: Option<PathBuf>,
 }
 
@@ -20,6 +93,22 @@ impl Default for Settings {
         Self {
             recent_workspaces: Vec::new(),
             token_estimator: TokenEstimator::default(),
+            output_format: OutputFormat::default(),
+            include_tree: false,
+            line_numbers: false,
+            line_number_width: default_line_number_width(),
+            order_policy: OrderPolicy::default(),
+            binary_placeholders: false,
+            truncation_enabled: false,
+            truncation: TruncationPolicy::default(),
+            budget_enabled: false,
+            token_budget: default_token_budget(),
+            llama2_tokenizer_path: None,
+            sentencepiece_model_path: None,
+            custom_tokenizers: Vec::new(),
+            target_model: default_target_model(),
+            custom_models: Vec::new(),
+            workspaces: HashMap::new(),
             config_path: None,
         }
     }
@@ -30,6 +119,22 @@ impl Settings {
         Self {
             recent_workspaces: Vec::new(),
             token_estimator: TokenEstimator::default(),
+            output_format: OutputFormat::default(),
+            include_tree: false,
+            line_numbers: false,
+            line_number_width: default_line_number_width(),
+            order_policy: OrderPolicy::default(),
+            binary_placeholders: false,
+            truncation_enabled: false,
+            truncation: TruncationPolicy::default(),
+            budget_enabled: false,
+            token_budget: default_token_budget(),
+            llama2_tokenizer_path: None,
+            sentencepiece_model_path: None,
+            custom_tokenizers: Vec::new(),
+            target_model: default_target_model(),
+            custom_models: Vec::new(),
+            workspaces: HashMap::new(),
             config_path: Some(config_path),
         }
     }
@@ -59,6 +164,150 @@ impl Settings {
         self.token_estimator.clone()
     }
 
+    pub fn set_output_format(&mut self, format: OutputFormat) {
+        self.output_format = format;
+    }
+
+    pub fn get_output_format(&self) -> OutputFormat {
+        self.output_format.clone()
+    }
+
+    pub fn set_include_tree(&mut self, include_tree: bool) {
+        self.include_tree = include_tree;
+    }
+
+    pub fn get_include_tree(&self) -> bool {
+        self.include_tree
+    }
+
+    pub fn set_order_policy(&mut self, policy: OrderPolicy) {
+        self.order_policy = policy;
+    }
+
+    pub fn get_order_policy(&self) -> OrderPolicy {
+        self.order_policy
+    }
+
+    pub fn set_binary_placeholders(&mut self, enabled: bool) {
+        self.binary_placeholders = enabled;
+    }
+
+    pub fn get_binary_placeholders(&self) -> bool {
+        self.binary_placeholders
+    }
+
+    pub fn set_truncation(&mut self, enabled: bool, policy: TruncationPolicy) {
+        self.truncation_enabled = enabled;
+        self.truncation = policy;
+    }
+
+    /// The per-file cap to apply, or `None` when truncation is off.
+    pub fn get_truncation(&self) -> Option<TruncationPolicy> {
+        self.truncation_enabled.then_some(self.truncation)
+    }
+
+    pub fn set_token_budget(&mut self, enabled: bool, budget: usize) {
+        self.budget_enabled = enabled;
+        self.token_budget = budget;
+    }
+
+    /// The token budget to pack the selection into, or `None` when packing is off.
+    pub fn get_token_budget(&self) -> Option<usize> {
+        self.budget_enabled.then_some(self.token_budget)
+    }
+
+    pub fn set_target_model(&mut self, name: String) {
+        self.target_model = name;
+    }
+
+    /// The selected target model, or the default one if it no longer exists.
+    pub fn get_target_model(&self) -> TargetModel {
+        models::find_model(&self.target_model, &self.custom_models)
+            .or_else(|| models::find_model(models::DEFAULT_MODEL, &[]))
+            .expect("default model is built in")
+    }
+
+    /// Every model the user can pick, built-in and custom.
+    pub fn get_models(&self) -> Vec<TargetModel> {
+        models::all_models(&self.custom_models)
+    }
+
+    /// Sets the tokenizer file of a built-in file-backed estimator; ignored
+    /// for others. Custom tokenizers are set with `add_custom_tokenizer`.
+    pub fn set_tokenizer_path(&mut self, estimator: &TokenEstimator, path: Option<PathBuf>) {
+        match estimator {
+            TokenEstimator::Llama2 => self.llama2_tokenizer_path = path,
+            TokenEstimator::SentencePiece => self.sentencepiece_model_path = path,
+            _ => {}
+        }
+    }
+
+    pub fn get_tokenizer_path(&self, estimator: &TokenEstimator) -> Option<&Path> {
+        match estimator {
+            TokenEstimator::Llama2 => self.llama2_tokenizer_path.as_deref(),
+            TokenEstimator::SentencePiece => self.sentencepiece_model_path.as_deref(),
+            TokenEstimator::Custom(name) => self
+                .custom_tokenizers
+                .iter()
+                .find(|t| t.name == *name)
+                .map(|t| t.path.as_path()),
+            _ => None,
+        }
+    }
+
+    /// Registers `tokenizer`, replacing any with the same name.
+    pub fn add_custom_tokenizer(&mut self, tokenizer: CustomTokenizer) {
+        self.custom_tokenizers.retain(|t| t.name != tokenizer.name);
+        self.custom_tokenizers.push(tokenizer);
+    }
+
+    /// Removes the named tokenizer, falling back to the default estimator if
+    /// it was selected.
+    pub fn remove_custom_tokenizer(&mut self, name: &str) {
+        self.custom_tokenizers.retain(|t| t.name != name);
+        if self.token_estimator == TokenEstimator::Custom(name.to_string()) {
+            self.token_estimator = TokenEstimator::default();
+        }
+    }
+
+    pub fn get_custom_tokenizers(&self) -> &[CustomTokenizer] {
+        &self.custom_tokenizers
+    }
+
+    pub fn set_line_numbers(&mut self, enabled: bool, width: usize) {
+        self.line_numbers = enabled;
+        self.line_number_width = width.max(1);
+    }
+
+    /// The line-number width to use when copying, or `None` when numbering is off.
+    pub fn get_line_numbers(&self) -> Option<usize> {
+        self.line_numbers.then_some(self.line_number_width)
+    }
+
+    pub fn set_manual_order(&mut self, workspace: &Path, order: Vec<PathBuf>) {
+        self.workspaces
+            .entry(workspace.to_path_buf())
+            .or_default()
+            .manual_order = order;
+    }
+
+    pub fn get_manual_order(&self, workspace: &Path) -> &[PathBuf] {
+        self.workspaces
+            .get(workspace)
+            .map_or(&[], |state| &state.manual_order)
+    }
+
+    pub fn set_pinned_files(&mut self, workspace: &Path, pinned: HashSet<PathBuf>) {
+        self.workspaces
+            .entry(workspace.to_path_buf())
+            .or_default()
+            .pinned = pinned;
+    }
+
+    pub fn get_pinned_files(&self, workspace: &Path) -> Option<&HashSet<PathBuf>> {
+        self.workspaces.get(workspace).map(|state| &state.pinned)
+    }
+
     pub async fn save(&self) -> std::io::Result<()> {
         if let Some(path) = &self.config_path {
             let json = serde_json::to_string_pretty(self)?;
@@ -99,6 +348,7 @@ impl Settings {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::tokenizer::SplitPattern;
     use tempfile::tempdir;
 
     #[tokio::test]
@@ -154,4 +404,188 @@ mod tests {
         // Verify loaded settings are empty
         assert_eq!(loaded_settings.recent_workspaces.len(), 0);
     }
+
+    #[tokio::test]
+    async fn test_output_format_persisted() {
+        let temp_dir = tempdir().unwrap();
+        let settings_file = temp_dir.path().join("settings.json");
+        let mut settings = Settings::new(settings_file.clone());
+        assert_eq!(settings.get_output_format(), OutputFormat::Headers);
+
+        settings.set_output_format(OutputFormat::Xml);
+        settings.save().await.unwrap();
+
+        let loaded_settings = Settings::load(&settings_file).await.unwrap();
+        assert_eq!(loaded_settings.get_output_format(), OutputFormat::Xml);
+    }
+
+    #[tokio::test]
+    async fn test_custom_tokenizers_persisted() {
+        let temp_dir = tempdir().unwrap();
+        let settings_file = temp_dir.path().join("settings.json");
+        let mut settings = Settings::new(settings_file.clone());
+        let tokenizer = |name: &str, path: &str| CustomTokenizer {
+            name: name.to_string(),
+            path: PathBuf::from(path),
+            split_pattern: SplitPattern::O200k,
+        };
+
+        settings.add_custom_tokenizer(tokenizer("support-ft", "/models/old.json"));
+        settings.add_custom_tokenizer(tokenizer("support-ft", "/models/support.json"));
+        settings.add_custom_tokenizer(tokenizer("legal", "/models/legal.tiktoken"));
+        settings.set_token_estimator(TokenEstimator::Custom("legal".to_string()));
+        settings.save().await.unwrap();
+
+        let json = tokio::fs::read_to_string(&settings_file).await.unwrap();
+        assert!(json.contains(r#""token_estimator": "custom:legal""#));
+
+        let mut loaded_settings = Settings::load(&settings_file).await.unwrap();
+        assert_eq!(
+            loaded_settings.get_custom_tokenizers(),
+            [
+                tokenizer("support-ft", "/models/support.json"),
+                tokenizer("legal", "/models/legal.tiktoken")
+            ]
+        );
+        assert_eq!(
+            loaded_settings.get_token_estimator(),
+            TokenEstimator::Custom("legal".to_string())
+        );
+        assert_eq!(
+            loaded_settings.get_tokenizer_path(&TokenEstimator::Custom("legal".to_string())),
+            Some(Path::new("/models/legal.tiktoken"))
+        );
+
+        loaded_settings.remove_custom_tokenizer("legal");
+        assert_eq!(loaded_settings.get_custom_tokenizers().len(), 1);
+        assert_eq!(
+            loaded_settings.get_token_estimator(),
+            TokenEstimator::CharDiv4
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tokenizer_paths_persisted() {
+        let temp_dir = tempdir().unwrap();
+        let settings_file = temp_dir.path().join("settings.json");
+        let mut settings = Settings::new(settings_file.clone());
+
+        settings.set_tokenizer_path(
+            &TokenEstimator::Llama2,
+            Some(PathBuf::from("/models/llama2/tokenizer.json")),
+        );
+        settings.set_tokenizer_path(
+            &TokenEstimator::SentencePiece,
+            Some(PathBuf::from("/models/gemma/tokenizer.model")),
+        );
+        settings.set_tokenizer_path(&TokenEstimator::Cl100k, Some(PathBuf::from("/ignored")));
+        settings.save().await.unwrap();
+
+        let loaded_settings = Settings::load(&settings_file).await.unwrap();
+        assert_eq!(
+            loaded_settings.get_tokenizer_path(&TokenEstimator::Llama2),
+            Some(Path::new("/models/llama2/tokenizer.json"))
+        );
+        assert_eq!(
+            loaded_settings.get_tokenizer_path(&TokenEstimator::SentencePiece),
+            Some(Path::new("/models/gemma/tokenizer.model"))
+        );
+        assert_eq!(
+            loaded_settings.get_tokenizer_path(&TokenEstimator::Cl100k),
+            None
+        );
+    }
+
+    #[test]
+    fn test_line_numbers() {
+        let mut settings = Settings::default();
+        assert_eq!(settings.get_line_numbers(), None);
+
+        settings.set_line_numbers(true, 6);
+        assert_eq!(settings.get_line_numbers(), Some(6));
+
+        settings.set_line_numbers(false, 0);
+        assert_eq!(settings.get_line_numbers(), None);
+        assert_eq!(settings.line_number_width, 1);
+    }
+
+    #[tokio::test]
+    async fn test_load_settings_without_output_format() {
+        let temp_dir = tempdir().unwrap();
+        let settings_file = temp_dir.path().join("settings.json");
+        tokio::fs::write(
+            &settings_file,
+            r#"{"recent_workspaces":[],"token_estimator":"Cl100k","config_path":null}"#,
+        )
+        .await
+        .unwrap();
+
+        let loaded_settings = Settings::load(&settings_file).await.unwrap();
+        assert_eq!(loaded_settings.get_output_format(), OutputFormat::Headers);
+        assert_eq!(
+            loaded_settings.get_token_estimator(),
+            TokenEstimator::Cl100k
+        );
+        assert_eq!(loaded_settings.line_number_width, 4);
+        assert_eq!(loaded_settings.get_order_policy(), OrderPolicy::Path);
+        assert!(!loaded_settings.get_binary_placeholders());
+        assert_eq!(loaded_settings.get_truncation(), None);
+        assert_eq!(loaded_settings.get_token_budget(), None);
+        assert_eq!(loaded_settings.token_budget, 100_000);
+        assert_eq!(
+            loaded_settings.get_tokenizer_path(&TokenEstimator::Llama2),
+            None
+        );
+        assert_eq!(loaded_settings.get_target_model().name, "gpt-4o");
+    }
+
+    #[tokio::test]
+    async fn test_target_model_persisted() {
+        let temp_dir = tempdir().unwrap();
+        let settings_file = temp_dir.path().join("settings.json");
+        tokio::fs::write(
+            &settings_file,
+            r#"{"recent_workspaces":[],"token_estimator":"Cl100k","config_path":null,
+                "target_model":"in-house",
+                "custom_models":[{"name":"in-house","context_window":32000,"output_reserve":2000}]}"#,
+        )
+        .await
+        .unwrap();
+
+        let mut loaded_settings = Settings::load(&settings_file).await.unwrap();
+        assert_eq!(loaded_settings.get_target_model().input_budget(), 30_000);
+        assert!(loaded_settings
+            .get_models()
+            .iter()
+            .any(|model| model.name == "in-house"));
+
+        loaded_settings.set_target_model("claude-sonnet-4".to_string());
+        loaded_settings.save().await.unwrap();
+        let reloaded = Settings::load(&settings_file).await.unwrap();
+        assert_eq!(reloaded.get_target_model().context_window, 200_000);
+        assert_eq!(reloaded.custom_models.len(), 1);
+
+        // A model removed from settings.json falls back to the default
+        let mut settings = Settings::default();
+        settings.set_target_model("removed".to_string());
+        assert_eq!(settings.get_target_model().name, "gpt-4o");
+    }
+
+    #[tokio::test]
+    async fn test_workspace_state_persisted() {
+        let temp_dir = tempdir().unwrap();
+        let settings_file = temp_dir.path().join("settings.json");
+        let mut settings = Settings::new(settings_file.clone());
+        let order = vec![PathBuf::from("/a/main.rs"), PathBuf::from("/a/lib.rs")];
+        settings.set_manual_order(Path::new("/a"), order.clone());
+        let pinned: HashSet<PathBuf> = [PathBuf::from("/a/lib.rs")].into_iter().collect();
+        settings.set_pinned_files(Path::new("/a"), pinned.clone());
+        settings.save().await.unwrap();
+
+        let loaded = Settings::load(&settings_file).await.unwrap();
+        assert_eq!(loaded.get_manual_order(Path::new("/a")), order.as_slice());
+        assert_eq!(loaded.get_pinned_files(Path::new("/a")), Some(&pinned));
+        assert!(loaded.get_manual_order(Path::new("/b")).is_empty());
+        assert_eq!(loaded.get_pinned_files(Path::new("/b")), None);
+    }
 }
@@ -1,40 +1,142 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fmt;
-use std::path::Path;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use tiktoken_rs::cl100k_base;
+use std::sync::{Arc, OnceLock, RwLock};
+use tiktoken_rs::{cl100k_base, o200k_base, p50k_base, CoreBPE};
+use tokenizers::Tokenizer;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+use crate::encoding::read_text_streamed;
+use crate::sentencepiece;
+
+/// Serialized as its `Display` form, e.g. "Cl100k" or "custom:my-model".
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
 pub enum TokenEstimator {
     CharDiv4,
     Cl100k,
+    /// GPT-4o and later OpenAI models.
+    O200k,
+    /// Codex and the text-davinci-002/003 models.
+    P50k,
     Llama2,
     SentencePiece,
+    /// A tokenizer file registered by the user, by name.
+    Custom(String),
+}
+
+/// Prefix of custom estimators in their string form.
+const CUSTOM_PREFIX: &str = "custom:";
+
+/// Model names accepted in place of an estimator id. A versioned name such
+/// as "gpt-4o-2024-08-06" matches its longest listed prefix.
+pub const MODEL_ALIASES: [(&str, TokenEstimator); 17] = [
+    ("gpt-4.1", TokenEstimator::O200k),
+    ("gpt-4.1-mini", TokenEstimator::O200k),
+    ("gpt-4.1-nano", TokenEstimator::O200k),
+    ("gpt-4o", TokenEstimator::O200k),
+    ("gpt-4o-mini", TokenEstimator::O200k),
+    ("o1", TokenEstimator::O200k),
+    ("o3", TokenEstimator::O200k),
+    ("o4-mini", TokenEstimator::O200k),
+    ("gpt-4", TokenEstimator::Cl100k),
+    ("gpt-4-turbo", TokenEstimator::Cl100k),
+    ("gpt-3.5-turbo", TokenEstimator::Cl100k),
+    ("text-embedding-3-small", TokenEstimator::Cl100k),
+    ("text-embedding-3-large", TokenEstimator::Cl100k),
+    ("text-davinci-003", TokenEstimator::P50k),
+    ("code-davinci-002", TokenEstimator::P50k),
+    ("llama-2", TokenEstimator::Llama2),
+    ("gemini", TokenEstimator::SentencePiece),
+];
+
+impl TokenEstimator {
+    /// Every built-in estimator, in Toolbar order.
+    pub const BUILT_IN: [TokenEstimator; 6] = [
+        Self::CharDiv4,
+        Self::Cl100k,
+        Self::O200k,
+        Self::P50k,
+        Self::Llama2,
+        Self::SentencePiece,
+    ];
+
+    /// All built-in estimators, in Toolbar order.
+    pub fn all() -> impl Iterator<Item = TokenEstimator> {
+        Self::BUILT_IN.into_iter()
+    }
+
+    /// The name used in settings and caches, which must not change; `None`
+    /// for custom estimators.
+    fn id(&self) -> Option<&'static str> {
+        match self {
+            Self::CharDiv4 => Some("CharDiv4"),
+            Self::Cl100k => Some("Cl100k"),
+            Self::O200k => Some("O200k"),
+            Self::P50k => Some("P50k"),
+            Self::Llama2 => Some("Llama2"),
+            Self::SentencePiece => Some("SentencePiece"),
+            Self::Custom(_) => None,
+        }
+    }
+
+    /// The estimator for a model name, see `MODEL_ALIASES`.
+    pub fn for_model(model: &str) -> Option<TokenEstimator> {
+        let model = model.to_ascii_lowercase();
+        MODEL_ALIASES
+            .iter()
+            .filter(|(alias, _)| {
+                model == *alias
+                    || model
+                        .strip_prefix(alias)
+                        .is_some_and(|rest| rest.starts_with('-'))
+            })
+            .max_by_key(|(alias, _)| alias.len())
+            .map(|(_, estimator)| estimator.clone())
+    }
 }
 
 impl fmt::Display for TokenEstimator {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Self::CharDiv4 => write!(f, "CharDiv4"),
-            Self::Cl100k => write!(f, "Cl100k"),
-            Self::Llama2 => write!(f, "Llama2"),
-            Self::SentencePiece => write!(f, "SentencePiece"),
+        match self.id() {
+            Some(id) => f.write_str(id),
+            None => write!(f, "{}{}", CUSTOM_PREFIX, self.name()),
         }
     }
 }
 
+/// Accepts an estimator id, "custom:<name>" or a model name.
 impl FromStr for TokenEstimator {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "CharDiv4" => Ok(Self::CharDiv4),
-            "Cl100k" => Ok(Self::Cl100k),
-            "Llama2" => Ok(Self::Llama2),
-            "SentencePiece" => Ok(Self::SentencePiece),
-            _ => Err(format!("Unknown token estimator: {}", s)),
+        if let Some(name) = s
+            .strip_prefix(CUSTOM_PREFIX)
+            .filter(|name| !name.is_empty())
+        {
+            return Ok(Self::Custom(name.to_string()));
         }
+        Self::all()
+            .find(|estimator| estimator.id() == Some(s))
+            .or_else(|| Self::for_model(s))
+            .ok_or_else(|| format!("Unknown token estimator: {}", s))
+    }
+}
+
+impl From<TokenEstimator> for String {
+    fn from(estimator: TokenEstimator) -> Self {
+        estimator.to_string()
+    }
+}
+
+impl TryFrom<String> for TokenEstimator {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
     }
 }
 
@@ -44,50 +146,432 @@ impl Default for TokenEstimator {
     }
 }
 
-impl TokenEstimator {
+/// Why an estimator cannot count tokens.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenizerError {
+    /// No tokenizer file is set in the settings.
+    NotConfigured,
+    Missing(PathBuf),
+    Invalid(PathBuf, String),
+    /// No custom tokenizer is registered under this name.
+    Unknown(String),
+}
+
+impl fmt::Display for TokenizerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotConfigured => write!(f, "tokenizer file missing: none configured"),
+            Self::Missing(path) => write!(f, "tokenizer file missing: {}", path.display()),
+            Self::Invalid(path, message) => {
+                write!(f, "invalid tokenizer file {}: {}", path.display(), message)
+            }
+            Self::Unknown(name) => write!(f, "no tokenizer named {}", name),
+        }
+    }
+}
+
+impl std::error::Error for TokenizerError {}
+
+impl TokenizerError {
+    /// Short label for the UI; `Display` has the details.
+    pub fn summary(&self) -> &'static str {
+        match self {
+            Self::NotConfigured | Self::Missing(_) => "Tokenizer file missing",
+            Self::Invalid(..) => "Invalid tokenizer file",
+            Self::Unknown(_) => "Unknown tokenizer",
+        }
+    }
+}
+
+/// A tokenizer registered by the user: a Hugging Face `tokenizer.json` or a
+/// tiktoken `.tiktoken` rank file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CustomTokenizer {
+    pub name: String,
+    pub path: PathBuf,
+    /// How a `.tiktoken` file splits text before ranking; unused for
+    /// `tokenizer.json`, which records its own.
+    #[serde(default)]
+    pub split_pattern: SplitPattern,
+}
+
+/// The pre-tokenizer regex of a `.tiktoken` file, which does not record
+/// its own: that of the bundled encoding its vocabulary derives from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SplitPattern {
+    #[default]
+    Cl100k,
+    O200k,
+    P50k,
+}
+
+impl SplitPattern {
+    /// All patterns, in Toolbar order.
+    pub const ALL: [SplitPattern; 3] = [Self::Cl100k, Self::O200k, Self::P50k];
+
     pub fn name(&self) -> &'static str {
+        match self {
+            Self::Cl100k => "cl100k split",
+            Self::O200k => "o200k split",
+            Self::P50k => "p50k split",
+        }
+    }
+
+    fn regex(&self) -> String {
+        match self {
+            Self::Cl100k => r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+".to_string(),
+            Self::O200k => [
+                r"[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]*[\p{Ll}\p{Lm}\p{Lo}\p{M}]+(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+                r"[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]+[\p{Ll}\p{Lm}\p{Lo}\p{M}]*(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+                r"\p{N}{1,3}",
+                r" ?[^\s\p{L}\p{N}]+[\r\n/]*",
+                r"\s*[\r\n]+",
+                r"\s+(?!\S)",
+                r"\s+",
+            ]
+            .join("|"),
+            Self::P50k => r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for SplitPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl FromStr for SplitPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|pattern| pattern.to_string() == s)
+            .ok_or_else(|| format!("Unknown split pattern: {}", s))
+    }
+}
+
+impl CustomTokenizer {
+    pub fn estimator(&self) -> TokenEstimator {
+        TokenEstimator::Custom(self.name.clone())
+    }
+}
+
+/// A tokenizer read from a local file.
+enum FileTokenizer {
+    HuggingFace(Box<Tokenizer>),
+    Tiktoken(CoreBPE),
+}
+
+impl FileTokenizer {
+    fn count(&self, text: &str) -> usize {
+        match self {
+            Self::HuggingFace(tokenizer) => match tokenizer.encode(text, false) {
+                Ok(encoding) => encoding.len(),
+                Err(e) => {
+                    log::error!("Tokenizer failed: {}", e);
+                    0
+                }
+            },
+            Self::Tiktoken(bpe) => bpe.encode_ordinary(text).len(),
+        }
+    }
+}
+
+/// A loaded tokenizer file, or why there is none.
+type Loaded = Result<Arc<FileTokenizer>, TokenizerError>;
+
+static LLAMA2_TOKENIZER: RwLock<Loaded> = RwLock::new(Err(TokenizerError::NotConfigured));
+static SENTENCEPIECE_TOKENIZER: RwLock<Loaded> = RwLock::new(Err(TokenizerError::NotConfigured));
+static CUSTOM_TOKENIZERS: RwLock<BTreeMap<String, Loaded>> = RwLock::new(BTreeMap::new());
+
+// Building a bundled BPE parses its whole rank table, so each one is built on
+// first use and then shared by every caller and thread.
+static CL100K: OnceLock<CoreBPE> = OnceLock::new();
+static O200K: OnceLock<CoreBPE> = OnceLock::new();
+static P50K: OnceLock<CoreBPE> = OnceLock::new();
+
+fn status(loaded: &Loaded) -> Result<(), TokenizerError> {
+    loaded.as_ref().map(|_| ()).map_err(Clone::clone)
+}
+
+/// 0 when unavailable; callers check `availability` before showing counts.
+fn count(loaded: &Loaded, text: &str) -> usize {
+    loaded.as_ref().map_or(0, |tokenizer| tokenizer.count(text))
+}
+
+/// Reads a `.tiktoken` file: one base64 token and its rank per line.
+fn load_tiktoken(path: &Path, pattern: SplitPattern) -> Result<CoreBPE, String> {
+    let ranks = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let mut encoder = std::collections::HashMap::default();
+    for (index, line) in ranks.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let parsed = line.split_once(' ').and_then(|(token, rank)| {
+            let token = BASE64.decode(token).ok()?;
+            let rank = rank.trim().parse::<usize>().ok()?;
+            Some((token, rank))
+        });
+        let (token, rank) = parsed
+            .ok_or_else(|| format!("line {}: expected \"<base64 token> <rank>\"", index + 1))?;
+        encoder.insert(token, rank);
+    }
+    // Any text can be encoded only if every single byte is a token
+    if (0..=u8::MAX).any(|byte| !encoder.contains_key([byte].as_slice())) {
+        return Err("rank file does not cover all 256 bytes".to_string());
+    }
+    CoreBPE::new(encoder, Default::default(), &pattern.regex()).map_err(|e| e.to_string())
+}
+
+fn load_file(
+    path: Option<&Path>,
+    read: impl FnOnce(&Path) -> Result<FileTokenizer, String>,
+) -> Loaded {
+    let path = match path {
+        None => return Err(TokenizerError::NotConfigured),
+        Some(path) if !path.is_file() => return Err(TokenizerError::Missing(path.to_path_buf())),
+        Some(path) => path,
+    };
+    read(path)
+        .map(Arc::new)
+        .map_err(|e| TokenizerError::Invalid(path.to_path_buf(), e))
+}
+
+fn read_tokenizer_json(path: &Path) -> Result<FileTokenizer, String> {
+    Tokenizer::from_file(path)
+        .map(|tokenizer| FileTokenizer::HuggingFace(Box::new(tokenizer)))
+        .map_err(|e| e.to_string())
+}
+
+/// Loads the tokenizer file of a built-in file-backed estimator, replacing
+/// any previous one: a `tokenizer.json` for `Llama2` and a SentencePiece
+/// `.model` for `SentencePiece`; other estimators need no file. Nothing is
+/// downloaded; without a readable file the estimator reports the error from
+/// `availability` instead of guessing. Parsing a large file takes a while,
+/// so call it off the UI thread.
+pub fn load_tokenizer_file(
+    estimator: &TokenEstimator,
+    path: Option<&Path>,
+) -> Result<(), TokenizerError> {
+    let (slot, loaded) = match estimator {
+        TokenEstimator::Llama2 => (&LLAMA2_TOKENIZER, load_file(path, read_tokenizer_json)),
+        TokenEstimator::SentencePiece => (
+            &SENTENCEPIECE_TOKENIZER,
+            load_file(path, |path| {
+                sentencepiece::load_model(path)
+                    .map(|tokenizer| FileTokenizer::HuggingFace(Box::new(tokenizer)))
+            }),
+        ),
+        _ => return Ok(()),
+    };
+    let result = status(&loaded);
+    *slot.write().unwrap() = loaded;
+    result
+}
+
+/// Loads a custom tokenizer, a `tokenizer.json` or a `.tiktoken` file split
+/// with its `split_pattern`, replacing any previous one of the same name.
+/// Like `load_tokenizer_file`, call it off the UI thread.
+pub fn load_custom_tokenizer(custom: &CustomTokenizer) -> Result<(), TokenizerError> {
+    let loaded = load_file(Some(&custom.path), |path| {
+        if path.extension().is_some_and(|ext| ext == "tiktoken") {
+            load_tiktoken(path, custom.split_pattern).map(FileTokenizer::Tiktoken)
+        } else {
+            read_tokenizer_json(path)
+        }
+    });
+    let result = status(&loaded);
+    CUSTOM_TOKENIZERS
+        .write()
+        .unwrap()
+        .insert(custom.name.clone(), loaded);
+    result
+}
+
+/// Drops a custom tokenizer; its estimator becomes unavailable.
+pub fn unload_custom_tokenizer(name: &str) {
+    CUSTOM_TOKENIZERS.write().unwrap().remove(name);
+}
+
+impl TokenEstimator {
+    /// What the Toolbar shows.
+    pub fn name(&self) -> &str {
         match self {
             Self::CharDiv4 => "Char/4 heuristic",
-            Self::Cl100k => "GPT-3/4 (cl100k)",
+            Self::Cl100k => "GPT-3.5/4 (cl100k)",
+            Self::O200k => "GPT-4o/4.1 (o200k)",
+            Self::P50k => "Codex/davinci (p50k)",
             Self::Llama2 => "Llama2 BPE",
             Self::SentencePiece => "Gemini SentencePiece",
+            Self::Custom(name) => name,
+        }
+    }
+
+    /// Estimators that count with a tokenizer loaded from a local file.
+    pub const FILE_BACKED: [TokenEstimator; 2] = [Self::Llama2, Self::SentencePiece];
+
+    /// The extension of the tokenizer file a built-in file-backed estimator
+    /// loads. Custom estimators get their file when registered.
+    pub fn tokenizer_file_extension(&self) -> Option<&'static str> {
+        match self {
+            Self::Llama2 => Some("json"),
+            Self::SentencePiece => Some("model"),
+            _ => None,
+        }
+    }
+
+    /// Whether this estimator can count tokens. File-backed estimators are
+    /// unavailable while their tokenizer file is missing or invalid.
+    pub fn availability(&self) -> Result<(), TokenizerError> {
+        match self {
+            Self::Llama2 => status(&LLAMA2_TOKENIZER.read().unwrap()),
+            Self::SentencePiece => status(&SENTENCEPIECE_TOKENIZER.read().unwrap()),
+            Self::Custom(name) => match CUSTOM_TOKENIZERS.read().unwrap().get(name) {
+                Some(loaded) => status(loaded),
+                None => Err(TokenizerError::Unknown(name.clone())),
+            },
+            _ => Ok(()),
         }
     }
 
+    /// The shared instance of a bundled tiktoken encoding, built on first use.
+    fn bundled_bpe(&self) -> Option<&'static CoreBPE> {
+        let bpe = match self {
+            Self::Cl100k => CL100K.get_or_init(|| cl100k_base().expect("bundled cl100k ranks")),
+            Self::O200k => O200K.get_or_init(|| o200k_base().expect("bundled o200k ranks")),
+            Self::P50k => P50K.get_or_init(|| p50k_base().expect("bundled p50k ranks")),
+            _ => return None,
+        };
+        Some(bpe)
+    }
+
     pub fn estimate_tokens(&self, text: &str) -> usize {
         match self {
             Self::CharDiv4 => text.chars().count() / 4,
-            Self::Cl100k => {
-                let bpe = cl100k_base().unwrap();
-                bpe.encode_with_special_tokens(text).len()
-            }
-            Self::Llama2 => {
-                // TODO: Implement Llama2 tokenizer
-                text.chars().count() / 4 // Fallback for now
-            }
-            Self::SentencePiece => {
-                // TODO: Implement SentencePiece tokenizer
-                text.chars().count() / 4 // Fallback for now
-            }
+            Self::Cl100k | Self::O200k | Self::P50k => self
+                .bundled_bpe()
+                .map_or(0, |bpe| bpe.encode_with_special_tokens(text).len()),
+            Self::Llama2 => count(&LLAMA2_TOKENIZER.read().unwrap(), text),
+            Self::SentencePiece => count(&SENTENCEPIECE_TOKENIZER.read().unwrap(), text),
+            Self::Custom(name) => CUSTOM_TOKENIZERS
+                .read()
+                .unwrap()
+                .get(name)
+                .map_or(0, |loaded| count(loaded, text)),
         }
     }
+}
 
+impl TokenEstimator {
+    /// Counts the tokens of a file without loading it whole; see `TokenStream`.
     pub fn estimate_file_tokens(&self, path: &Path) -> std::io::Result<usize> {
-        let content = std::fs::read_to_string(path)?;
-        Ok(self.estimate_tokens(&content))
+        let mut stream = TokenStream::new(self);
+        read_text_streamed(path, |text| stream.push(text))?;
+        Ok(stream.finish())
     }
 }
 
-pub async fn count_tokens(path: &PathBuf, estimator: TokenEstimator) -> std::io::Result<usize> {
-    let content = tokio::fs::read_to_string(path).await?;
-    Ok(estimator.estimate_tokens(&content))
+/// Text tokenized at once when streaming.
+const STREAM_CHUNK_LEN: usize = 1024 * 1024;
+
+/// Whether text can be tokenized in two parts split between `before` and
+/// `after`: the split must come right before a line that starts with a
+/// non-whitespace character. No token of the bundled tiktoken encodings
+/// spans such a split, so the parts' counts add up to the count of the whole
+/// text. Other tokenizers may differ by about one token per split.
+pub fn is_token_boundary(before: &str, after: &str) -> bool {
+    before.ends_with('\n') && after.starts_with(|c: char| !c.is_whitespace())
+}
+
+/// Counts the tokens of text that arrives in pieces, tokenizing about
+/// `STREAM_CHUNK_LEN` bytes at a time so memory stays bounded.
+///
+/// Chunks end at an `is_token_boundary`, so totals match `estimate_tokens` on
+/// the whole text wherever that rule holds, and always for CharDiv4. A single
+/// line longer than two chunks has to be split elsewhere, which may be off
+/// by about one token.
+pub struct TokenStream<'a> {
+    estimator: &'a TokenEstimator,
+    chunk_len: usize,
+    buffer: String,
+    tokens: usize,
+    chars: usize,
+}
+
+impl<'a> TokenStream<'a> {
+    pub fn new(estimator: &'a TokenEstimator) -> Self {
+        Self::with_chunk_len(estimator, STREAM_CHUNK_LEN)
+    }
+
+    fn with_chunk_len(estimator: &'a TokenEstimator, chunk_len: usize) -> Self {
+        Self {
+            estimator,
+            chunk_len,
+            buffer: String::new(),
+            tokens: 0,
+            chars: 0,
+        }
+    }
+
+    pub fn push(&mut self, text: &str) {
+        if *self.estimator == TokenEstimator::CharDiv4 {
+            self.chars += text.chars().count();
+            return;
+        }
+        self.buffer.push_str(text);
+        while self.buffer.len() >= self.chunk_len {
+            let Some(end) = self.chunk_end() else {
+                break;
+            };
+            self.tokens += self.estimator.estimate_tokens(&self.buffer[..end]);
+            self.buffer.drain(..end);
+        }
+    }
+
+    /// The total for everything pushed.
+    pub fn finish(mut self) -> usize {
+        if *self.estimator == TokenEstimator::CharDiv4 {
+            return self.chars / 4;
+        }
+        self.tokens
+            + self
+                .estimator
+                .estimate_tokens(&std::mem::take(&mut self.buffer))
+    }
+
+    /// Where the next chunk ends, if the buffer holds a full one.
+    fn chunk_end(&self) -> Option<usize> {
+        let line_start = self
+            .buffer
+            .match_indices('\n')
+            .rev()
+            .map(|(newline, _)| newline + 1)
+            .find(|&start| is_token_boundary(&self.buffer[..start], &self.buffer[start..]));
+        if line_start.is_some() {
+            return line_start;
+        }
+        if self.buffer.len() < 2 * self.chunk_len {
+            return None;
+        }
+        // One very long line: split before a space, else anywhere
+        let mut end = self
+            .buffer
+            .as_bytes()
+            .windows(2)
+            .rposition(|pair| !pair[0].is_ascii_whitespace() && pair[1] == b' ')
+            .map_or(self.chunk_len, |space| space + 1);
+        while !self.buffer.is_char_boundary(end) {
+            end -= 1;
+        }
+        Some(end)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs::File;
-    use std::io::Write;
     use tempfile::tempdir;
 
     #[test]
@@ -113,18 +597,275 @@ mod tests {
         assert_eq!(estimator.estimate_tokens("Hello World"), 2);
     }
 
-    #[tokio::test]
-    async fn test_count_tokens() {
-        let temp_dir = tempdir().unwrap();
-        let file_path = temp_dir.path().join("test.txt");
-        let content = "Hello, World!";
+    #[test]
+    fn test_bundled_bpe_is_shared_across_threads() {
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                std::thread::spawn(|| {
+                    TokenEstimator::Cl100k.bundled_bpe().unwrap() as *const CoreBPE as usize
+                })
+            })
+            .collect();
+        let first = TokenEstimator::Cl100k.bundled_bpe().unwrap() as *const CoreBPE as usize;
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), first);
+        }
+        assert!(TokenEstimator::CharDiv4.bundled_bpe().is_none());
+    }
+
+    #[test]
+    fn test_o200k_and_p50k() {
+        assert_eq!(TokenEstimator::O200k.estimate_tokens("Hello World"), 2);
+        assert_eq!(TokenEstimator::P50k.estimate_tokens("Hello World"), 2);
+        // The larger vocabularies are much denser on non-English text
+        let text = "Привет мир";
+        assert_eq!(TokenEstimator::P50k.estimate_tokens(text), 11);
+        assert_eq!(TokenEstimator::Cl100k.estimate_tokens(text), 5);
+        assert_eq!(TokenEstimator::O200k.estimate_tokens(text), 3);
+    }
+
+    #[test]
+    fn test_estimator_registry_round_trip() {
+        for estimator in TokenEstimator::all() {
+            assert!(!estimator.name().is_empty());
+            assert_eq!(
+                estimator.to_string().parse::<TokenEstimator>(),
+                Ok(estimator)
+            );
+        }
+        assert_eq!(TokenEstimator::all().count(), 6);
+        // Ids are persisted in settings and caches
+        assert_eq!(TokenEstimator::Cl100k.to_string(), "Cl100k");
+        assert_eq!(
+            serde_json::to_string(&TokenEstimator::O200k).unwrap(),
+            "\"O200k\""
+        );
+        assert!("Random".parse::<TokenEstimator>().is_err());
 
-        let mut file = File::create(&file_path).unwrap();
-        file.write_all(content.as_bytes()).unwrap();
+        let custom = TokenEstimator::Custom("in-house".to_string());
+        assert_eq!(custom.to_string(), "custom:in-house");
+        assert_eq!("custom:in-house".parse(), Ok(custom.clone()));
+        assert_eq!(custom.name(), "in-house");
+        assert!("custom:".parse::<TokenEstimator>().is_err());
+        let json = serde_json::to_string(&custom).unwrap();
+        assert_eq!(json, "\"custom:in-house\"");
+        assert_eq!(
+            serde_json::from_str::<TokenEstimator>(&json).unwrap(),
+            custom
+        );
+    }
 
-        let count = count_tokens(&file_path, TokenEstimator::Cl100k)
-            .await
+    #[test]
+    fn test_model_aliases() {
+        assert_eq!("gpt-4o".parse(), Ok(TokenEstimator::O200k));
+        assert_eq!("gpt-4.1-mini".parse(), Ok(TokenEstimator::O200k));
+        assert_eq!("GPT-4o-2024-08-06".parse(), Ok(TokenEstimator::O200k));
+        assert_eq!("gpt-4-0613".parse(), Ok(TokenEstimator::Cl100k));
+        assert_eq!("gpt-3.5-turbo".parse(), Ok(TokenEstimator::Cl100k));
+        assert_eq!("text-davinci-003".parse(), Ok(TokenEstimator::P50k));
+        assert_eq!("gemini-1.5-pro".parse(), Ok(TokenEstimator::SentencePiece));
+        // A prefix only matches at a '-' boundary
+        assert_eq!(TokenEstimator::for_model("gpt-4x"), None);
+        for (model, estimator) in MODEL_ALIASES {
+            assert_eq!(TokenEstimator::for_model(model), Some(estimator));
+        }
+    }
+
+    /// Writes a word-level `tokenizer.json` knowing only "hello" and "world".
+    fn write_tokenizer_json(path: &Path) {
+        use tokenizers::models::wordlevel::WordLevel;
+        use tokenizers::pre_tokenizers::whitespace::Whitespace;
+
+        let vocab = [
+            ("[UNK]".to_string(), 0),
+            ("hello".to_string(), 1),
+            ("world".to_string(), 2),
+        ];
+        let model = WordLevel::builder()
+            .vocab(vocab.into_iter().collect())
+            .unk_token("[UNK]".to_string())
+            .build()
             .unwrap();
-        assert_eq!(count, 4); // "Hello", ",", " World", "!"
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Whitespace {});
+        tokenizer.save(path, false).unwrap();
+    }
+
+    // One test, since the loaded tokenizer is process-wide
+    #[test]
+    fn test_llama2_tokenizer_file() {
+        let estimator = TokenEstimator::Llama2;
+        let temp_dir = tempdir().unwrap();
+
+        assert_eq!(
+            load_tokenizer_file(&estimator, None),
+            Err(TokenizerError::NotConfigured)
+        );
+        assert_eq!(estimator.availability(), Err(TokenizerError::NotConfigured));
+        assert_eq!(estimator.estimate_tokens("hello world"), 0);
+
+        let missing = temp_dir.path().join("missing.json");
+        assert_eq!(
+            load_tokenizer_file(&estimator, Some(&missing)),
+            Err(TokenizerError::Missing(missing.clone()))
+        );
+        assert!(estimator
+            .availability()
+            .unwrap_err()
+            .to_string()
+            .starts_with("tokenizer file missing"));
+
+        let invalid = temp_dir.path().join("invalid.json");
+        std::fs::write(&invalid, "{}").unwrap();
+        assert!(matches!(
+            load_tokenizer_file(&estimator, Some(&invalid)),
+            Err(TokenizerError::Invalid(path, _)) if path == invalid
+        ));
+
+        let valid = temp_dir.path().join("tokenizer.json");
+        write_tokenizer_json(&valid);
+        assert_eq!(load_tokenizer_file(&estimator, Some(&valid)), Ok(()));
+        assert_eq!(estimator.availability(), Ok(()));
+        // Word-level: one token per word, unknown words included
+        assert_eq!(estimator.estimate_tokens("hello world hello"), 3);
+        assert_eq!(estimator.estimate_tokens("hello there"), 2);
+        assert_eq!(TokenEstimator::CharDiv4.availability(), Ok(()));
+    }
+
+    #[test]
+    fn test_sentencepiece_model_file() {
+        let estimator = TokenEstimator::SentencePiece;
+        let temp_dir = tempdir().unwrap();
+
+        let missing = temp_dir.path().join("missing.model");
+        assert_eq!(
+            load_tokenizer_file(&estimator, Some(&missing)),
+            Err(TokenizerError::Missing(missing))
+        );
+        assert_eq!(estimator.estimate_tokens("hello world"), 0);
+
+        let model = temp_dir.path().join("tokenizer.model");
+        std::fs::write(&model, sentencepiece::tests::unigram_model_bytes()).unwrap();
+        assert_eq!(load_tokenizer_file(&estimator, Some(&model)), Ok(()));
+        assert_eq!(estimator.availability(), Ok(()));
+        // ▁hello ▁wor ld
+        assert_eq!(estimator.estimate_tokens("hello world"), 3);
+    }
+
+    /// Writes a `.tiktoken` file with every byte plus "ab", "cd" and "1234".
+    fn write_tiktoken(path: &Path) {
+        let mut ranks = String::new();
+        for byte in 0..=u8::MAX {
+            ranks.push_str(&format!("{} {}\n", BASE64.encode([byte]), byte));
+        }
+        for (rank, token) in ["ab", "cd", "12", "34", "1234"].iter().enumerate() {
+            ranks.push_str(&format!("{} {}\n", BASE64.encode(token), 256 + rank));
+        }
+        std::fs::write(path, ranks).unwrap();
+    }
+
+    #[test]
+    fn test_custom_tokenizers() {
+        let temp_dir = tempdir().unwrap();
+
+        let json = CustomTokenizer {
+            name: "words".to_string(),
+            path: temp_dir.path().join("words.json"),
+            split_pattern: SplitPattern::default(),
+        };
+        write_tokenizer_json(&json.path);
+        assert_eq!(load_custom_tokenizer(&json), Ok(()));
+        assert_eq!(json.estimator().estimate_tokens("hello world hello"), 3);
+
+        let mut tiktoken = CustomTokenizer {
+            name: "ranks".to_string(),
+            path: temp_dir.path().join("ranks.tiktoken"),
+            split_pattern: SplitPattern::Cl100k,
+        };
+        write_tiktoken(&tiktoken.path);
+        let estimator = tiktoken.estimator();
+        assert_eq!(load_custom_tokenizer(&tiktoken), Ok(()));
+        // "abcd" is one pre-token: ab + cd; " x" is two single bytes
+        assert_eq!(estimator.estimate_tokens("abcd"), 2);
+        assert_eq!(estimator.estimate_tokens("abcd x"), 4);
+        // cl100k splits digits in threes (12 + 3, 4), p50k keeps the run whole
+        assert_eq!(estimator.estimate_tokens("1234"), 3);
+        tiktoken.split_pattern = SplitPattern::P50k;
+        assert_eq!(load_custom_tokenizer(&tiktoken), Ok(()));
+        assert_eq!(estimator.estimate_tokens("1234"), 1);
+        assert_eq!(estimator.estimate_tokens("abcd x"), 4);
+
+        // Without every single byte, some text could not be encoded
+        std::fs::write(&tiktoken.path, format!("{} 0\n", BASE64.encode("a"))).unwrap();
+        assert!(matches!(
+            load_custom_tokenizer(&tiktoken),
+            Err(TokenizerError::Invalid(..))
+        ));
+        std::fs::write(&tiktoken.path, "not base64!\n").unwrap();
+        assert!(load_custom_tokenizer(&tiktoken).is_err());
+        assert!(estimator.availability().is_err());
+        assert_eq!(estimator.estimate_tokens("abcd"), 0);
+
+        unload_custom_tokenizer("words");
+        assert_eq!(
+            json.estimator().availability(),
+            Err(TokenizerError::Unknown("words".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_token_stream_matches_whole_text() {
+        let text = "fn main() {\n    println!(\"héllo\");\n}\n\n\n// «comment»  \nlet x = 1;\r\n"
+            .repeat(200);
+        for estimator in [
+            TokenEstimator::CharDiv4,
+            TokenEstimator::Cl100k,
+            TokenEstimator::O200k,
+            TokenEstimator::P50k,
+        ] {
+            let mut stream = TokenStream::with_chunk_len(&estimator, 64);
+            // Pieces that split lines and characters at odd places
+            let mut rest = text.as_str();
+            while !rest.is_empty() {
+                let mut end = rest.len().min(37);
+                while !rest.is_char_boundary(end) {
+                    end += 1;
+                }
+                stream.push(&rest[..end]);
+                rest = &rest[end..];
+            }
+            assert_eq!(
+                stream.finish(),
+                estimator.estimate_tokens(&text),
+                "{}",
+                estimator
+            );
+        }
+    }
+
+    #[test]
+    fn test_token_stream_splits_long_lines() {
+        let estimator = TokenEstimator::Cl100k;
+        let line = "word ".repeat(1_000);
+        let mut stream = TokenStream::with_chunk_len(&estimator, 100);
+        stream.push(&line);
+        assert!(stream.buffer.len() < 200);
+        let streamed = stream.finish();
+        let whole = estimator.estimate_tokens(&line);
+        assert!(streamed.abs_diff(whole) <= line.len() / 100);
+    }
+
+    #[test]
+    fn test_estimate_file_tokens() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("log.txt");
+        let content = "2024-01-01 INFO request served in 12ms\n".repeat(1_000);
+        std::fs::write(&file_path, &content).unwrap();
+
+        let estimator = TokenEstimator::Cl100k;
+        assert_eq!(
+            estimator.estimate_file_tokens(&file_path).unwrap(),
+            estimator.estimate_tokens(&content)
+        );
     }
 }
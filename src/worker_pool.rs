@@ -0,0 +1,212 @@
+use std::any::Any;
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, Once, OnceLock};
+use std::thread;
+use tokio::sync::mpsc;
+
+/// Upper bound on worker threads, whatever the core count.
+const MAX_WORKERS: usize = 8;
+
+/// Results buffered before workers wait for the receiver to catch up.
+const CHANNEL_CAPACITY: usize = 256;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// The worker threads every `spawn` shares, started on first use.
+#[derive(Default)]
+struct Pool {
+    jobs: Mutex<VecDeque<Job>>,
+    available: Condvar,
+}
+
+impl Pool {
+    fn get() -> &'static Pool {
+        static POOL: OnceLock<Pool> = OnceLock::new();
+        static STARTED: Once = Once::new();
+        let pool = POOL.get_or_init(Pool::default);
+        STARTED.call_once(|| {
+            for _ in 0..worker_count() {
+                thread::spawn(|| pool.run());
+            }
+        });
+        pool
+    }
+
+    fn submit(&self, job: Job) {
+        self.jobs.lock().unwrap().push_back(job);
+        self.available.notify_one();
+    }
+
+    fn run(&self) {
+        loop {
+            let job = {
+                let mut jobs = self.jobs.lock().unwrap();
+                loop {
+                    match jobs.pop_front() {
+                        Some(job) => break job,
+                        None => jobs = self.available.wait(jobs).unwrap(),
+                    }
+                }
+            };
+            // A panicking job must not take its worker down with it: the
+            // pool is started once and never replaces lost threads
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                log::error!("Worker pool job panicked: {}", panic_message(&*payload));
+            }
+        }
+    }
+}
+
+/// The items of one `spawn` call and where their results go.
+struct Batch<T, R, F> {
+    items: Mutex<std::vec::IntoIter<T>>,
+    work: F,
+    tx: mpsc::Sender<R>,
+}
+
+/// Runs `work` over `items` on the shared pool of background threads and
+/// streams each result back as it completes, in completion order. Dropping
+/// the receiver stops the batch once the items in progress are done.
+///
+/// Each item is its own job, queued behind those of earlier calls, so
+/// concurrent batches take turns and the thread count stays bounded.
+pub fn spawn<T, R, F>(items: Vec<T>, work: F) -> mpsc::Receiver<R>
+where
+    T: Send + 'static,
+    R: Send + 'static,
+    F: Fn(T) -> R + Send + Sync + 'static,
+{
+    let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+    let jobs = worker_count().min(items.len());
+    let batch = Arc::new(Batch {
+        items: Mutex::new(items.into_iter()),
+        work,
+        tx,
+    });
+    for _ in 0..jobs {
+        Pool::get().submit(step(Arc::clone(&batch)));
+    }
+    rx
+}
+
+/// Processes one item of `batch`, then queues the next step behind any
+/// other work. An item whose `work` panics is skipped. The channel closes
+/// once the last step drops the batch.
+fn step<T, R, F>(batch: Arc<Batch<T, R, F>>) -> Job
+where
+    T: Send + 'static,
+    R: Send + 'static,
+    F: Fn(T) -> R + Send + Sync + 'static,
+{
+    Box::new(move || {
+        if batch.tx.is_closed() {
+            return;
+        }
+        let Some(item) = batch.items.lock().unwrap().next() else {
+            return;
+        };
+        match panic::catch_unwind(AssertUnwindSafe(|| (batch.work)(item))) {
+            Ok(result) => {
+                if batch.tx.blocking_send(result).is_err() {
+                    return;
+                }
+            }
+            Err(payload) => log::error!("Worker pool item panicked: {}", panic_message(&*payload)),
+        }
+        Pool::get().submit(step(batch));
+    })
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+fn worker_count() -> usize {
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+    cores.min(MAX_WORKERS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[test]
+    fn test_every_item_is_processed() {
+        let mut rx = spawn((1..=1_000).collect(), |n: u64| n * 2);
+        let mut results = Vec::new();
+        while let Some(result) = rx.blocking_recv() {
+            results.push(result);
+        }
+        results.sort();
+        assert_eq!(results, (1..=1_000).map(|n| n * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_no_items() {
+        let mut rx = spawn(Vec::<u8>::new(), |n| n);
+        assert_eq!(rx.blocking_recv(), None);
+    }
+
+    #[test]
+    fn test_dropping_the_receiver_stops_the_pool() {
+        let processed = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&processed);
+        let mut rx = spawn((0..100_000).collect(), move |n: usize| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            n
+        });
+        assert!(rx.blocking_recv().is_some());
+        drop(rx);
+
+        thread::sleep(Duration::from_millis(200));
+        let stopped_at = processed.load(Ordering::SeqCst);
+        assert!(stopped_at <= 1 + CHANNEL_CAPACITY + MAX_WORKERS);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(processed.load(Ordering::SeqCst), stopped_at);
+    }
+
+    #[test]
+    fn test_a_panicking_item_is_skipped() {
+        let mut rx = spawn((0..100).collect(), |n: usize| {
+            if n == 42 {
+                panic!("item {n} failed");
+            }
+            n
+        });
+        let mut results = Vec::new();
+        while let Some(result) = rx.blocking_recv() {
+            results.push(result);
+        }
+        results.sort();
+        assert_eq!(results, (0..100).filter(|&n| n != 42).collect::<Vec<_>>());
+
+        // The workers survived and still take new batches
+        let mut rx = spawn((0..10).collect(), |n: usize| n);
+        let mut count = 0;
+        while rx.blocking_recv().is_some() {
+            count += 1;
+        }
+        assert_eq!(count, 10);
+    }
+
+    #[test]
+    fn test_calls_share_the_pool_threads() {
+        let receivers: Vec<_> = (0..20)
+            .map(|_| spawn((0..50).collect(), |_: usize| thread::current().id()))
+            .collect();
+        let mut threads = std::collections::HashSet::new();
+        for mut rx in receivers {
+            while let Some(id) = rx.blocking_recv() {
+                threads.insert(id);
+            }
+        }
+        assert!(threads.len() <= MAX_WORKERS);
+    }
+}
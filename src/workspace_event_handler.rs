@@ -8,62 +8,95 @@ use crate::gitignore_handler::{
     check_for_gitignore, is_file_ignored, preprocess_gitignore_lines, read_gitignore_patterns,
 };
 
+/// The files of a workspace just opened, as relative paths.
+pub struct OpenedWorkspace {
+    /// Every file the root .gitignore keeps, reused for the bundle tree.
+    pub non_ignored: HashSet<String>,
+    /// The files selected initially, see `initial_selection`.
+    pub selected: HashSet<String>,
+}
+
 pub fn handle_workspace_opened(
     workspace_path_str: String,
-) -> Result<HashSet<String>, Box<dyn Error>> {
-    println!(
-        "[INFO] Workspace opened event triggered for path: {}",
+) -> Result<OpenedWorkspace, Box<dyn Error>> {
+    log::info!(
+        "Workspace opened event triggered for path: {}",
         workspace_path_str
     );
 
     let workspace_root = Path::new(&workspace_path_str);
-    let mut final_selected_files = HashSet::new();
+    let non_ignored = get_non_ignored_files(workspace_root)?;
+    let selected = initial_selection(workspace_root, non_ignored.clone());
+    Ok(OpenedWorkspace {
+        non_ignored,
+        selected,
+    })
+}
 
+/// Lists every workspace file (relative path) that survives the root
+/// .gitignore. Without a .gitignore, all files are returned.
+pub fn get_non_ignored_files(workspace_root: &Path) -> Result<HashSet<String>, Box<dyn Error>> {
     // Get all files first (excluding .git)
     let all_files = get_all_workspace_files(workspace_root)?; // Propagate IO errors
-    println!("[INFO] Found {} files initially.", all_files.len());
+    log::info!("Found {} files initially.", all_files.len());
 
     // Check for .gitignore
-    if let Some(gitignore_path) = check_for_gitignore(workspace_root) {
-        println!("[INFO] Found .gitignore at: {}", gitignore_path.display());
-
-        // Try reading and processing .gitignore
-        match read_gitignore_patterns(&gitignore_path) {
-            Ok(raw_patterns) => {
-                let processed_patterns = preprocess_gitignore_lines(raw_patterns);
-                println!(
-                    "[INFO] Loaded {} effective patterns from .gitignore.",
-                    processed_patterns.len()
-                );
-
-                // Filter files based on patterns
-                for file_path in all_files {
-                    if !is_file_ignored(&file_path, &processed_patterns, workspace_root) {
-                        final_selected_files.insert(file_path);
-                    }
-                }
-                println!(
-                    "[INFO] Selected {} files after applying .gitignore rules.",
-                    final_selected_files.len()
-                );
-            }
-            Err(e) => {
-                eprintln!(
-                    "[ERROR] Failed to read .gitignore file at {}: {}. Returning error.",
-                    gitignore_path.display(),
-                    e
-                );
-                // Return the error if .gitignore exists but is unreadable
-                return Err(Box::new(e));
-            }
+    let Some(gitignore_path) = check_for_gitignore(workspace_root) else {
+        return Ok(all_files.into_iter().collect());
+    };
+    log::info!("Found .gitignore at: {}", gitignore_path.display());
+
+    // Try reading and processing .gitignore
+    match read_gitignore_patterns(&gitignore_path) {
+        Ok(raw_patterns) => {
+            let processed_patterns = preprocess_gitignore_lines(raw_patterns);
+            log::info!(
+                "Loaded {} effective patterns from .gitignore.",
+                processed_patterns.len()
+            );
+
+            // Filter files based on patterns
+            let non_ignored_files: HashSet<String> = all_files
+                .into_iter()
+                .filter(|file_path| {
+                    !is_file_ignored(file_path, &processed_patterns, workspace_root)
+                })
+                .collect();
+            log::info!(
+                "{} files remain after applying .gitignore rules.",
+                non_ignored_files.len()
+            );
+            Ok(non_ignored_files)
         }
+        Err(e) => {
+            log::error!(
+                "Failed to read .gitignore file at {}: {}. Returning error.",
+                gitignore_path.display(),
+                e
+            );
+            // Return the error if .gitignore exists but is unreadable
+            Err(Box::new(e))
+        }
+    }
+}
+
+/// Picks the files selected when a workspace is opened: everything not
+/// ignored when a .gitignore exists, nothing otherwise.
+pub fn initial_selection(
+    workspace_root: &Path,
+    non_ignored_files: HashSet<String>,
+) -> HashSet<String> {
+    if check_for_gitignore(workspace_root).is_some() {
+        log::info!(
+            "Selected {} files after applying .gitignore rules.",
+            non_ignored_files.len()
+        );
+        non_ignored_files
     } else {
         // No .gitignore found. Spec says deselect all files.
-        println!("[INFO] No .gitignore found. Deselecting all files.");
-        // final_selected_files remains empty, which is correct.
+        log::info!("No .gitignore found. Deselecting all files.");
+        HashSet::new()
     }
-
-    Ok(final_selected_files)
 }
 
 // Placeholder for where this function might be called from, // Modified placeholder
@@ -92,7 +125,7 @@ mod tests {
 
         // Case 1: No .gitignore
         println!("\n--- Testing without .gitignore ---");
-        let selected_none = handle_workspace_opened(root.to_str().unwrap().to_string())?;
+        let selected_none = handle_workspace_opened(root.to_str().unwrap().to_string())?.selected;
         assert!(
             selected_none.is_empty(),
             "Expected empty set without .gitignore"
@@ -106,7 +139,9 @@ mod tests {
         writeln!(gitignore_file, "*.log")?;
         drop(gitignore_file);
 
-        let selected_with = handle_workspace_opened(root.to_str().unwrap().to_string())?;
+        let opened = handle_workspace_opened(root.to_str().unwrap().to_string())?;
+        assert_eq!(opened.non_ignored, opened.selected);
+        let selected_with = opened.selected;
 
         let expected_files: HashSet<String> = [
             "src/main.rs".to_string(),
@@ -136,4 +171,28 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_get_non_ignored_files() -> Result<(), Box<dyn Error>> {
+        let dir = tempdir()?;
+        let root = dir.path();
+
+        create_dir_all(root.join("src"))?;
+        File::create(root.join("src/main.rs"))?.write_all(b"fn main() {}")?;
+        create_dir_all(root.join("target"))?;
+        File::create(root.join("target/debug.log"))?.write_all(b"debug info")?;
+
+        // Without .gitignore every file is visible, even though none is selected
+        let visible = get_non_ignored_files(root)?;
+        assert_eq!(visible.len(), 2);
+        assert!(initial_selection(root, visible).is_empty());
+
+        File::create(root.join(".gitignore"))?.write_all(b"target/\n")?;
+        let visible = get_non_ignored_files(root)?;
+        let expected: HashSet<String> = ["src/main.rs".to_string()].into_iter().collect();
+        assert_eq!(visible, expected);
+        assert_eq!(initial_selection(root, visible), expected);
+
+        Ok(())
+    }
 }